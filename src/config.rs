@@ -0,0 +1,40 @@
+use rocket::config::Value;
+use std::collections::BTreeMap;
+
+/// Returns the string specified by name in the table.
+pub(crate) fn table_get_string(
+    table: &BTreeMap<String, Value>,
+    name: &str,
+    def_val: &str,
+) -> String {
+    table
+        .get(name)
+        .map_or(def_val, |x| x.as_str().unwrap_or(def_val))
+        .to_string()
+}
+
+/// Returns the integer specified by name in the table.
+pub(crate) fn table_get_int(table: &BTreeMap<String, Value>, name: &str, def_val: i64) -> i64 {
+    table
+        .get(name)
+        .map_or(def_val, |x| x.as_integer().unwrap_or(def_val))
+}
+
+/// Returns the boolean specified by name in the table.
+pub(crate) fn table_get_bool(table: &BTreeMap<String, Value>, name: &str, def_val: bool) -> bool {
+    table
+        .get(name)
+        .map_or(def_val, |x| x.as_bool().unwrap_or(def_val))
+}
+
+/// Returns the array of strings specified by name in the table.
+pub(crate) fn table_get_strings(table: &BTreeMap<String, Value>, name: &str) -> Vec<String> {
+    table
+        .get(name)
+        .and_then(|x| x.as_array())
+        .map_or_else(Vec::new, |x| {
+            x.iter()
+                .filter_map(|x| x.as_str().map(|x| x.to_string()))
+                .collect()
+        })
+}
@@ -0,0 +1,216 @@
+use crate::models::SessionManager;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::data::{self, FromDataSimple};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Cookie, Method, SameSite, Status};
+use rocket::request::{self, FormItems, FromForm, FromRequest, Request};
+use rocket::{Data, Outcome, State};
+use std::fmt;
+use std::io::Read;
+use std::ops::Deref;
+
+/// The name of the cookie to carry the token.
+pub const CSRF_COOKIE: &str = "csrf";
+/// The name of the form field to submit the token.
+pub const CSRF_FIELD: &str = "csrf_token";
+/// The name of the header to submit the token by scripts.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// The route the rejected requests are redirected to.
+const CSRF_REJECTED_URI: &str = "/csrf/rejected";
+/// The length of the token in bytes before encoding.
+const CSRF_TOKEN_LEN: usize = 32;
+/// The default size limit of the forms.
+const DEFAULT_FORM_LIMIT: u64 = 32 * 1024;
+
+/// The token of current request, issued by the `CsrfFairing`.
+#[derive(Clone, Debug, Default)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns true if `value` matches the token, compared in constant time.
+    fn matches(&self, value: &str) -> bool {
+        !self.0.is_empty()
+            && self.0.len() == value.len()
+            && self
+                .0
+                .bytes()
+                .zip(value.bytes())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl Deref for CsrfToken {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+    type Error = !;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, !> {
+        Outcome::Success(Clone::clone(request.local_cache(CsrfToken::default)))
+    }
+}
+
+/// Returns a new randomized token.
+pub fn make_token() -> String {
+    let mut bytes = [0u8; CSRF_TOKEN_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Marks the request rejected by the CSRF checks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CsrfRejected(pub bool);
+
+/// Returns true if the request was rejected by the CSRF checks.
+pub fn is_csrf_rejected(request: &Request) -> bool {
+    request.local_cache(CsrfRejected::default).0
+}
+
+/// Returns true if the header of `request` carries the valid token.
+fn header_matches(request: &Request, token: &CsrfToken) -> bool {
+    request
+        .headers()
+        .get_one(CSRF_HEADER)
+        .map_or(false, |x| token.matches(x))
+}
+
+/// The fairing to issue the tokens and check the requests not submitting forms.
+///
+/// Every request gets a token stored in a private cookie, which is replaced by the
+/// `SessionManager` whenever a session starts or ends, the non-GET requests
+/// must submit the token by the `X-CSRF-Token` header, or by the `csrf_token`
+/// field if the body is a form, which is checked by `CsrfForm`.
+pub struct CsrfFairing;
+
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF Protection",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let existing = request
+            .cookies()
+            .get_private(CSRF_COOKIE)
+            .map(|x| x.value().to_string());
+        let token = match existing {
+            Some(v) => v,
+            None => {
+                let token = make_token();
+                let cookie = match request.guard::<State<SessionManager>>() {
+                    Outcome::Success(manager) => manager.cookie(CSRF_COOKIE, Clone::clone(&token)),
+                    _ => Cookie::build(CSRF_COOKIE, Clone::clone(&token))
+                        .path("/")
+                        .http_only(true)
+                        .same_site(SameSite::Strict)
+                        .finish(),
+                };
+                request.cookies().add_private(cookie);
+                token
+            }
+        };
+        let token = request.local_cache(|| CsrfToken(token));
+        let safe = matches!(
+            request.method(),
+            Method::Get | Method::Head | Method::Options
+        );
+        let is_form = request.content_type().map_or(false, |x| x.is_form());
+        if safe || is_form || header_matches(request, token) {
+            return;
+        }
+        request.local_cache(|| CsrfRejected(true));
+        request.set_method(Method::Get);
+        request.set_uri(Origin::parse(CSRF_REJECTED_URI).expect("valid uri"));
+    }
+}
+
+/// The errors of `CsrfForm`.
+#[derive(Debug)]
+pub enum CsrfError {
+    Io(std::io::Error),
+    Mismatch,
+    Parse,
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrfError::Io(err) => write!(f, "{}", err),
+            CsrfError::Mismatch => write!(f, "CSRF token mismatch"),
+            CsrfError::Parse => write!(f, "Failed to parse the form"),
+        }
+    }
+}
+
+/// The form verified the `csrf_token` field or the `X-CSRF-Token` header.
+///
+/// The field is removed before parsing into `T`, so `T` is parsed strictly
+/// without declaring it.
+#[derive(Debug)]
+pub struct CsrfForm<T>(pub T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for CsrfForm<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> FromDataSimple for CsrfForm<T>
+where
+    T: for<'f> FromForm<'f>,
+{
+    type Error = CsrfError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        if !request.content_type().map_or(false, |x| x.is_form()) {
+            return Outcome::Forward(data);
+        }
+        let limit = request.limits().get("forms").unwrap_or(DEFAULT_FORM_LIMIT);
+        let mut body = String::new();
+        if let Err(err) = data.open().take(limit).read_to_string(&mut body) {
+            return Outcome::Failure((Status::BadRequest, CsrfError::Io(err)));
+        }
+        let token = request.local_cache(CsrfToken::default);
+        let mut verified = header_matches(request, token);
+        let mut fields = Vec::new();
+        for item in FormItems::from(body.as_str()) {
+            if item.key.as_str() == CSRF_FIELD {
+                verified |= item.value.url_decode().map_or(false, |x| token.matches(&x));
+            } else {
+                fields.push(format!("{}={}", item.key, item.value));
+            }
+        }
+        if !verified {
+            request.local_cache(|| CsrfRejected(true));
+            return Outcome::Failure((Status::Forbidden, CsrfError::Mismatch));
+        }
+        let fields = fields.join("&");
+        let form = T::from_form(&mut FormItems::from(fields.as_str()), true);
+        match form {
+            Ok(v) => Outcome::Success(CsrfForm(v)),
+            Err(_) => Outcome::Failure((Status::UnprocessableEntity, CsrfError::Parse)),
+        }
+    }
+}
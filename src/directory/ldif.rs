@@ -0,0 +1,140 @@
+//! A minimal parser of the LDIF content records (RFC 2849), enough to seed the
+//! in-memory directory. The change records and the `:<` URL values are not
+//! supported.
+
+use ldap3::SearchEntry;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The errors may occurred on parsing LDIF.
+#[derive(Debug, PartialEq)]
+pub enum LdifError {
+    /// The line is not `attr: value`, with the line number.
+    Syntax(usize),
+    /// The base64 value is invalid, with the line number.
+    Base64(usize),
+    /// The record does not start with `dn:`, with the line number.
+    NoDn(usize),
+    /// The change records are not supported, with the line number.
+    Unsupported(usize),
+}
+
+impl fmt::Display for LdifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LdifError::Syntax(line) => write!(f, "Invalid LDIF line {}", line),
+            LdifError::Base64(line) => write!(f, "Invalid base64 value at line {}", line),
+            LdifError::NoDn(line) => write!(f, "The record at line {} has no dn", line),
+            LdifError::Unsupported(line) => {
+                write!(f, "The change record at line {} is not supported", line)
+            }
+        }
+    }
+}
+
+/// Returns the entries of the LDIF `content`, the values not valid UTF-8 are
+/// put into `bin_attrs`.
+pub fn parse_ldif(content: &str) -> Result<Vec<SearchEntry>, LdifError> {
+    // Unfold the continuation lines, keeping the number of the first line.
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        match (line.strip_prefix(' '), lines.last_mut()) {
+            (Some(rest), Some((_, last))) if !last.is_empty() => last.push_str(rest),
+            _ => lines.push((i + 1, line.to_string())),
+        }
+    }
+    let mut entries = Vec::new();
+    let mut entry: Option<SearchEntry> = None;
+    for (number, line) in lines {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.is_empty() {
+            entries.extend(entry.take());
+            continue;
+        }
+        let (attr, value) = parse_line(&line, number)?;
+        match entry.as_mut() {
+            None if attr.eq_ignore_ascii_case("version") => {}
+            None if attr.eq_ignore_ascii_case("dn") => {
+                entry = Some(SearchEntry {
+                    dn: String::from_utf8(value).map_err(|_| LdifError::Syntax(number))?,
+                    attrs: HashMap::new(),
+                    bin_attrs: HashMap::new(),
+                });
+            }
+            None => return Err(LdifError::NoDn(number)),
+            Some(_) if attr.eq_ignore_ascii_case("changetype") => {
+                return Err(LdifError::Unsupported(number))
+            }
+            Some(entry) => match String::from_utf8(value) {
+                Ok(v) => entry.attrs.entry(attr).or_default().push(v),
+                Err(err) => entry
+                    .bin_attrs
+                    .entry(attr)
+                    .or_default()
+                    .push(err.into_bytes()),
+            },
+        }
+    }
+    entries.extend(entry);
+    Ok(entries)
+}
+
+/// Returns the attribute and the value of the unfolded `line`.
+fn parse_line(line: &str, number: usize) -> Result<(String, Vec<u8>), LdifError> {
+    let i = line.find(':').ok_or(LdifError::Syntax(number))?;
+    let attr = line[..i].trim();
+    if attr.is_empty() {
+        return Err(LdifError::Syntax(number));
+    }
+    let rest = &line[i + 1..];
+    let value = if let Some(encoded) = rest.strip_prefix(':') {
+        base64::decode(encoded.trim()).map_err(|_| LdifError::Base64(number))?
+    } else if rest.starts_with('<') {
+        return Err(LdifError::Unsupported(number));
+    } else {
+        rest.trim_start().as_bytes().to_vec()
+    };
+    Ok((attr.to_string(), value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ldif() {
+        let content = "version: 1\n\
+                       # the users\n\
+                       dn: uid=alice,ou=demo,dc=example,dc=com\n\
+                       objectClass: inetOrgPerson\n\
+                       objectClass: person\n\
+                       uid: alice\n\
+                       cn: Alice\n  Liddell\n\
+                       cn:: 54ix5Li95Lid\n\
+                       photo:: /9j/\n\
+                       \n\
+                       \n\
+                       dn: cn=admins,ou=groups,dc=example,dc=com\n\
+                       member: uid=alice,ou=demo,dc=example,dc=com\n";
+        let entries = parse_ldif(content).unwrap();
+        assert_eq!(entries.len(), 2);
+        let alice = &entries[0];
+        assert_eq!(alice.dn, "uid=alice,ou=demo,dc=example,dc=com");
+        assert_eq!(alice.attrs["objectClass"], vec!["inetOrgPerson", "person"]);
+        assert_eq!(alice.attrs["cn"], vec!["Alice Liddell", "爱丽丝"]);
+        assert_eq!(alice.bin_attrs["photo"], vec![vec![0xff, 0xd8, 0xff]]);
+        assert_eq!(entries[1].attrs["member"].len(), 1);
+        assert_eq!(parse_ldif("uid: alice\n").unwrap_err(), LdifError::NoDn(1));
+        assert_eq!(
+            parse_ldif("dn: uid=a\nchangetype: delete\n").unwrap_err(),
+            LdifError::Unsupported(2)
+        );
+        assert_eq!(
+            parse_ldif("dn: uid=a\nuid\n").unwrap_err(),
+            LdifError::Syntax(2)
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use super::{parse_ldif, Directory, LdifError};
+use crate::error::{RC_ENTRY_ALREADY_EXISTS, RC_INVALID_CREDENTIALS, RC_NO_SUCH_OBJECT};
+use crate::ldap::ppolicy::PolicyResponse;
+use crate::ldap::{
+    escape_dn_value, is_dn_under, normalize_dn, LdapConfig, PWD_ACCOUNT_LOCKED_TIME,
+};
+use crate::models::{
+    is_policy_locked, verify_digest, NewPassword, PasswordDigest, PendingRegistration, Person,
+    Role, UserListMeta, UserQuery, UserSummary, PWD_CHANGED_TIME, PWD_GRACE_USE_TIME, PWD_RESET,
+};
+use chrono::Utc;
+use ldap3::result::{LdapError, LdapResult, Result};
+use ldap3::SearchEntry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Returns the error result of `rc` with the diagnostic `text`.
+fn error(rc: u32, text: &str) -> LdapError {
+    LdapError::LdapResult {
+        result: LdapResult {
+            rc,
+            matched: String::new(),
+            text: text.to_string(),
+            refs: Vec::new(),
+            ctrls: Vec::new(),
+        },
+    }
+}
+
+/// Returns true if `attr` of `entry` has `value`, compared ignoring case.
+fn has_value(entry: &SearchEntry, attr: &str, value: &str) -> bool {
+    entry
+        .attrs
+        .get(attr)
+        .map_or(false, |x| x.iter().any(|v| v.eq_ignore_ascii_case(value)))
+}
+
+/// Returns true if the password of `entry` matches `password`, the digests
+/// prefixed by the schemes and the cleartext values are supported.
+fn password_matches(entry: &SearchEntry, password: &str) -> bool {
+    entry.attrs.get("userPassword").map_or(false, |x| {
+        x.iter().any(|v| {
+            if v.starts_with('{') {
+                verify_digest(password, v)
+            } else {
+                v == password
+            }
+        })
+    })
+}
+
+/// Returns the time `now` in the LDAP GeneralizedTime.
+fn generalized_time_now() -> String {
+    Utc::now().format("%Y%m%d%H%M%S%.3fZ").to_string()
+}
+
+/// The directory kept in the process for the tests and the demos, seeded from
+/// LDIF. The clones share the same entries.
+#[derive(Clone)]
+pub struct MemoryDirectory {
+    cfg: LdapConfig,
+    /// The entries by the normalized dn.
+    entries: Arc<RwLock<BTreeMap<String, SearchEntry>>>,
+}
+
+impl MemoryDirectory {
+    /// Construct an empty directory with the settings of `cfg`.
+    pub fn new(cfg: LdapConfig) -> Self {
+        Self {
+            cfg,
+            entries: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Add the entries of the LDIF `content`, returns the count of them.
+    pub fn load_ldif(&self, content: &str) -> std::result::Result<usize, LdifError> {
+        let entries = parse_ldif(content)?;
+        let count = entries.len();
+        for entry in entries {
+            self.insert(entry);
+        }
+        Ok(count)
+    }
+
+    /// Add or replace `entry`, the `createTimestamp` is set if absent.
+    pub fn insert(&self, mut entry: SearchEntry) {
+        entry
+            .attrs
+            .entry("createTimestamp".to_string())
+            .or_insert_with(|| vec![generalized_time_now()]);
+        self.write().insert(normalize_dn(&entry.dn), entry);
+    }
+
+    /// Remove the entry specified by `dn`, returns it if found.
+    #[cfg(test)]
+    pub fn remove(&self, dn: &str) -> Option<SearchEntry> {
+        self.write().remove(&normalize_dn(dn))
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, SearchEntry>> {
+        self.entries.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, SearchEntry>> {
+        self.entries.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Apply `f` to the entry specified by `dn`.
+    fn modify<F>(&self, dn: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut SearchEntry),
+    {
+        let mut entries = self.write();
+        let entry = entries
+            .get_mut(&normalize_dn(dn))
+            .ok_or_else(|| error(RC_NO_SUCH_OBJECT, "No such object"))?;
+        f(entry);
+        Ok(())
+    }
+
+    /// Returns the `inetOrgPerson` entries under `base_dn` match to `predicate`,
+    /// without the passwords as the searches of the server.
+    fn users<P>(&self, predicate: P) -> Vec<SearchEntry>
+    where
+        P: Fn(&SearchEntry) -> bool,
+    {
+        self.read()
+            .values()
+            .filter(|x| has_value(x, "objectClass", "inetOrgPerson"))
+            .filter(|x| self.cfg.is_under_base_dn(&x.dn) && predicate(x))
+            .map(|x| {
+                let mut entry = Clone::clone(x);
+                entry.attrs.remove("userPassword");
+                entry
+            })
+            .collect()
+    }
+
+    /// Replace the password of `user_dn` with `digest`, as the password policy
+    /// overlay does.
+    fn set_password(&self, user_dn: &str, digest: String) -> Result<()> {
+        self.modify(user_dn, |entry| {
+            entry.attrs.insert("userPassword".to_string(), vec![digest]);
+            entry
+                .attrs
+                .insert(PWD_CHANGED_TIME.to_string(), vec![generalized_time_now()]);
+            entry.attrs.remove(PWD_RESET);
+            entry.attrs.remove(PWD_GRACE_USE_TIME);
+        })
+    }
+
+    /// Add the user specified by `user_dn` to the group specified by `group_dn`.
+    fn add_member(&self, group_dn: &str, user_dn: &str) -> Result<()> {
+        self.modify(group_dn, |group| {
+            let attr = if has_value(group, "objectClass", "groupOfUniqueNames") {
+                "uniqueMember"
+            } else {
+                "member"
+            };
+            group
+                .attrs
+                .entry(attr.to_string())
+                .or_default()
+                .push(user_dn.to_string());
+        })
+    }
+}
+
+impl Directory for MemoryDirectory {
+    fn cfg(&self) -> &LdapConfig {
+        &self.cfg
+    }
+
+    fn entry_of_username(&mut self, username: &str) -> Result<SearchEntry> {
+        self.users(|x| has_value(x, "uid", username) || has_value(x, "mail", username))
+            .into_iter()
+            .next()
+            .ok_or(LdapError::EndOfStream)
+    }
+
+    fn roles_of(&mut self, user_dn: &str) -> Result<Vec<Role>> {
+        let entries = self.read();
+        let user = entries
+            .get(&normalize_dn(user_dn))
+            .ok_or_else(|| error(RC_NO_SUCH_OBJECT, "No such object"))?;
+        let mut groups = user.attrs.get("memberOf").cloned().unwrap_or_default();
+        let dn = normalize_dn(user_dn);
+        let base = &self.cfg.group_base_dn;
+        groups.extend(
+            entries
+                .values()
+                .filter(|x| !base.is_empty() && is_dn_under(&x.dn, base))
+                .filter(|x| {
+                    ["member", "uniqueMember"].iter().any(|attr| {
+                        x.attrs
+                            .get(*attr)
+                            .map_or(false, |v| v.iter().any(|m| normalize_dn(m) == dn))
+                    })
+                })
+                .map(|x| Clone::clone(&x.dn)),
+        );
+        Ok(self.cfg.roles_of_groups(&groups))
+    }
+
+    fn search_users(&mut self, query: &UserQuery) -> Result<(Vec<UserSummary>, UserListMeta)> {
+        let keyword = query.q.as_deref().unwrap_or_default().to_lowercase();
+        let users = self.users(|x| {
+            keyword.is_empty()
+                || ["uid", "cn", "mail"].iter().any(|attr| {
+                    x.attrs.get(*attr).map_or(false, |v| {
+                        v.iter().any(|v| v.to_lowercase().contains(&keyword))
+                    })
+                })
+        });
+        Ok(query.apply(users.into_iter().map(UserSummary::from).collect()))
+    }
+
+    fn locked_users(&mut self) -> Result<Vec<SearchEntry>> {
+        Ok(self.users(|x| x.attrs.contains_key(PWD_ACCOUNT_LOCKED_TIME)))
+    }
+
+    fn unlock_user(&mut self, user_dn: &str) -> Result<()> {
+        self.modify(user_dn, |entry| {
+            entry.attrs.remove(PWD_ACCOUNT_LOCKED_TIME);
+        })
+    }
+
+    fn new_user(&mut self, user: &PendingRegistration) -> Result<()> {
+        let parent = user.ou.as_ref().unwrap_or(&self.cfg.base_dn);
+        let dn = format!("uid={},{}", escape_dn_value(&user.uid), parent);
+        if self.read().contains_key(&normalize_dn(&dn)) {
+            return Err(error(RC_ENTRY_ALREADY_EXISTS, "Entry already exists"));
+        }
+        let attrs: HashMap<String, Vec<String>> = vec![
+            ("objectClass", vec!["inetOrgPerson", "person"]),
+            ("uid", vec![user.uid.as_str()]),
+            ("cn", vec![user.cn.as_str()]),
+            ("sn", vec![user.cn.as_str()]),
+            ("mail", vec![user.mail.as_str()]),
+            ("userPassword", vec![user.password_digest.as_str()]),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.into_iter().map(String::from).collect()))
+        .collect();
+        self.insert(SearchEntry {
+            dn: Clone::clone(&dn),
+            attrs,
+            bin_attrs: HashMap::new(),
+        });
+        for group in user.groups.iter() {
+            if let Err(err) = self.add_member(group, &dn) {
+                println!("Failed to add {} to group {}: {}", dn, group, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_password(&mut self, user_dn: &str, new_password: &NewPassword) -> Result<()> {
+        if !self.verify_password(user_dn, &new_password.old_password) {
+            return Err(error(RC_INVALID_CREDENTIALS, "Invalid credentials"));
+        }
+        self.set_password(user_dn, new_password.digest(self.cfg.password_scheme))
+    }
+
+    fn reset_password(&mut self, user_dn: &str, new_password: &dyn PasswordDigest) -> Result<()> {
+        self.set_password(user_dn, new_password.digest(self.cfg.password_scheme))
+    }
+
+    fn update_person(&mut self, user_dn: &str, person: &Person) -> Result<()> {
+        self.modify(user_dn, |entry| {
+            entry
+                .attrs
+                .insert("cn".to_string(), vec![Clone::clone(&person.cn)]);
+            entry
+                .attrs
+                .insert("mail".to_string(), vec![Clone::clone(&person.mail)]);
+        })
+    }
+
+    fn update_photo(&mut self, user_dn: &str, bytes: &[u8]) -> Result<()> {
+        self.modify(user_dn, |entry| {
+            entry.attrs.remove("photo");
+            entry
+                .bin_attrs
+                .insert("photo".to_string(), vec![bytes.to_vec()]);
+        })
+    }
+
+    fn update_totp(
+        &mut self,
+        user_dn: &str,
+        secret: Option<&str>,
+        recovery_codes: &[String],
+    ) -> Result<()> {
+        let digests: Vec<String> = recovery_codes
+            .iter()
+            .map(|x| format!("{{SSHA256}}{}", x.as_str().ssha256()))
+            .collect();
+        let totp_attribute = Clone::clone(&self.cfg.totp_attribute);
+        let totp_recovery_attribute = Clone::clone(&self.cfg.totp_recovery_attribute);
+        self.modify(user_dn, |entry| match secret {
+            Some(secret) => {
+                entry.attrs.insert(totp_attribute, vec![secret.to_string()]);
+                entry.attrs.insert(totp_recovery_attribute, digests);
+            }
+            None => {
+                entry.attrs.remove(&totp_attribute);
+                entry.attrs.remove(&totp_recovery_attribute);
+            }
+        })
+    }
+
+    fn remove_recovery_code(&mut self, user_dn: &str, digest: &str) -> Result<()> {
+        let attr = Clone::clone(&self.cfg.totp_recovery_attribute);
+        self.modify(user_dn, |entry| {
+            if let Some(values) = entry.attrs.get_mut(&attr) {
+                values.retain(|x| x != digest);
+            }
+        })
+    }
+
+    fn verify_password(&mut self, user_dn: &str, user_pwd: &str) -> bool {
+        self.read()
+            .get(&normalize_dn(user_dn))
+            .map_or(false, |x| password_matches(x, user_pwd))
+    }
+
+    fn bind_user(&mut self, user_dn: &str, user_pwd: &str) -> Result<Option<PolicyResponse>> {
+        let entries = self.read();
+        match entries.get(&normalize_dn(user_dn)) {
+            Some(entry)
+                if !entry.attrs.get(PWD_ACCOUNT_LOCKED_TIME).map_or(false, |x| {
+                    is_policy_locked(x, self.cfg.pwd_lockout_duration, Utc::now())
+                }) && password_matches(entry, user_pwd) =>
+            {
+                Ok(None)
+            }
+            _ => Err(error(RC_INVALID_CREDENTIALS, "Invalid credentials")),
+        }
+    }
+}
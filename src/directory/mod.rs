@@ -0,0 +1,120 @@
+use crate::ldap::ppolicy::PolicyResponse;
+use crate::ldap::{LdapAccessor, LdapCluster, LdapConfig};
+use crate::models::{
+    NewPassword, PasswordDigest, PendingRegistration, Person, Role, UserListMeta, UserQuery,
+    UserSummary,
+};
+use ldap3::result::Result;
+use ldap3::SearchEntry;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use std::ops::{Deref, DerefMut};
+
+#[cfg(any(test, feature = "demo"))]
+mod ldif;
+#[cfg(any(test, feature = "demo"))]
+mod memory;
+
+#[cfg(any(test, feature = "demo"))]
+pub use ldif::{parse_ldif, LdifError};
+#[cfg(any(test, feature = "demo"))]
+pub use memory::MemoryDirectory;
+
+/// The directory of the users and groups, the routes access the users only
+/// through it, so that the backend can be replaced, e.g. in the tests.
+pub trait Directory {
+    /// Returns the settings of the directory.
+    fn cfg(&self) -> &LdapConfig;
+
+    /// Returns the entry under `base_dn` and the `uid` or `mail` match to `username`.
+    fn entry_of_username(&mut self, username: &str) -> Result<SearchEntry>;
+
+    /// Returns the roles of the user specified by `user_dn`.
+    fn roles_of(&mut self, user_dn: &str) -> Result<Vec<Role>>;
+
+    /// Returns the page of `inetOrgPerson` entries under `base_dn` requested by
+    /// `query`, the `uid`, `cn` or `mail` of them contains the keyword.
+    fn search_users(&mut self, query: &UserQuery) -> Result<(Vec<UserSummary>, UserListMeta)>;
+
+    /// Returns the entries under `base_dn` locked by the password policy.
+    fn locked_users(&mut self) -> Result<Vec<SearchEntry>>;
+
+    /// Unlock the user specfied with `user_dn` locked by the password policy.
+    fn unlock_user(&mut self, user_dn: &str) -> Result<()>;
+
+    /// Create the entry of the confirmed registration `user`.
+    fn new_user(&mut self, user: &PendingRegistration) -> Result<()>;
+
+    /// Update user password to specfied with `user_dn`, the old password is verified.
+    fn update_password(&mut self, user_dn: &str, new_password: &NewPassword) -> Result<()>;
+
+    /// Reset user password to specfied with `user_dn`, no old password required.
+    fn reset_password(&mut self, user_dn: &str, new_password: &dyn PasswordDigest) -> Result<()>;
+
+    /// Update person attributes to specfied with `user_dn`.
+    fn update_person(&mut self, user_dn: &str, person: &Person) -> Result<()>;
+
+    /// Update photo with `bytes` to specfied with `user_dn`.
+    fn update_photo(&mut self, user_dn: &str, bytes: &[u8]) -> Result<()>;
+
+    /// Update the TOTP secret and the digests of recovery codes to specfied with `user_dn`,
+    /// the two-factor authentication will be disabled if `secret` is `None`.
+    fn update_totp(
+        &mut self,
+        user_dn: &str,
+        secret: Option<&str>,
+        recovery_codes: &[String],
+    ) -> Result<()>;
+
+    /// Remove the used recovery code `digest` from specfied with `user_dn`.
+    fn remove_recovery_code(&mut self, user_dn: &str, digest: &str) -> Result<()>;
+
+    /// Returns true if the the password matched.
+    fn verify_password(&mut self, user_dn: &str, user_pwd: &str) -> bool;
+
+    /// Bind as `user_dn`, returns the password policy response if attached. The
+    /// error result carries the response too, see `PolicyResponse::from_controls`.
+    fn bind_user(&mut self, user_dn: &str, user_pwd: &str) -> Result<Option<PolicyResponse>>;
+}
+
+/// The backend of the directory, selected by `backend` of the `ldap` table.
+pub enum DirectoryBackend {
+    Ldap(LdapCluster),
+    /// Only built with the `demo` feature or in the tests.
+    #[cfg(any(test, feature = "demo"))]
+    Memory(MemoryDirectory),
+}
+
+/// The directory of current request.
+pub struct DirectoryRef(Box<dyn Directory>);
+
+impl Deref for DirectoryRef {
+    type Target = dyn Directory;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl DerefMut for DirectoryRef {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for DirectoryRef {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let backend = request.guard::<State<DirectoryBackend>>()?;
+        match &*backend {
+            DirectoryBackend::Ldap(cluster) => Outcome::Success(DirectoryRef(Box::new(
+                LdapAccessor::for_request(cluster, request),
+            ))),
+            #[cfg(any(test, feature = "demo"))]
+            DirectoryBackend::Memory(memory) => {
+                Outcome::Success(DirectoryRef(Box::new(Clone::clone(memory))))
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! The errors of the directory operations, classified by the LDAP result codes
+//! so that the routes report them with the same statuses, messages and logs.
+
+use crate::ldap::ppolicy::{PolicyError, PolicyResponse};
+use ldap3::result::LdapError;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::fmt;
+
+/// The result code of constraint violation.
+pub const RC_CONSTRAINT_VIOLATION: u32 = 19;
+/// The result code of invalid attribute syntax.
+pub const RC_INVALID_ATTRIBUTE_SYNTAX: u32 = 21;
+/// The result code of no such object.
+pub const RC_NO_SUCH_OBJECT: u32 = 32;
+/// The result code of invalid credentials.
+pub const RC_INVALID_CREDENTIALS: u32 = 49;
+/// The result code of insufficient access rights.
+pub const RC_INSUFFICIENT_ACCESS_RIGHTS: u32 = 50;
+/// The result code of busy.
+pub const RC_BUSY: u32 = 51;
+/// The result code of unavailable.
+pub const RC_UNAVAILABLE: u32 = 52;
+/// The result code of unwilling to perform.
+pub const RC_UNWILLING_TO_PERFORM: u32 = 53;
+/// The result code of object class violation.
+pub const RC_OBJECT_CLASS_VIOLATION: u32 = 65;
+/// The result code of entry already exists.
+pub const RC_ENTRY_ALREADY_EXISTS: u32 = 68;
+
+/// The errors may occurred on accessing the directory.
+#[derive(Debug)]
+pub enum Error {
+    /// The entry does not exist, or the search found nothing.
+    NoSuchObject,
+    /// The password is wrong.
+    InvalidCredentials,
+    /// The entry to create exists already.
+    EntryAlreadyExists,
+    /// The values violate the schema, with the diagnostic message.
+    ConstraintViolation(String),
+    /// The bound user is not allowed to do the operation.
+    InsufficientAccessRights,
+    /// The server refused the operation, with the diagnostic message.
+    UnwillingToPerform(String),
+    /// The server is busy, unavailable or unreachable.
+    Unavailable(String),
+    /// The password policy of the server rejected the operation.
+    Policy(PolicyError),
+    /// The uploaded file is invalid, with the reason.
+    InvalidUpload(String),
+    /// The other errors.
+    Ldap(LdapError),
+}
+
+impl Error {
+    /// Returns the HTTP status of the error.
+    pub fn status(&self) -> Status {
+        match self {
+            Error::NoSuchObject => Status::NotFound,
+            Error::InvalidCredentials | Error::InsufficientAccessRights => Status::Forbidden,
+            Error::EntryAlreadyExists => Status::Conflict,
+            Error::ConstraintViolation(_) | Error::UnwillingToPerform(_) | Error::Policy(_) => {
+                Status::UnprocessableEntity
+            }
+            Error::Unavailable(_) => Status::ServiceUnavailable,
+            Error::InvalidUpload(_) => Status::BadRequest,
+            Error::Ldap(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Returns the message shown to the user.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Error::NoSuchObject => "账号不存在或已被删除",
+            Error::InvalidCredentials => "密码错误",
+            Error::EntryAlreadyExists => "用户名称或电子邮箱已被使用",
+            Error::ConstraintViolation(_) => "提交的内容不符合目录服务的要求，请检查后再次尝试",
+            Error::InsufficientAccessRights => "没有执行该操作的权限，请与管理员联系",
+            Error::UnwillingToPerform(_) => "目录服务拒绝执行该操作，请与管理员联系",
+            Error::Unavailable(_) => "目录服务暂时不可用，请稍后再次尝试",
+            Error::Policy(err) => err.message(),
+            Error::InvalidUpload(_) => "上传的图片无效，请更换后再次尝试",
+            Error::Ldap(_) => "目录服务出现错误，请与管理员联系",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSuchObject => write!(f, "noSuchObject"),
+            Error::InvalidCredentials => write!(f, "invalidCredentials"),
+            Error::EntryAlreadyExists => write!(f, "entryAlreadyExists"),
+            Error::ConstraintViolation(text) => write!(f, "constraintViolation: {}", text),
+            Error::InsufficientAccessRights => write!(f, "insufficientAccessRights"),
+            Error::UnwillingToPerform(text) => write!(f, "unwillingToPerform: {}", text),
+            Error::Unavailable(text) => write!(f, "unavailable: {}", text),
+            Error::Policy(err) => write!(f, "passwordPolicy: {:?}", err),
+            Error::InvalidUpload(reason) => write!(f, "invalidUpload: {}", reason),
+            Error::Ldap(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<LdapError> for Error {
+    fn from(err: LdapError) -> Self {
+        match err {
+            LdapError::LdapResult { result } => {
+                // The password policy error is more specific than the result code.
+                if let Some(error) =
+                    PolicyResponse::from_controls(&result.ctrls).and_then(|x| x.error)
+                {
+                    return Error::Policy(error);
+                }
+                match result.rc {
+                    RC_NO_SUCH_OBJECT => Error::NoSuchObject,
+                    RC_INVALID_CREDENTIALS => Error::InvalidCredentials,
+                    RC_ENTRY_ALREADY_EXISTS => Error::EntryAlreadyExists,
+                    RC_CONSTRAINT_VIOLATION
+                    | RC_INVALID_ATTRIBUTE_SYNTAX
+                    | RC_OBJECT_CLASS_VIOLATION => Error::ConstraintViolation(result.text),
+                    RC_INSUFFICIENT_ACCESS_RIGHTS => Error::InsufficientAccessRights,
+                    RC_UNWILLING_TO_PERFORM => Error::UnwillingToPerform(result.text),
+                    RC_BUSY | RC_UNAVAILABLE => Error::Unavailable(result.text),
+                    _ => Error::Ldap(LdapError::LdapResult { result }),
+                }
+            }
+            // The lookups return `EndOfStream` if nothing found.
+            LdapError::EndOfStream => Error::NoSuchObject,
+            LdapError::Io { .. }
+            | LdapError::OpSend { .. }
+            | LdapError::ResultRecv { .. }
+            | LdapError::Timeout { .. }
+            | LdapError::NativeTLS { .. } => Error::Unavailable(err.to_string()),
+            err => Error::Ldap(err),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        println!("{} {} failed: {}", request.method(), request.uri(), self);
+        Response::build_from(self.message().respond_to(request)?)
+            .status(self.status())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ldap::ppolicy::PPOLICY_OID;
+    use ldap3::controls::{Control, RawControl};
+    use ldap3::result::LdapResult;
+    use std::io;
+
+    fn result_with(rc: u32, ctrls: Vec<Control>) -> LdapError {
+        LdapError::LdapResult {
+            result: LdapResult {
+                rc,
+                matched: String::new(),
+                text: "text".to_string(),
+                refs: Vec::new(),
+                ctrls,
+            },
+        }
+    }
+
+    fn result(rc: u32) -> LdapError {
+        result_with(rc, Vec::new())
+    }
+
+    #[test]
+    fn test_error_from_ldap() {
+        let cases = vec![
+            (result(RC_NO_SUCH_OBJECT), Status::NotFound),
+            (result(RC_INVALID_CREDENTIALS), Status::Forbidden),
+            (result(RC_ENTRY_ALREADY_EXISTS), Status::Conflict),
+            (result(RC_CONSTRAINT_VIOLATION), Status::UnprocessableEntity),
+            (result(RC_INSUFFICIENT_ACCESS_RIGHTS), Status::Forbidden),
+            (result(RC_BUSY), Status::ServiceUnavailable),
+            (result(80), Status::InternalServerError),
+            (LdapError::EndOfStream, Status::NotFound),
+            (
+                LdapError::from(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
+                Status::ServiceUnavailable,
+            ),
+        ];
+        for (err, status) in cases {
+            assert_eq!(Error::from(err).status(), status);
+        }
+        match Error::from(result(RC_UNWILLING_TO_PERFORM)) {
+            Error::UnwillingToPerform(text) => assert_eq!(text, "text"),
+            err => panic!("unexpected {}", err),
+        }
+    }
+
+    #[test]
+    fn test_error_from_ppolicy() {
+        let control = |val: &[u8]| {
+            Control(
+                None,
+                RawControl {
+                    ctype: PPOLICY_OID.to_string(),
+                    crit: false,
+                    val: Some(val.to_vec()),
+                },
+            )
+        };
+        // passwordInHistory is reported with constraintViolation.
+        let err = Error::from(result_with(
+            RC_CONSTRAINT_VIOLATION,
+            vec![control(&[0x30, 0x03, 0x81, 0x01, 0x08])],
+        ));
+        match err {
+            Error::Policy(PolicyError::PasswordInHistory) => {}
+            ref err => panic!("unexpected {}", err),
+        }
+        assert_eq!(err.status(), Status::UnprocessableEntity);
+        assert_eq!(err.message(), PolicyError::PasswordInHistory.message());
+        // A warning only falls back to the result code.
+        let err = Error::from(result_with(
+            RC_INVALID_CREDENTIALS,
+            vec![control(&[0x30, 0x05, 0xa0, 0x03, 0x81, 0x01, 0x02])],
+        ));
+        assert_eq!(err.status(), Status::Forbidden);
+    }
+}
@@ -0,0 +1,197 @@
+use super::pool::{LdapPool, PoolStats, PooledConn};
+use super::LdapConfig;
+use ldap3::result::Result;
+use ldap3::LdapConn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// The LDAP servers, the writes go to the provider, and the reads go to the
+/// replicas if configured, except for the writers within `read_after_write`.
+#[derive(Clone)]
+pub struct LdapCluster {
+    pub provider: LdapPool,
+    pub replica: Option<LdapPool>,
+    /// The writers and the time of their last writes.
+    writes: Arc<RwLock<HashMap<String, Instant>>>,
+}
+
+/// The snapshot of the pools usage.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClusterStats {
+    pub provider: PoolStats,
+    pub replica: Option<PoolStats>,
+}
+
+impl LdapCluster {
+    /// Construct the pools of the servers of `cfg`.
+    pub fn new(cfg: &LdapConfig) -> Self {
+        Self {
+            provider: LdapPool::new(Clone::clone(cfg), &cfg.uris),
+            replica: if cfg.replica_uris.is_empty() {
+                None
+            } else {
+                Some(LdapPool::new(Clone::clone(cfg), &cfg.replica_uris))
+            },
+            writes: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn cfg(&self) -> &LdapConfig {
+        self.provider.cfg()
+    }
+
+    /// Record a write of `writer`, the expired records are removed.
+    pub fn record_write(&self, writer: &str) {
+        if self.replica.is_none() {
+            return;
+        }
+        let window = self.cfg().read_after_write();
+        if let Ok(mut x) = self.writes.write() {
+            x.retain(|_, v| v.elapsed() < window);
+            x.insert(writer.to_string(), Instant::now());
+        }
+    }
+
+    /// Returns true if `writer` wrote within `read_after_write`, the reads of it
+    /// go to the provider since the replicas may not be synchronized yet.
+    pub fn wrote_recently(&self, writer: &str) -> bool {
+        let window = self.cfg().read_after_write();
+        self.writes
+            .read()
+            .map(|x| x.get(writer).map_or(false, |v| v.elapsed() < window))
+            .unwrap_or(false)
+    }
+
+    /// Probe all the servers, the servers down are skipped until they are up again.
+    pub fn health_check(&self) {
+        self.provider.health_check();
+        if let Some(replica) = &self.replica {
+            replica.health_check();
+        }
+    }
+
+    /// Returns the snapshot of the pools usage.
+    pub fn stats(&self) -> ClusterStats {
+        ClusterStats {
+            provider: self.provider.stats(),
+            replica: self.replica.as_ref().map(|x| x.stats()),
+        }
+    }
+}
+
+/// The connections of a request by `writer`, checked out on demand.
+pub struct ClusterConns {
+    cluster: LdapCluster,
+    /// The key of the client to read its own writes, not tracked if unknown.
+    writer: Option<String>,
+    read: Option<PooledConn>,
+    write: Option<PooledConn>,
+}
+
+impl ClusterConns {
+    pub fn new(cluster: &LdapCluster, writer: Option<String>) -> Self {
+        Self {
+            cluster: Clone::clone(cluster),
+            writer,
+            read: None,
+            write: None,
+        }
+    }
+
+    /// Returns the connection for the reads, to a replica unless wrote recently,
+    /// fallback to the provider if no replica available.
+    pub fn read(&mut self) -> Result<&mut LdapConn> {
+        let replica = match &self.cluster.replica {
+            Some(v) if self.reads_from_replica() => Clone::clone(v),
+            _ => return self.provider(),
+        };
+        if self.read.is_none() {
+            match replica.get() {
+                Ok(con) => self.read = Some(con),
+                Err(err) => {
+                    println!("Failed to get a connection of the replicas: {}", err);
+                    return self.provider();
+                }
+            }
+        }
+        Ok(&mut **self.read.as_mut().expect("checked out above"))
+    }
+
+    /// Returns true if the reads go to the replicas, false if no replica, or the
+    /// writer wrote in this request or within `read_after_write`.
+    fn reads_from_replica(&self) -> bool {
+        self.cluster.replica.is_some()
+            && self.write.is_none()
+            && !self
+                .writer
+                .as_ref()
+                .map_or(false, |x| self.cluster.wrote_recently(x))
+    }
+
+    /// Returns the connection for the writes, to the provider.
+    pub fn write(&mut self) -> Result<&mut LdapConn> {
+        self.record_write();
+        self.provider()
+    }
+
+    /// Record a write done by a connection out of the pools, e.g. the user binds.
+    pub fn record_write(&self) {
+        if let Some(writer) = &self.writer {
+            self.cluster.record_write(writer);
+        }
+    }
+
+    /// Open a short-lived connection to the provider for the user binds.
+    pub fn connect_for_user(&self) -> Result<LdapConn> {
+        self.cluster.provider.connect_for_user()
+    }
+
+    fn provider(&mut self) -> Result<&mut LdapConn> {
+        if self.write.is_none() {
+            self.write = Some(self.cluster.provider.get()?);
+        }
+        Ok(&mut **self.write.as_mut().expect("checked out above"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn cluster(replica_uris: Vec<String>) -> LdapCluster {
+        let mut cfg = LdapConfig::from(&BTreeMap::new());
+        cfg.replica_uris = replica_uris;
+        LdapCluster::new(&cfg)
+    }
+
+    #[test]
+    fn test_read_after_write() {
+        let cluster = cluster(vec!["ldap://127.0.0.1:1".to_string()]);
+        let alice = ClusterConns::new(&cluster, Some("dn:uid=alice".to_string()));
+        let bob = ClusterConns::new(&cluster, Some("dn:uid=bob".to_string()));
+        let anonymous = ClusterConns::new(&cluster, None);
+        assert!(alice.reads_from_replica());
+        alice.record_write();
+        assert!(!alice.reads_from_replica());
+        // The other sessions of the writer read its writes too.
+        let another = ClusterConns::new(&cluster, Some("dn:uid=alice".to_string()));
+        assert!(!another.reads_from_replica());
+        assert!(bob.reads_from_replica());
+        // The writes of the unknown clients are not shared by each other.
+        anonymous.record_write();
+        assert!(anonymous.reads_from_replica());
+        assert!(bob.reads_from_replica());
+    }
+
+    #[test]
+    fn test_no_replica() {
+        let cluster = cluster(Vec::new());
+        let alice = ClusterConns::new(&cluster, Some("dn:uid=alice".to_string()));
+        assert!(!alice.reads_from_replica());
+        alice.record_write();
+        assert!(!cluster.wrote_recently("dn:uid=alice"));
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+
+/// Returns the `value` escaped for the assertion value in search filters (RFC 4515).
+pub fn escape_filter_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the `value` escaped for the attribute value in distinguished names (RFC 4514).
+pub fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\0' => escaped.push_str("\\00"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '#' if i == 0 => escaped.push_str("\\#"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns the `dn` in the canonical form to compare (RFC 4514), the types and
+/// the values are in lowercase without the spaces around the separators, the
+/// values are unescaped and escaped again, the multi-valued RDNs are sorted.
+pub fn normalize_dn(dn: &str) -> String {
+    let mut rdns = Vec::new();
+    let mut rdn = Vec::new();
+    let mut attr = String::new();
+    // The characters of the value, true if escaped.
+    let mut value: Vec<(char, bool)> = Vec::new();
+    // The bytes of the hex escapes not decoded to UTF-8 yet.
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut in_value = false;
+    let mut chars = dn.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            flush_bytes(&mut bytes, &mut value);
+        }
+        match c {
+            '\\' if in_value => match chars.next() {
+                Some(h)
+                    if h.is_ascii_hexdigit()
+                        && chars.peek().map_or(false, char::is_ascii_hexdigit) =>
+                {
+                    let l = chars.next().unwrap_or('0');
+                    let byte =
+                        (h.to_digit(16).unwrap_or(0) * 16 + l.to_digit(16).unwrap_or(0)) as u8;
+                    bytes.push(byte);
+                    if let Ok(s) = std::str::from_utf8(&bytes) {
+                        value.extend(s.chars().map(|x| (x, true)));
+                        bytes.clear();
+                    }
+                }
+                Some(x) => {
+                    flush_bytes(&mut bytes, &mut value);
+                    value.push((x, true));
+                }
+                None => {}
+            },
+            '=' if !in_value => in_value = true,
+            ',' | ';' | '+' if in_value => {
+                rdn.push(normalize_ava(&attr, &value));
+                attr.clear();
+                value.clear();
+                in_value = false;
+                if c != '+' {
+                    rdn.sort();
+                    rdns.push(rdn.join("+"));
+                    rdn.clear();
+                }
+            }
+            _ if in_value => value.push((c, false)),
+            _ => attr.push(c),
+        }
+    }
+    flush_bytes(&mut bytes, &mut value);
+    if in_value || !attr.trim().is_empty() {
+        rdn.push(normalize_ava(&attr, &value));
+    }
+    if !rdn.is_empty() {
+        rdn.sort();
+        rdns.push(rdn.join("+"));
+    }
+    rdns.join(",")
+}
+
+/// Append the `bytes` of the hex escapes not decoded yet to `value`.
+fn flush_bytes(bytes: &mut Vec<u8>, value: &mut Vec<(char, bool)>) {
+    if !bytes.is_empty() {
+        value.extend(String::from_utf8_lossy(bytes).chars().map(|x| (x, true)));
+        bytes.clear();
+    }
+}
+
+/// Returns the attribute value assertion of `attr` and `value` in the canonical
+/// form, the unescaped spaces around the value are removed.
+fn normalize_ava(attr: &str, value: &[(char, bool)]) -> String {
+    let kept = |(c, escaped): &(char, bool)| *escaped || *c != ' ';
+    let start = value.iter().position(kept).unwrap_or(value.len());
+    let end = value.iter().rposition(kept).map_or(start, |x| x + 1);
+    let value: String = value[start..end].iter().map(|(c, _)| *c).collect();
+    format!(
+        "{}={}",
+        attr.trim().to_lowercase(),
+        escape_dn_value(&value.to_lowercase())
+    )
+}
+
+/// Returns true if `dn` is `base` or under it.
+pub fn is_dn_under(dn: &str, base: &str) -> bool {
+    let dn = normalize_dn(dn);
+    let base = normalize_dn(base);
+    dn == base || dn.ends_with(&format!(",{}", base))
+}
+
+/// A search filter, the values are escaped on formatting.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    #[cfg(test)]
+    Not(Box<Filter>),
+    /// Equality match, `(attr=value)`.
+    Eq(String, String),
+    /// Substring match, `(attr=*value*)`.
+    Contains(String, String),
+    /// Presence match, `(attr=*)`.
+    Present(String),
+}
+
+impl Filter {
+    /// Returns a filter matches all of the `filters`.
+    pub fn and(filters: Vec<Filter>) -> Self {
+        Filter::And(filters)
+    }
+
+    /// Returns a filter matches any of the `filters`.
+    pub fn or(filters: Vec<Filter>) -> Self {
+        Filter::Or(filters)
+    }
+
+    /// Returns a filter matches if `filter` not matched.
+    #[cfg(test)]
+    pub fn not(filter: Filter) -> Self {
+        Filter::Not(Box::new(filter))
+    }
+
+    /// Returns a filter matches the `attr` equal to `value`.
+    pub fn eq<A: Into<String>, V: Into<String>>(attr: A, value: V) -> Self {
+        Filter::Eq(attr.into(), value.into())
+    }
+
+    /// Returns a filter matches the `attr` contains `value`.
+    pub fn contains<A: Into<String>, V: Into<String>>(attr: A, value: V) -> Self {
+        Filter::Contains(attr.into(), value.into())
+    }
+
+    /// Returns a filter matches the `attr` present.
+    pub fn present<A: Into<String>>(attr: A) -> Self {
+        Filter::Present(attr.into())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::And(filters) => {
+                write!(f, "(&")?;
+                for x in filters {
+                    write!(f, "{}", x)?;
+                }
+                write!(f, ")")
+            }
+            Filter::Or(filters) => {
+                write!(f, "(|")?;
+                for x in filters {
+                    write!(f, "{}", x)?;
+                }
+                write!(f, ")")
+            }
+            #[cfg(test)]
+            Filter::Not(filter) => write!(f, "(!{})", filter),
+            Filter::Eq(attr, value) => write!(f, "({}={})", attr, escape_filter_value(value)),
+            Filter::Contains(attr, value) => {
+                write!(f, "({}=*{}*)", attr, escape_filter_value(value))
+            }
+            Filter::Present(attr) => write!(f, "({}=*)", attr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_filter_value() {
+        assert_eq!(escape_filter_value("alice"), "alice");
+        assert_eq!(escape_filter_value("*"), "\\2a");
+        assert_eq!(escape_filter_value("*)(uid=*"), "\\2a\\29\\28uid=\\2a");
+        assert_eq!(escape_filter_value("a\\b"), "a\\5cb");
+        assert_eq!(escape_filter_value("a\0b"), "a\\00b");
+        assert_eq!(escape_filter_value("张三"), "张三");
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("alice,ou=admins"), "alice\\,ou\\=admins");
+        assert_eq!(escape_dn_value("a+b"), "a\\+b");
+        assert_eq!(escape_dn_value("\"<a>;\\"), "\\\"\\<a\\>\\;\\\\");
+        assert_eq!(escape_dn_value(" alice "), "\\ alice\\ ");
+        assert_eq!(escape_dn_value("#alice#"), "\\#alice#");
+        assert_eq!(escape_dn_value("a\0b"), "a\\00b");
+        assert_eq!(escape_dn_value(""), "");
+    }
+
+    #[test]
+    fn test_normalize_dn() {
+        assert_eq!(
+            normalize_dn("CN=Admins, OU=Groups ,dc=Example,dc=com"),
+            "cn=admins,ou=groups,dc=example,dc=com"
+        );
+        assert_eq!(
+            normalize_dn("cn=Doe\\, John,ou=people"),
+            "cn=doe\\, john,ou=people"
+        );
+        assert_eq!(
+            normalize_dn("cn=Doe\\2C John,ou=people"),
+            "cn=doe\\, john,ou=people"
+        );
+        assert_eq!(normalize_dn("cn=\\E5\\BC\\A0\\E4\\B8\\89"), "cn=张三");
+        assert_eq!(normalize_dn("cn=\\ a\\ ,dc=com"), "cn=\\ a\\ ,dc=com");
+        assert_eq!(
+            normalize_dn("uid=a+CN=B,dc=com"),
+            normalize_dn("cn=b + uid=a,dc=com")
+        );
+        assert_ne!(
+            normalize_dn("cn=a\\,dc=b,dc=com"),
+            normalize_dn("cn=a,dc=b,dc=com")
+        );
+        assert_eq!(normalize_dn(""), "");
+    }
+
+    #[test]
+    fn test_is_dn_under() {
+        assert!(is_dn_under("uid=a,ou=demo,dc=com", "ou=Demo, dc=com"));
+        assert!(is_dn_under("ou=demo,dc=com", "ou=demo,dc=com"));
+        assert!(!is_dn_under("uid=a,ou=demo2,dc=com", "ou=demo,dc=com"));
+        assert!(!is_dn_under("uid=a\\,ou=demo,dc=com", "ou=demo,dc=com"));
+        assert!(!is_dn_under("uid=a,ou=other,dc=com", "ou=demo,dc=com"));
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::and(vec![
+            Filter::eq("objectClass", "inetOrgPerson"),
+            Filter::or(vec![
+                Filter::eq("mail", "*)(uid=*"),
+                Filter::eq("uid", "*)(uid=*"),
+            ]),
+        ]);
+        assert_eq!(
+            filter.to_string(),
+            "(&(objectClass=inetOrgPerson)(|(mail=\\2a\\29\\28uid=\\2a)(uid=\\2a\\29\\28uid=\\2a)))"
+        );
+        assert_eq!(Filter::contains("cn", "a*b").to_string(), "(cn=*a\\2ab*)");
+        assert_eq!(
+            Filter::not(Filter::present("photo")).to_string(),
+            "(!(photo=*))"
+        );
+    }
+}
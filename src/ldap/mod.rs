@@ -1,49 +1,238 @@
-use crate::models::{NewPassword, NewUser, PasswordDigest, Person};
+use crate::config::{table_get_bool, table_get_int, table_get_string, table_get_strings};
+use crate::directory::Directory;
+use crate::models::{
+    client_ip, NewPassword, PasswordDigest, PasswordScheme, PendingRegistration, Person, Role,
+    SessionRef, UserListMeta, UserQuery, UserSummary, PWD_CHANGED_TIME, PWD_GRACE_USE_TIME,
+    PWD_RESET, SHADOW_LAST_CHANGE, SHADOW_MAX,
+};
+use ldap3::controls::{Control, ControlType, PagedResults, RawControl};
+use ldap3::exop::PasswordModify;
 use ldap3::result::{LdapError, Result};
-use ldap3::{LdapConn, Mod, Scope, SearchEntry};
+use ldap3::{LdapConn, LdapConnSettings, Mod, Scope, SearchEntry};
 use maplit::hashset;
+use native_tls::TlsConnector;
 use rocket::config::Value;
-use rocket::request::{FromRequest, Outcome, Request};
-use rocket::State;
-use std::collections::BTreeMap;
+use rocket::Request;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
 
+mod cluster;
+mod filter;
+mod pool;
+pub mod ppolicy;
+mod tls;
+
+pub use cluster::{ClusterConns, ClusterStats, LdapCluster};
+pub use filter::*;
+use ppolicy::PolicyResponse;
+pub use tls::TlsError;
+
+const DEFAULT_BACKEND: &str = "ldap";
 const DEFAULT_URI: &str = "ldap://127.0.0.1:10389";
 const DEFAULT_BASE_DN: &str = "dc=example,dc=com";
 const DEFAULT_ADMIN_DN: &str = "uid=admin,dc=example,dc=com";
 const DEFAULT_ADMIN_PWD: &str = "password of admin";
+const DEFAULT_TOTP_ATTRIBUTE: &str = "totpSecret";
+const DEFAULT_TOTP_RECOVERY_ATTRIBUTE: &str = "totpRecoveryCode";
+const DEFAULT_PASSWORD_SCHEME: &str = "SSHA256";
+const DEFAULT_MIN_TLS_VERSION: &str = "1.2";
+const DEFAULT_POOL_SIZE: i64 = 8;
+const DEFAULT_POOL_TIMEOUT: i64 = 5;
+const DEFAULT_POOL_IDLE_TIMEOUT: i64 = 300;
+const DEFAULT_POOL_CHECK_INTERVAL: i64 = 30;
+const DEFAULT_CONN_TIMEOUT: i64 = 5;
+const DEFAULT_FAILOVER_COOLDOWN: i64 = 30;
+const DEFAULT_READ_AFTER_WRITE: i64 = 30;
+const DEFAULT_HEALTH_CHECK_INTERVAL: i64 = 10;
+/// The operational attribute set by the password policy overlay (ppolicy) on lockout.
+pub const PWD_ACCOUNT_LOCKED_TIME: &str = "pwdAccountLockedTime";
+
+/// The attributes of the users in the listing.
+const USER_ATTRS: [&str; 5] = ["uid", "cn", "sn", "mail", "createTimestamp"];
+/// The OID of the server side sort request control of RFC 2891.
+const SORT_REQUEST_OID: &str = "1.2.840.113556.1.4.473";
+
+/// Returns the server side sort request control to sort by `attr`, not
+/// critical, the results are unsorted if the server doesn't support it.
+fn sort_control(attr: &str, reverse: bool) -> RawControl {
+    // SEQUENCE OF SEQUENCE { attributeType OCTET STRING, reverseOrder [1] BOOLEAN }
+    let mut key = vec![0x04, attr.len() as u8];
+    key.extend(attr.as_bytes());
+    if reverse {
+        key.extend(&[0x81, 0x01, 0xff]);
+    }
+    let mut keys = vec![0x30, key.len() as u8];
+    keys.extend(key);
+    let mut val = vec![0x30, keys.len() as u8];
+    val.extend(keys);
+    RawControl {
+        ctype: SORT_REQUEST_OID.to_string(),
+        crit: false,
+        val: Some(val),
+    }
+}
 
-/// The accessor for LDAP.
+/// The accessor for LDAP, the connections are checked out from the pools and
+/// bound as admin, the user binds are done by the short-lived connections.
+/// The searches go to the replicas if configured, the writes to the provider.
 pub struct LdapAccessor {
     pub cfg: LdapConfig,
-    pub con: LdapConn,
+    pub conns: ClusterConns,
 }
 
 impl LdapAccessor {
-    /// Construct a new ldap accessor.
-    pub fn new(cfg: &LdapConfig) -> Result<Self> {
-        Ok(Self {
-            cfg: Clone::clone(cfg),
-            con: LdapConn::new(&cfg.uri)?,
-        })
+    /// Construct a new ldap accessor of `cluster` for `writer`, the key of the
+    /// client to read its own writes, not tracked if unknown.
+    pub fn new(cluster: &LdapCluster, writer: Option<String>) -> Self {
+        Self {
+            cfg: Clone::clone(cluster.cfg()),
+            conns: ClusterConns::new(cluster, writer),
+        }
+    }
+
+    /// Construct a new ldap accessor of `cluster` for `request`, the writer is
+    /// keyed by the dn of the session, so all sessions of the user read its own
+    /// writes, or the client ip if not logged in. The connections are checked
+    /// out on demand, the failures are reported by the operations.
+    pub fn for_request(cluster: &LdapCluster, request: &Request) -> Self {
+        let writer = request
+            .guard::<SessionRef>()
+            .succeeded()
+            .map(|x| format!("dn:{}", normalize_dn(&x.dn)))
+            .or_else(|| client_ip(request).map(|x| format!("ip:{}", x)));
+        LdapAccessor::new(cluster, writer)
+    }
+
+    /// Open a short-lived connection bound as `user_dn` with the password policy
+    /// request control, returns it and the password policy response if attached.
+    fn user_conn(
+        &self,
+        user_dn: &str,
+        user_pwd: &str,
+    ) -> Result<(LdapConn, Option<PolicyResponse>)> {
+        let mut con = self.conns.connect_for_user()?;
+        let res = con
+            .with_controls(ppolicy::request_control())
+            .simple_bind(user_dn, user_pwd)?
+            .success()?;
+        Ok((con, PolicyResponse::from_controls(&res.ctrls)))
+    }
+
+    /// Returns the dn of the groups which the user specified by `user_dn` is a member of,
+    /// both `groupOfNames`/`groupOfUniqueNames` under `group_base_dn` if configured and
+    /// the `memberOf` attribute of the user are looked up.
+    pub fn groups_of<D>(&mut self, user_dn: D) -> Result<Vec<String>>
+    where
+        D: AsRef<str>,
+    {
+        let mut groups = Vec::new();
+        let (rs, _res) = self
+            .conns
+            .read()?
+            .search(
+                user_dn.as_ref(),
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["memberOf"],
+            )?
+            .success()?;
+        for entry in rs.into_iter().map(SearchEntry::construct) {
+            groups.extend(entry.attrs.get("memberOf").cloned().unwrap_or_default());
+        }
+        if self.cfg.group_base_dn.is_empty() {
+            return Ok(groups);
+        }
+        let filter = Filter::or(vec![
+            Filter::and(vec![
+                Filter::eq("objectClass", "groupOfNames"),
+                Filter::eq("member", user_dn.as_ref()),
+            ]),
+            Filter::and(vec![
+                Filter::eq("objectClass", "groupOfUniqueNames"),
+                Filter::eq("uniqueMember", user_dn.as_ref()),
+            ]),
+        ])
+        .to_string();
+        let (rs, _res) = self
+            .conns
+            .read()?
+            .search(&self.cfg.group_base_dn, Scope::Subtree, &filter, vec!["cn"])?
+            .success()?;
+        groups.extend(rs.into_iter().map(|x| SearchEntry::construct(x).dn));
+        Ok(groups)
     }
 
-    /// Returns the entry under `base_dn` and the `uid` or `mail` match to `username`.
-    pub fn entry_of_username<N>(&mut self, username: N) -> Result<SearchEntry>
+    /// Add the user specified by `user_dn` to the group specified by `group_dn`,
+    /// `uniqueMember` for `groupOfUniqueNames`, otherwise `member`.
+    fn add_member<G, D>(&mut self, group_dn: G, user_dn: D) -> Result<()>
     where
-        N: AsRef<str>,
+        G: AsRef<str>,
+        D: AsRef<str>,
     {
-        let filter = format!(
-            "(&(objectClass=inetOrgPerson)(|(mail={})(uid={})))",
-            username.as_ref(),
-            username.as_ref()
-        );
         let (rs, _res) = self
-            .con
+            .conns
+            .read()?
+            .search(
+                group_dn.as_ref(),
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["objectClass"],
+            )?
+            .success()?;
+        let unique = rs
+            .into_iter()
+            .map(SearchEntry::construct)
+            .flat_map(|x| x.attrs.get("objectClass").cloned().unwrap_or_default())
+            .any(|x| x.eq_ignore_ascii_case("groupOfUniqueNames"));
+        let attr = if unique { "uniqueMember" } else { "member" };
+        self.conns
+            .write()?
+            .modify(
+                group_dn.as_ref(),
+                vec![Mod::Add(attr, hashset! { user_dn.as_ref() })],
+            )?
+            .success()?;
+        Ok(())
+    }
+}
+
+impl Directory for LdapAccessor {
+    fn cfg(&self) -> &LdapConfig {
+        &self.cfg
+    }
+
+    fn entry_of_username(&mut self, username: &str) -> Result<SearchEntry> {
+        let filter = Filter::and(vec![
+            Filter::eq("objectClass", "inetOrgPerson"),
+            Filter::or(vec![
+                Filter::eq("mail", username),
+                Filter::eq("uid", username),
+            ]),
+        ])
+        .to_string();
+        let (rs, _res) = self
+            .conns
+            .read()?
             .search(
                 &self.cfg.base_dn,
                 Scope::Subtree,
                 &filter,
-                vec!["uid", "cn", "sn", "mail", "photo", "createTimestamp"],
+                vec![
+                    "uid",
+                    "cn",
+                    "sn",
+                    "mail",
+                    "photo",
+                    "createTimestamp",
+                    self.cfg.totp_attribute.as_str(),
+                    self.cfg.totp_recovery_attribute.as_str(),
+                    PWD_ACCOUNT_LOCKED_TIME,
+                    PWD_CHANGED_TIME,
+                    PWD_GRACE_USE_TIME,
+                    PWD_RESET,
+                    SHADOW_LAST_CHANGE,
+                    SHADOW_MAX,
+                ],
             )?
             .success()?;
         rs.into_iter()
@@ -52,13 +241,109 @@ impl LdapAccessor {
             .ok_or(LdapError::EndOfStream)
     }
 
-    pub fn new_user(&mut self, user: &NewUser) -> Result<()> {
-        self.con
-            .simple_bind(&self.cfg.admin_dn, &self.cfg.admin_pwd)?
+    fn roles_of(&mut self, user_dn: &str) -> Result<Vec<Role>> {
+        let groups = self.groups_of(user_dn)?;
+        Ok(self.cfg.roles_of_groups(&groups))
+    }
+
+    fn search_users(&mut self, query: &UserQuery) -> Result<(Vec<UserSummary>, UserListMeta)> {
+        let mut filters = vec![Filter::eq("objectClass", "inetOrgPerson")];
+        if let Some(keyword) = query.q.as_deref().filter(|x| !x.is_empty()) {
+            filters.push(Filter::or(vec![
+                Filter::contains("uid", keyword),
+                Filter::contains("cn", keyword),
+                Filter::contains("mail", keyword),
+            ]));
+        }
+        let filter = Filter::and(filters).to_string();
+        let sort = sort_control(query.sort_attr(), query.desc());
+        // The pages before the requested one are skipped, the cookie is bound
+        // to the connection, so all pages are read by the same one.
+        let con = self.conns.read()?;
+        let mut cookie = Vec::new();
+        let mut page = 0;
+        loop {
+            page += 1;
+            let (rs, res) = con
+                .with_controls(vec![
+                    RawControl::from(PagedResults {
+                        size: query.per_page() as i32,
+                        cookie,
+                    }),
+                    Clone::clone(&sort),
+                ])
+                .search(
+                    &self.cfg.base_dn,
+                    Scope::Subtree,
+                    &filter,
+                    USER_ATTRS.to_vec(),
+                )?
+                .success()?;
+            let paged = res.ctrls.iter().find_map(|x| match x {
+                Control(Some(ControlType::PagedResults), raw) => Some(raw.parse::<PagedResults>()),
+                _ => None,
+            });
+            cookie = paged
+                .as_ref()
+                .map(|x| Clone::clone(&x.cookie))
+                .unwrap_or_default();
+            if page < query.page() && !cookie.is_empty() {
+                continue;
+            }
+            let more = !cookie.is_empty();
+            if more {
+                // Release the results left on the server.
+                let _ = con
+                    .with_controls(RawControl::from(PagedResults { size: 0, cookie }))
+                    .search(
+                        &self.cfg.base_dn,
+                        Scope::Subtree,
+                        &filter,
+                        USER_ATTRS.to_vec(),
+                    );
+            }
+            let total = paged.map(|x| x.size).filter(|x| *x > 0).map(|x| x as usize);
+            let users = rs
+                .into_iter()
+                .map(|x| UserSummary::from(SearchEntry::construct(x)))
+                .collect();
+            return Ok(query.paged(users, page, total, more));
+        }
+    }
+
+    fn locked_users(&mut self) -> Result<Vec<SearchEntry>> {
+        let filter = Filter::and(vec![
+            Filter::eq("objectClass", "inetOrgPerson"),
+            Filter::present(PWD_ACCOUNT_LOCKED_TIME),
+        ])
+        .to_string();
+        let (rs, _res) = self
+            .conns
+            .read()?
+            .search(
+                &self.cfg.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec!["uid", PWD_ACCOUNT_LOCKED_TIME],
+            )?
             .success()?;
-        let dn = format!("uid={},{}", user.uid, self.cfg.base_dn);
-        let ssha256_pwd = format!("{{SSHA256}}{}", user.ssha256());
-        self.con
+        Ok(rs.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    fn unlock_user(&mut self, user_dn: &str) -> Result<()> {
+        let mod_options = vec![Mod::Delete(PWD_ACCOUNT_LOCKED_TIME, HashSet::new())];
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
+            .success()?;
+        Ok(())
+    }
+
+    fn new_user(&mut self, user: &PendingRegistration) -> Result<()> {
+        let parent = user.ou.as_ref().unwrap_or(&self.cfg.base_dn);
+        let dn = format!("uid={},{}", escape_dn_value(&user.uid), parent);
+        self.conns
+            .write()?
             .add(
                 &dn,
                 vec![
@@ -67,114 +352,371 @@ impl LdapAccessor {
                     ("cn", hashset! { user.cn.as_str() }),
                     ("sn", hashset! { user.cn.as_str() }),
                     ("mail", hashset! { user.mail.as_str() }),
-                    ("userPassword", hashset! { ssha256_pwd.as_str() }),
+                    ("userPassword", hashset! { user.password_digest.as_str() }),
                 ],
             )?
             .success()?;
+        for group in user.groups.iter() {
+            if let Err(err) = self.add_member(group, &dn) {
+                println!("Failed to add {} to group {}: {}", dn, group, err);
+            }
+        }
         Ok(())
     }
 
-    /// Update user password to specfied with `user_dn`.
-    pub fn update_password<D>(&mut self, user_dn: D, new_password: &NewPassword) -> Result<()>
-    where
-        D: AsRef<str>,
-    {
-        self.con
-            .simple_bind(user_dn.as_ref(), new_password.old_password.as_ref())?
-            .success()?;
-        let ssha256_pwd = format!("{{SSHA256}}{}", new_password.ssha256());
-        let mod_options = vec![Mod::Replace(
-            "userPassword",
-            hashset! { ssha256_pwd.as_str() },
-        )];
-        self.con.modify(user_dn.as_ref(), mod_options)?.success()?;
+    fn update_password(&mut self, user_dn: &str, new_password: &NewPassword) -> Result<()> {
+        let (mut con, _) = self.user_conn(user_dn, &new_password.old_password)?;
+        if self.cfg.password_modify {
+            password_modify(
+                &mut con,
+                user_dn,
+                Some(new_password.old_password.as_str()),
+                new_password.password(),
+            )?;
+        } else {
+            let digest = new_password.digest(self.cfg.password_scheme);
+            let mod_options = vec![Mod::Replace("userPassword", hashset! { digest.as_str() })];
+            con.modify(user_dn, mod_options)?.success()?;
+        }
+        self.conns.record_write();
+        let _ = con.unbind();
         Ok(())
     }
 
-    /// Update person attributes to specfied with `user_dn`.
-    pub fn update_person<D>(&mut self, user_dn: D, person: &Person) -> Result<()>
-    where
-        D: AsRef<str>,
-    {
-        self.con
-            .simple_bind(&self.cfg.admin_dn, &self.cfg.admin_pwd)?
+    fn reset_password(&mut self, user_dn: &str, new_password: &dyn PasswordDigest) -> Result<()> {
+        if self.cfg.password_modify {
+            return password_modify(self.conns.write()?, user_dn, None, new_password.password());
+        }
+        let digest = new_password.digest(self.cfg.password_scheme);
+        let mod_options = vec![Mod::Replace("userPassword", hashset! { digest.as_str() })];
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
             .success()?;
+        Ok(())
+    }
+
+    fn update_person(&mut self, user_dn: &str, person: &Person) -> Result<()> {
         let mod_options = vec![
             Mod::Replace("cn", hashset! { person.cn.as_str() }),
             Mod::Replace("mail", hashset! { person.mail.as_str() }),
             // Mod::Replace("location", hashset! { person.location.as_str() }),
         ];
-        self.con.modify(user_dn.as_ref(), mod_options)?.success()?;
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
+            .success()?;
         Ok(())
     }
 
-    /// Update photo with `bytes` to specfied with `user_dn`.
-    pub fn update_photo<D>(&mut self, user_dn: D, bytes: &[u8]) -> Result<()>
-    where
-        D: AsRef<str>,
-    {
-        self.con
-            .simple_bind(&self.cfg.admin_dn, &self.cfg.admin_pwd)?
-            .success()?;
+    fn update_photo(&mut self, user_dn: &str, bytes: &[u8]) -> Result<()> {
         let photo_content = unsafe { std::str::from_utf8_unchecked(bytes) };
         let mod_options = vec![Mod::Replace("photo", hashset! { photo_content })];
-        self.con.modify(user_dn.as_ref(), mod_options)?.success()?;
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
+            .success()?;
         Ok(())
     }
 
-    /// Returns true if the the password matched.
-    pub fn verify_password<D, P>(&mut self, user_dn: D, user_pwd: P) -> bool
-    where
-        D: AsRef<str>,
-        P: AsRef<str>,
-    {
-        self.con
-            .simple_bind(user_dn.as_ref(), user_pwd.as_ref())
-            .map_or(false, |x| x.success().is_ok())
+    fn update_totp(
+        &mut self,
+        user_dn: &str,
+        secret: Option<&str>,
+        recovery_codes: &[String],
+    ) -> Result<()> {
+        let digests: Vec<String> = recovery_codes
+            .iter()
+            .map(|x| format!("{{SSHA256}}{}", x.as_str().ssha256()))
+            .collect();
+        let mod_options = vec![
+            Mod::Replace(
+                self.cfg.totp_attribute.as_str(),
+                secret.into_iter().collect(),
+            ),
+            Mod::Replace(
+                self.cfg.totp_recovery_attribute.as_str(),
+                digests.iter().map(|x| x.as_str()).collect(),
+            ),
+        ];
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
+            .success()?;
+        Ok(())
     }
-}
 
-impl Drop for LdapAccessor {
-    fn drop(&mut self) {
-        self.con.unbind().unwrap();
+    fn remove_recovery_code(&mut self, user_dn: &str, digest: &str) -> Result<()> {
+        let mod_options = vec![Mod::Delete(
+            self.cfg.totp_recovery_attribute.as_str(),
+            hashset! { digest },
+        )];
+        self.conns
+            .write()?
+            .modify(user_dn, mod_options)?
+            .success()?;
+        Ok(())
     }
-}
 
-impl<'a, 'r> FromRequest<'a, 'r> for LdapAccessor {
-    type Error = ();
+    fn verify_password(&mut self, user_dn: &str, user_pwd: &str) -> bool {
+        self.user_conn(user_dn, user_pwd)
+            .map(|(mut con, _)| {
+                let _ = con.unbind();
+            })
+            .is_ok()
+    }
 
-    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
-        let cfg = request.guard::<State<LdapConfig>>()?;
-        match LdapAccessor::new(&cfg) {
-            Ok(v) => Outcome::Success(v),
-            Err(_) => Outcome::Forward(()),
-        }
+    fn bind_user(&mut self, user_dn: &str, user_pwd: &str) -> Result<Option<PolicyResponse>> {
+        let (mut con, response) = self.user_conn(user_dn, user_pwd)?;
+        let _ = con.unbind();
+        Ok(response)
     }
 }
 
-/// Returns the string specified by name in the table.
-fn table_get_string(table: &BTreeMap<String, Value>, name: &str, def_val: &str) -> String {
-    table
-        .get(name)
-        .map_or(def_val, |x| x.as_str().unwrap_or(def_val))
-        .to_string()
+/// Change the password of `user_dn` by the Password Modify extended operation
+/// (RFC 3062) as the user bound to `con`, the server hashes the password and
+/// enforces its password policy. The policy error is attached to the error
+/// result, see `crate::error::Error`.
+fn password_modify(
+    con: &mut LdapConn,
+    user_dn: &str,
+    old_pass: Option<&str>,
+    new_pass: &str,
+) -> Result<()> {
+    con.with_controls(ppolicy::request_control())
+        .extended(PasswordModify {
+            user_id: Some(user_dn),
+            old_pass,
+            new_pass: Some(new_pass),
+        })?
+        .success()?;
+    Ok(())
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct LdapConfig {
-    pub uri: String,
+    /// The backend of the directory, `ldap`, or `memory` for the tests and the demos.
+    pub backend: String,
+    /// The LDIF file to seed the `memory` backend.
+    #[cfg(any(test, feature = "demo"))]
+    pub ldif: String,
+    /// The servers accept the writes, tried in order.
+    pub uris: Vec<String>,
+    /// The read-only replicas for the searches, tried in order.
+    pub replica_uris: Vec<String>,
+    /// The seconds to skip a server after failed to connect.
+    pub failover_cooldown: i64,
+    /// The seconds to read from the provider after a client wrote.
+    pub read_after_write: i64,
+    /// The interval in seconds to probe the servers, 0 to disable.
+    pub health_check_interval: i64,
     pub base_dn: String,
     pub admin_dn: String,
     pub admin_pwd: String,
+    /// The base dn to look up the groups by the members, only the `memberOf` of
+    /// the users is looked up if empty.
+    pub group_base_dn: String,
+    /// The dn of the groups granting the roles, by the names of the roles.
+    pub roles: BTreeMap<String, Vec<String>>,
+    /// The `pwdLockoutDuration` in seconds of the password policy, the accounts
+    /// locked by the policy are locked until unlocked by the administrators if 0.
+    pub pwd_lockout_duration: i64,
+    /// The attribute to store the TOTP secret.
+    pub totp_attribute: String,
+    /// The attribute to store the digests of TOTP recovery codes.
+    pub totp_recovery_attribute: String,
+    /// The scheme of the `userPassword` values written.
+    pub password_scheme: PasswordScheme,
+    /// Change the passwords by the Password Modify extended operation instead of
+    /// replacing `userPassword` with the digest.
+    pub password_modify: bool,
+    /// Upgrade the `ldap://` connections by the StartTLS extended operation.
+    pub starttls: bool,
+    /// The PEM file of the CA certificates to verify the server, trusted in
+    /// addition to the system roots.
+    pub ca_file: String,
+    /// The PEM file of the client certificate.
+    pub client_cert: String,
+    /// The PEM file of the PKCS#8 private key of the client certificate.
+    pub client_key: String,
+    /// Verify the hostname of the server certificate.
+    pub verify_hostname: bool,
+    /// The minimum TLS version, `1.0`, `1.1` or `1.2`.
+    pub min_tls_version: String,
+    /// The TLS connector built by `load_tls`.
+    pub connector: Option<TlsConnector>,
+    /// The maximum connections of the pool.
+    pub pool_size: usize,
+    /// The seconds to wait for a connection if all in use.
+    pub pool_timeout: i64,
+    /// The seconds to close the idle connections.
+    pub pool_idle_timeout: i64,
+    /// The seconds to check the idle connections before reusing.
+    pub pool_check_interval: i64,
+    /// The seconds to wait for establishing the connections.
+    pub conn_timeout: i64,
+}
+
+impl LdapConfig {
+    /// Returns error if the settings in `table` are invalid, which can't fallback
+    /// to the defaults silently.
+    pub fn validate(table: &BTreeMap<String, Value>) -> std::result::Result<(), String> {
+        table_get_string(table, "password_scheme", DEFAULT_PASSWORD_SCHEME)
+            .parse::<PasswordScheme>()
+            .map(|_| ())
+    }
+
+    /// Returns the uris of all the servers.
+    fn all_uris(&self) -> impl Iterator<Item = &String> {
+        self.uris.iter().chain(self.replica_uris.iter())
+    }
+
+    /// Returns true if the connections to all the servers are encrypted by
+    /// `ldaps://` or StartTLS.
+    pub fn uses_tls(&self) -> bool {
+        self.starttls || self.all_uris().all(|x| is_ldaps(x))
+    }
+
+    /// Build the TLS connector if any connection is encrypted, the CA and the
+    /// client certificate are loaded.
+    pub fn load_tls(&mut self) -> std::result::Result<(), TlsError> {
+        self.connector = if self.starttls || self.all_uris().any(|x| is_ldaps(x)) {
+            Some(tls::build_connector(self)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    pub fn failover_cooldown(&self) -> Duration {
+        Duration::from_secs(self.failover_cooldown as u64)
+    }
+
+    pub fn read_after_write(&self) -> Duration {
+        Duration::from_secs(self.read_after_write as u64)
+    }
+
+    /// Returns the interval to probe the servers, None if disabled.
+    pub fn health_check_interval(&self) -> Option<Duration> {
+        Some(self.health_check_interval)
+            .filter(|x| *x > 0)
+            .map(|x| Duration::from_secs(x as u64))
+    }
+
+    pub fn pool_timeout(&self) -> Duration {
+        Duration::from_secs(self.pool_timeout as u64)
+    }
+
+    pub fn pool_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.pool_idle_timeout as u64)
+    }
+
+    pub fn pool_check_interval(&self) -> Duration {
+        Duration::from_secs(self.pool_check_interval as u64)
+    }
+
+    /// Returns the settings to open the connections.
+    pub fn conn_settings(&self) -> LdapConnSettings {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(self.conn_timeout as u64))
+            .set_starttls(self.starttls);
+        match &self.connector {
+            Some(connector) => settings.set_connector(Clone::clone(connector)),
+            None => settings,
+        }
+    }
+
+    /// Returns true if `dn` is the `base_dn` or under it.
+    pub fn is_under_base_dn(&self, dn: &str) -> bool {
+        is_dn_under(dn, &self.base_dn)
+    }
+
+    /// Returns the roles granted by the membership of `groups`, the `user` role
+    /// is granted to everyone.
+    pub fn roles_of_groups(&self, groups: &[String]) -> Vec<Role> {
+        let groups: Vec<String> = groups.iter().map(|x| normalize_dn(x)).collect();
+        let mut roles: Vec<Role> = self
+            .roles
+            .iter()
+            .filter(|(_, configured)| configured.iter().any(|x| groups.contains(&normalize_dn(x))))
+            .map(|(name, _)| Role::from(name.as_str()))
+            .filter(|x| *x != Role::USER)
+            .collect();
+        roles.push(Role::USER);
+        roles
+    }
+}
+
+fn is_ldaps(uri: &str) -> bool {
+    uri.to_lowercase().starts_with("ldaps://")
 }
 
 impl From<&BTreeMap<String, Value>> for LdapConfig {
     fn from(table: &BTreeMap<String, Value>) -> Self {
         Self {
-            uri: table_get_string(table, "uri", DEFAULT_URI),
+            backend: table_get_string(table, "backend", DEFAULT_BACKEND),
+            #[cfg(any(test, feature = "demo"))]
+            ldif: table_get_string(table, "ldif", ""),
+            uris: match table.get("uri").and_then(|x| x.as_str()) {
+                Some(uri) => vec![uri.to_string()],
+                None => Some(table_get_strings(table, "uri"))
+                    .filter(|x| !x.is_empty())
+                    .unwrap_or_else(|| vec![DEFAULT_URI.to_string()]),
+            },
+            replica_uris: table_get_strings(table, "replica_uris"),
+            failover_cooldown: table_get_int(table, "failover_cooldown", DEFAULT_FAILOVER_COOLDOWN)
+                .max(0),
+            read_after_write: table_get_int(table, "read_after_write", DEFAULT_READ_AFTER_WRITE)
+                .max(0),
+            health_check_interval: table_get_int(
+                table,
+                "health_check_interval",
+                DEFAULT_HEALTH_CHECK_INTERVAL,
+            )
+            .max(0),
             base_dn: table_get_string(table, "base_dn", DEFAULT_BASE_DN),
             admin_dn: table_get_string(table, "admin_dn", DEFAULT_ADMIN_DN),
             admin_pwd: table_get_string(table, "admin_pwd", DEFAULT_ADMIN_PWD),
+            group_base_dn: table_get_string(table, "group_base_dn", ""),
+            roles: table
+                .get("roles")
+                .and_then(|x| x.as_table())
+                .map(|roles| {
+                    roles
+                        .keys()
+                        .map(|name| (Clone::clone(name), table_get_strings(roles, name)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            pwd_lockout_duration: table_get_int(table, "pwd_lockout_duration", 0).max(0),
+            totp_attribute: table_get_string(table, "totp_attribute", DEFAULT_TOTP_ATTRIBUTE),
+            totp_recovery_attribute: table_get_string(
+                table,
+                "totp_recovery_attribute",
+                DEFAULT_TOTP_RECOVERY_ATTRIBUTE,
+            ),
+            password_scheme: table_get_string(table, "password_scheme", DEFAULT_PASSWORD_SCHEME)
+                .parse()
+                .unwrap_or_default(),
+            password_modify: table_get_bool(table, "password_modify", false),
+            starttls: table_get_bool(table, "starttls", false),
+            ca_file: table_get_string(table, "ca_file", ""),
+            client_cert: table_get_string(table, "client_cert", ""),
+            client_key: table_get_string(table, "client_key", ""),
+            verify_hostname: table_get_bool(table, "verify_hostname", true),
+            min_tls_version: table_get_string(table, "min_tls_version", DEFAULT_MIN_TLS_VERSION),
+            connector: None,
+            pool_size: table_get_int(table, "pool_size", DEFAULT_POOL_SIZE).max(1) as usize,
+            pool_timeout: table_get_int(table, "pool_timeout", DEFAULT_POOL_TIMEOUT).max(0),
+            pool_idle_timeout: table_get_int(table, "pool_idle_timeout", DEFAULT_POOL_IDLE_TIMEOUT)
+                .max(0),
+            pool_check_interval: table_get_int(
+                table,
+                "pool_check_interval",
+                DEFAULT_POOL_CHECK_INTERVAL,
+            )
+            .max(0),
+            conn_timeout: table_get_int(table, "conn_timeout", DEFAULT_CONN_TIMEOUT).max(1),
         }
     }
 }
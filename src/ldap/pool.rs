@@ -0,0 +1,442 @@
+use super::LdapConfig;
+use ldap3::result::{LdapError, Result};
+use ldap3::LdapConn;
+use serde::Serialize;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The idle connection in the pool.
+struct IdleConn {
+    con: LdapConn,
+    last_used: Instant,
+}
+
+struct PoolState {
+    idle: Vec<IdleConn>,
+    /// The connections checked out or being opened.
+    active: usize,
+}
+
+/// The counters of the pool usage.
+#[derive(Default)]
+struct PoolCounters {
+    created: AtomicU64,
+    reused: AtomicU64,
+    rebound: AtomicU64,
+    discarded: AtomicU64,
+    waits: AtomicU64,
+    timeouts: AtomicU64,
+    failures: AtomicU64,
+    user_binds: AtomicU64,
+}
+
+/// The snapshot of the pool usage.
+#[derive(Clone, Debug, Serialize)]
+pub struct PoolStats {
+    pub size: usize,
+    pub active: usize,
+    pub idle: usize,
+    /// The connections opened and bound as admin.
+    pub created: u64,
+    /// The idle connections checked out again.
+    pub reused: u64,
+    /// The idle connections re-bound by the health check.
+    pub rebound: u64,
+    /// The connections closed, broken or idle too long.
+    pub discarded: u64,
+    /// The checkouts waited for a connection released.
+    pub waits: u64,
+    /// The checkouts failed since no connection released in time.
+    pub timeouts: u64,
+    /// The connections failed to open or bind.
+    pub failures: u64,
+    /// The short-lived connections opened for the user binds.
+    pub user_binds: u64,
+    pub servers: Vec<ServerStats>,
+}
+
+/// The snapshot of a server of the pool.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerStats {
+    pub uri: String,
+    /// False if failed to connect within `failover_cooldown`.
+    pub up: bool,
+    /// The connections failed to open.
+    pub failures: u64,
+}
+
+/// A server of the pool, skipped for `failover_cooldown` after failed to connect.
+struct Server {
+    uri: String,
+    down_until: Mutex<Option<Instant>>,
+    failures: AtomicU64,
+}
+
+impl Server {
+    fn new(uri: String) -> Self {
+        Self {
+            uri,
+            down_until: Mutex::new(None),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    fn is_down(&self, now: Instant) -> bool {
+        self.down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map_or(false, |x| x > now)
+    }
+
+    /// Mark the server down for `cooldown`.
+    fn mark_down(&self, cooldown: Duration) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        *self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now() + cooldown);
+    }
+
+    fn mark_up(&self) {
+        *self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+struct PoolShared {
+    cfg: LdapConfig,
+    servers: Vec<Server>,
+    state: Mutex<PoolState>,
+    released: Condvar,
+    counters: PoolCounters,
+}
+
+impl PoolShared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Give back a slot of the pool, `con` is kept if still usable.
+    fn release(&self, con: Option<LdapConn>) {
+        let mut state = self.lock();
+        state.active -= 1;
+        if let Some(mut con) = con {
+            if con.is_closed() {
+                self.counters.discarded.fetch_add(1, Ordering::Relaxed);
+            } else {
+                state.idle.push(IdleConn {
+                    con,
+                    last_used: Instant::now(),
+                });
+            }
+        }
+        self.released.notify_one();
+    }
+}
+
+/// The bounded pool of the service connections bound as admin, the connections
+/// are opened to the first server available of `uris`.
+#[derive(Clone)]
+pub struct LdapPool {
+    shared: Arc<PoolShared>,
+}
+
+impl LdapPool {
+    /// Construct a new pool of the servers `uris`, the connections are opened on demand.
+    pub fn new(cfg: LdapConfig, uris: &[String]) -> Self {
+        Self {
+            shared: Arc::new(PoolShared {
+                cfg,
+                servers: uris.iter().map(|x| Server::new(Clone::clone(x))).collect(),
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    active: 0,
+                }),
+                released: Condvar::new(),
+                counters: PoolCounters::default(),
+            }),
+        }
+    }
+
+    pub fn cfg(&self) -> &LdapConfig {
+        &self.shared.cfg
+    }
+
+    /// Open a new connection with the settings, not bound. The servers are tried
+    /// in order, the servers down are tried last.
+    pub fn connect(&self) -> Result<LdapConn> {
+        let shared = &self.shared;
+        let now = Instant::now();
+        let (up, down): (Vec<&Server>, Vec<&Server>) =
+            shared.servers.iter().partition(|x| !x.is_down(now));
+        let mut last_err = None;
+        for server in up.into_iter().chain(down) {
+            match LdapConn::with_settings(shared.cfg.conn_settings(), &server.uri) {
+                Ok(con) => {
+                    server.mark_up();
+                    return Ok(con);
+                }
+                Err(err) => {
+                    println!("Failed to connect to {}: {}", server.uri, err);
+                    server.mark_down(shared.cfg.failover_cooldown());
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            LdapError::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no LDAP server configured",
+            ))
+        }))
+    }
+
+    /// Probe every server by connecting, marks it up or down as `connect` does,
+    /// so the servers recovered are preferred again before the cooldown ends,
+    /// and the servers failed are skipped before any request fails on them.
+    pub fn health_check(&self) {
+        let shared = &self.shared;
+        for server in &shared.servers {
+            match LdapConn::with_settings(shared.cfg.conn_settings(), &server.uri) {
+                Ok(mut con) => {
+                    let _ = con.unbind();
+                    server.mark_up();
+                }
+                Err(err) => {
+                    if !server.is_down(Instant::now()) {
+                        println!("Health check failed on {}: {}", server.uri, err);
+                    }
+                    server.mark_down(shared.cfg.failover_cooldown());
+                }
+            }
+        }
+    }
+
+    /// Open a short-lived connection for binding as an user, the connections
+    /// of the pool are kept bound as admin.
+    pub fn connect_for_user(&self) -> Result<LdapConn> {
+        self.shared
+            .counters
+            .user_binds
+            .fetch_add(1, Ordering::Relaxed);
+        self.connect()
+    }
+
+    /// Bind `con` as admin.
+    fn bind_admin(&self, con: &mut LdapConn) -> Result<()> {
+        let cfg = &self.shared.cfg;
+        con.simple_bind(&cfg.admin_dn, &cfg.admin_pwd)?.success()?;
+        Ok(())
+    }
+
+    /// Returns the idle connection `idle` if still usable, checked by re-binding
+    /// as admin if it has been idle longer than `pool_check_interval`.
+    fn check(&self, mut idle: IdleConn) -> Option<LdapConn> {
+        let cfg = &self.shared.cfg;
+        let counters = &self.shared.counters;
+        let elapsed = idle.last_used.elapsed();
+        if idle.con.is_closed() || elapsed > cfg.pool_idle_timeout() {
+            counters.discarded.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if elapsed > cfg.pool_check_interval() {
+            if self.bind_admin(&mut idle.con).is_err() {
+                counters.discarded.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            counters.rebound.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.reused.fetch_add(1, Ordering::Relaxed);
+        Some(idle.con)
+    }
+
+    /// Check out a connection bound as admin, waits up to `pool_timeout` if all
+    /// the connections are in use.
+    pub fn get(&self) -> Result<PooledConn> {
+        let shared = &self.shared;
+        let deadline = Instant::now() + shared.cfg.pool_timeout();
+        let mut waited = false;
+        let mut state = shared.lock();
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                state.active += 1;
+                drop(state);
+                match self.check(idle) {
+                    Some(con) => return Ok(PooledConn::new(Clone::clone(self), con)),
+                    None => {
+                        shared.release(None);
+                        state = shared.lock();
+                        continue;
+                    }
+                }
+            }
+            if state.active < shared.cfg.pool_size {
+                state.active += 1;
+                drop(state);
+                let con = self.connect().and_then(|mut con| {
+                    self.bind_admin(&mut con)?;
+                    Ok(con)
+                });
+                return match con {
+                    Ok(con) => {
+                        shared.counters.created.fetch_add(1, Ordering::Relaxed);
+                        Ok(PooledConn::new(Clone::clone(self), con))
+                    }
+                    Err(err) => {
+                        shared.counters.failures.fetch_add(1, Ordering::Relaxed);
+                        shared.release(None);
+                        Err(err)
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                shared.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+                return Err(LdapError::from(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no LDAP connection available in the pool",
+                )));
+            }
+            if !waited {
+                waited = true;
+                shared.counters.waits.fetch_add(1, Ordering::Relaxed);
+            }
+            state = shared
+                .released
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Returns the snapshot of the pool usage.
+    pub fn stats(&self) -> PoolStats {
+        let shared = &self.shared;
+        let counters = &shared.counters;
+        let (active, idle) = {
+            let state = shared.lock();
+            (state.active, state.idle.len())
+        };
+        PoolStats {
+            size: shared.cfg.pool_size,
+            active,
+            idle,
+            created: counters.created.load(Ordering::Relaxed),
+            reused: counters.reused.load(Ordering::Relaxed),
+            rebound: counters.rebound.load(Ordering::Relaxed),
+            discarded: counters.discarded.load(Ordering::Relaxed),
+            waits: counters.waits.load(Ordering::Relaxed),
+            timeouts: counters.timeouts.load(Ordering::Relaxed),
+            failures: counters.failures.load(Ordering::Relaxed),
+            user_binds: counters.user_binds.load(Ordering::Relaxed),
+            servers: shared
+                .servers
+                .iter()
+                .map(|x| ServerStats {
+                    uri: Clone::clone(&x.uri),
+                    up: !x.is_down(Instant::now()),
+                    failures: x.failures.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The connection checked out from the pool, given back on drop.
+pub struct PooledConn {
+    pool: LdapPool,
+    con: Option<LdapConn>,
+}
+
+impl PooledConn {
+    fn new(pool: LdapPool, con: LdapConn) -> Self {
+        Self {
+            pool,
+            con: Some(con),
+        }
+    }
+}
+
+impl Deref for PooledConn {
+    type Target = LdapConn;
+
+    fn deref(&self) -> &Self::Target {
+        self.con.as_ref().expect("connection taken")
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.con.as_mut().expect("connection taken")
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        self.pool.shared.release(self.con.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::Value;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+
+    /// Returns the uri of a port refusing the connections.
+    fn refused_uri() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ldap://{}", listener.local_addr().unwrap())
+    }
+
+    fn pool(uris: &[String]) -> LdapPool {
+        let mut table = BTreeMap::new();
+        table.insert("conn_timeout".to_string(), Value::from(1));
+        LdapPool::new(LdapConfig::from(&table), uris)
+    }
+
+    fn up(pool: &LdapPool) -> Vec<bool> {
+        pool.stats().servers.iter().map(|x| x.up).collect()
+    }
+
+    #[test]
+    fn test_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uris = vec![
+            refused_uri(),
+            format!("ldap://{}", listener.local_addr().unwrap()),
+        ];
+        let pool = pool(&uris);
+        assert_eq!(up(&pool), vec![true, true]);
+        assert!(pool.connect().is_ok());
+        assert_eq!(up(&pool), vec![false, true]);
+        // The server down is skipped in the cooldown.
+        assert!(pool.connect().is_ok());
+        assert_eq!(pool.stats().servers[0].failures, 1);
+        drop(listener);
+        assert!(pool.connect().is_err());
+        assert_eq!(up(&pool), vec![false, false]);
+    }
+
+    #[test]
+    fn test_health_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = pool(&[format!("ldap://{}", addr), refused_uri()]);
+        pool.health_check();
+        assert_eq!(up(&pool), vec![true, false]);
+        drop(listener);
+        pool.health_check();
+        assert_eq!(up(&pool), vec![false, false]);
+        // The server recovered is marked up before the cooldown ends.
+        let _listener = TcpListener::bind(addr).unwrap();
+        pool.health_check();
+        assert_eq!(up(&pool), vec![true, false]);
+    }
+}
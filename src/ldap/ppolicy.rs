@@ -0,0 +1,194 @@
+//! The password policy controls of draft-behera-ldap-password-policy.
+//!
+//! The response control is a BER SEQUENCE of an optional `[0]` warning,
+//! either `[0] timeBeforeExpiration` or `[1] graceAuthNsRemaining`, and an
+//! optional `[1]` ENUMERATED error.
+
+use ldap3::controls::{Control, RawControl};
+
+/// The OID of the password policy request and response controls.
+pub const PPOLICY_OID: &str = "1.3.6.1.4.1.42.2.27.8.5.1";
+
+/// Returns the password policy request control, asks the server to attach the response.
+pub fn request_control() -> RawControl {
+    RawControl {
+        ctype: PPOLICY_OID.to_string(),
+        crit: false,
+        val: None,
+    }
+}
+
+/// The warning of the password policy response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolicyWarning {
+    /// The seconds before the password expires.
+    TimeBeforeExpiration(u32),
+    /// The binds remaining with the expired password.
+    GraceAuthNsRemaining(u32),
+}
+
+/// The error of the password policy response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolicyError {
+    PasswordExpired,
+    AccountLocked,
+    ChangeAfterReset,
+    PasswordModNotAllowed,
+    MustSupplyOldPassword,
+    InsufficientPasswordQuality,
+    PasswordTooShort,
+    PasswordTooYoung,
+    PasswordInHistory,
+    Unknown(u32),
+}
+
+impl From<u32> for PolicyError {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => PolicyError::PasswordExpired,
+            1 => PolicyError::AccountLocked,
+            2 => PolicyError::ChangeAfterReset,
+            3 => PolicyError::PasswordModNotAllowed,
+            4 => PolicyError::MustSupplyOldPassword,
+            5 => PolicyError::InsufficientPasswordQuality,
+            6 => PolicyError::PasswordTooShort,
+            7 => PolicyError::PasswordTooYoung,
+            8 => PolicyError::PasswordInHistory,
+            v => PolicyError::Unknown(v),
+        }
+    }
+}
+
+impl PolicyError {
+    /// Returns the message shown to the user.
+    pub fn message(&self) -> &'static str {
+        match self {
+            PolicyError::PasswordExpired => "密码已过期，请修改密码",
+            PolicyError::AccountLocked => "账号已被锁定，请与管理员联系",
+            PolicyError::ChangeAfterReset => "密码已被重置，请先修改密码",
+            PolicyError::PasswordModNotAllowed => "不允许修改密码，请与管理员联系",
+            PolicyError::MustSupplyOldPassword => "修改密码需要提供原密码",
+            PolicyError::InsufficientPasswordQuality => "新密码不符合服务器的密码质量要求",
+            PolicyError::PasswordTooShort => "新密码长度不符合服务器的要求",
+            PolicyError::PasswordTooYoung => "密码修改过于频繁，请稍后再试",
+            PolicyError::PasswordInHistory => "新密码不能与最近使用过的密码相同",
+            PolicyError::Unknown(_) => "密码策略检查未通过，请与管理员联系",
+        }
+    }
+}
+
+/// The password policy response control.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolicyResponse {
+    pub warning: Option<PolicyWarning>,
+    pub error: Option<PolicyError>,
+}
+
+impl PolicyResponse {
+    /// Parse the value of the response control, returns None if malformed.
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let (tag, body, _) = read_tlv(value)?;
+        if tag != 0x30 {
+            return None;
+        }
+        let mut response = Self::default();
+        let mut rest = body;
+        while !rest.is_empty() {
+            let (tag, body, next) = read_tlv(rest)?;
+            match tag {
+                0xa0 => {
+                    let (tag, body, _) = read_tlv(body)?;
+                    let value = read_uint(body)?;
+                    response.warning = match tag {
+                        0x80 => Some(PolicyWarning::TimeBeforeExpiration(value)),
+                        0x81 => Some(PolicyWarning::GraceAuthNsRemaining(value)),
+                        _ => return None,
+                    };
+                }
+                0x81 => response.error = Some(PolicyError::from(read_uint(body)?)),
+                _ => {}
+            }
+            rest = next;
+        }
+        Some(response)
+    }
+
+    /// Returns the response in `controls`, if any.
+    pub fn from_controls(controls: &[Control]) -> Option<Self> {
+        controls
+            .iter()
+            .find(|x| x.1.ctype == PPOLICY_OID)
+            .and_then(|x| x.1.val.as_ref())
+            .and_then(|x| Self::parse(x))
+    }
+}
+
+/// Returns the (tag, contents, rest) of the first BER TLV in `data`.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = if first & 0x80 == 0 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || data.len() < n {
+            return None;
+        }
+        let len = data[..n]
+            .iter()
+            .fold(0usize, |acc, &x| acc << 8 | x as usize);
+        data = &data[n..];
+        len
+    };
+    if data.len() < len {
+        return None;
+    }
+    Some((tag, &data[..len], &data[len..]))
+}
+
+/// Returns the unsigned integer encoded in `data`.
+fn read_uint(data: &[u8]) -> Option<u32> {
+    if data.is_empty() || data.len() > 5 {
+        return None;
+    }
+    data.iter()
+        .try_fold(0u64, |acc, &x| Some(acc << 8 | x as u64))
+        .filter(|&x| x <= u32::MAX as u64)
+        .map(|x| x as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            PolicyResponse::parse(&[0x30, 0x00]),
+            Some(PolicyResponse::default())
+        );
+        assert_eq!(
+            PolicyResponse::parse(&[0x30, 0x03, 0x81, 0x01, 0x08]),
+            Some(PolicyResponse {
+                warning: None,
+                error: Some(PolicyError::PasswordInHistory),
+            })
+        );
+        assert_eq!(
+            PolicyResponse::parse(&[0x30, 0x07, 0xa0, 0x05, 0x80, 0x03, 0x01, 0x51, 0x80]),
+            Some(PolicyResponse {
+                warning: Some(PolicyWarning::TimeBeforeExpiration(86400)),
+                error: None,
+            })
+        );
+        assert_eq!(
+            PolicyResponse::parse(&[0x30, 0x08, 0xa0, 0x03, 0x81, 0x01, 0x02, 0x81, 0x01, 0x00]),
+            Some(PolicyResponse {
+                warning: Some(PolicyWarning::GraceAuthNsRemaining(2)),
+                error: Some(PolicyError::PasswordExpired),
+            })
+        );
+        assert_eq!(PolicyResponse::parse(&[0x30, 0x03, 0x81, 0x01]), None);
+        assert_eq!(PolicyResponse::parse(&[0x04, 0x00]), None);
+    }
+}
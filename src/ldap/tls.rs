@@ -0,0 +1,134 @@
+use super::LdapConfig;
+use native_tls::{Certificate, Identity, Protocol, TlsConnector};
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// The errors may occurred on loading the TLS settings.
+#[derive(Debug)]
+pub enum TlsError {
+    /// Failed to read the file at the path.
+    Io(String, io::Error),
+    /// The certificate or the key in the file at the path is invalid.
+    Invalid(String, native_tls::Error),
+    /// No certificate in the CA file at the path.
+    NoCertificate(String),
+    Tls(native_tls::Error),
+    /// Only one of `client_cert` and `client_key` is specified.
+    IncompleteIdentity,
+    UnknownVersion(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(path, err) => write!(f, "Failed to read {}: {}", path, err),
+            TlsError::Invalid(path, err) => write!(f, "Invalid PEM file {}: {}", path, err),
+            TlsError::NoCertificate(path) => write!(f, "No certificate in {}", path),
+            TlsError::Tls(err) => write!(f, "{}", err),
+            TlsError::IncompleteIdentity => {
+                write!(f, "Both client_cert and client_key must be specified")
+            }
+            TlsError::UnknownVersion(v) => {
+                write!(f, "Unknown TLS version: {}, should be 1.0, 1.1 or 1.2", v)
+            }
+        }
+    }
+}
+
+impl From<native_tls::Error> for TlsError {
+    fn from(err: native_tls::Error) -> Self {
+        TlsError::Tls(err)
+    }
+}
+
+/// Returns the protocol of the TLS `version`, e.g. `1.2`.
+fn parse_version(version: &str) -> Result<Protocol, TlsError> {
+    match version
+        .trim()
+        .trim_start_matches("TLSv")
+        .trim_start_matches("tlsv")
+    {
+        "1.0" | "1" => Ok(Protocol::Tlsv10),
+        "1.1" => Ok(Protocol::Tlsv11),
+        "1.2" => Ok(Protocol::Tlsv12),
+        _ => Err(TlsError::UnknownVersion(version.to_string())),
+    }
+}
+
+/// Returns the content of the file at `path`.
+fn read_file(path: &str) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|err| TlsError::Io(path.to_string(), err))
+}
+
+/// Returns the certificate blocks in the PEM bundle `pem`.
+fn pem_blocks(pem: &str) -> Vec<&str> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut blocks = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+        match rest[start..].find(END) {
+            Some(end) => {
+                blocks.push(&rest[start..start + end + END.len()]);
+                rest = &rest[start + end + END.len()..];
+            }
+            None => break,
+        }
+    }
+    blocks
+}
+
+/// Build the TLS connector by the settings of `cfg`, the CA and the client
+/// certificate are loaded from the PEM files.
+pub fn build_connector(cfg: &LdapConfig) -> Result<TlsConnector, TlsError> {
+    let mut builder = TlsConnector::builder();
+    builder.min_protocol_version(Some(parse_version(&cfg.min_tls_version)?));
+    builder.danger_accept_invalid_hostnames(!cfg.verify_hostname);
+    if !cfg.ca_file.is_empty() {
+        let pem = String::from_utf8_lossy(&read_file(&cfg.ca_file)?).into_owned();
+        let blocks = pem_blocks(&pem);
+        if blocks.is_empty() {
+            return Err(TlsError::NoCertificate(Clone::clone(&cfg.ca_file)));
+        }
+        for block in blocks {
+            let ca = Certificate::from_pem(block.as_bytes())
+                .map_err(|err| TlsError::Invalid(Clone::clone(&cfg.ca_file), err))?;
+            builder.add_root_certificate(ca);
+        }
+    }
+    match (cfg.client_cert.is_empty(), cfg.client_key.is_empty()) {
+        (true, true) => {}
+        (false, false) => {
+            let cert = read_file(&cfg.client_cert)?;
+            let key = read_file(&cfg.client_key)?;
+            let identity = Identity::from_pkcs8(&cert, &key)
+                .map_err(|err| TlsError::Invalid(Clone::clone(&cfg.client_cert), err))?;
+            builder.identity(identity);
+        }
+        _ => return Err(TlsError::IncompleteIdentity),
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pem_blocks() {
+        let pem = "subject=CN=a\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                   -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let blocks = pem_blocks(pem);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].contains("AAAA") && blocks[0].ends_with("-----END CERTIFICATE-----"));
+        assert!(blocks[1].contains("BBBB"));
+        assert!(pem_blocks("-----BEGIN CERTIFICATE-----\nCCCC").is_empty());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert!(matches!(parse_version("1.2"), Ok(Protocol::Tlsv12)));
+        assert!(matches!(parse_version("TLSv1.1"), Ok(Protocol::Tlsv11)));
+        assert!(parse_version("1.3").is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use super::{MailConfig, MailTransport, Result};
+use lettre::SendableEmail;
+use std::fs;
+use std::path::PathBuf;
+
+/// The transport to write mails as `.eml` files to a directory.
+pub struct FileMailTransport {
+    dir: PathBuf,
+}
+
+impl FileMailTransport {
+    /// Construct a new file transport, the directory will be created if not exists.
+    pub fn new(cfg: &MailConfig) -> Result<Self> {
+        fs::create_dir_all(&cfg.dir)?;
+        Ok(Self {
+            dir: PathBuf::from(&cfg.dir),
+        })
+    }
+}
+
+impl MailTransport for FileMailTransport {
+    fn send(&self, email: SendableEmail) -> Result<()> {
+        let path = self.dir.join(format!("{}.eml", email.message_id()));
+        fs::write(path, email.message_to_string()?)?;
+        Ok(())
+    }
+}
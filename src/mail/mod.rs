@@ -0,0 +1,271 @@
+use crate::config::{table_get_bool, table_get_int, table_get_string};
+use handlebars::Handlebars;
+use lettre::SendableEmail;
+use lettre_email::EmailBuilder;
+use rocket::config::Value;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+mod file;
+mod smtp;
+
+pub use file::FileMailTransport;
+pub use smtp::SmtpMailTransport;
+
+const DEFAULT_TRANSPORT: &str = "smtp";
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: i64 = 25;
+const DEFAULT_FROM: &str = "lamager@example.com";
+const DEFAULT_BASE_URL: &str = "http://127.0.0.1:8000";
+const DEFAULT_DIR: &str = "mails";
+/// Out of the web template root, which is loaded by `Template::fairing`.
+const DEFAULT_TEMPLATE_DIR: &str = "mail_templates";
+
+/// The extension of the mail templates.
+const TEMPLATE_EXT: &str = ".txt.hbs";
+
+/// The errors may occurred on sending mails.
+#[derive(Debug)]
+pub enum MailError {
+    Email(lettre_email::error::Error),
+    Smtp(lettre::smtp::error::Error),
+    Tls(native_tls::Error),
+    Io(std::io::Error),
+    Template(handlebars::TemplateError),
+    Render(handlebars::RenderError),
+    UnknownTransport(String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Email(err) => write!(f, "{}", err),
+            MailError::Smtp(err) => write!(f, "{}", err),
+            MailError::Tls(err) => write!(f, "{}", err),
+            MailError::Io(err) => write!(f, "{}", err),
+            MailError::Template(err) => write!(f, "{}", err),
+            MailError::Render(err) => write!(f, "{}", err),
+            MailError::UnknownTransport(name) => write!(f, "Unknown mail transport: {}", name),
+        }
+    }
+}
+
+impl From<lettre_email::error::Error> for MailError {
+    fn from(err: lettre_email::error::Error) -> Self {
+        MailError::Email(err)
+    }
+}
+
+impl From<lettre::smtp::error::Error> for MailError {
+    fn from(err: lettre::smtp::error::Error) -> Self {
+        MailError::Smtp(err)
+    }
+}
+
+impl From<native_tls::Error> for MailError {
+    fn from(err: native_tls::Error) -> Self {
+        MailError::Tls(err)
+    }
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(err: std::io::Error) -> Self {
+        MailError::Io(err)
+    }
+}
+
+impl From<handlebars::TemplateError> for MailError {
+    fn from(err: handlebars::TemplateError) -> Self {
+        MailError::Template(err)
+    }
+}
+
+impl From<handlebars::RenderError> for MailError {
+    fn from(err: handlebars::RenderError) -> Self {
+        MailError::Render(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, MailError>;
+
+/// A trait to deliver the outbound mails.
+pub trait MailTransport: Send + Sync {
+    /// Deliver the `email`.
+    fn send(&self, email: SendableEmail) -> Result<()>;
+}
+
+/// The sender of the outbound mails.
+pub struct Mailer {
+    pub cfg: MailConfig,
+    transport: Box<dyn MailTransport>,
+    templates: Handlebars,
+}
+
+impl Mailer {
+    /// Construct a new mailer with the transport and templates specified in `cfg`.
+    pub fn new(cfg: &MailConfig) -> Result<Self> {
+        let transport: Box<dyn MailTransport> = match cfg.transport.as_str() {
+            "smtp" => Box::new(SmtpMailTransport::new(cfg)?),
+            "file" => Box::new(FileMailTransport::new(cfg)?),
+            other => return Err(MailError::UnknownTransport(other.to_string())),
+        };
+        let mut templates = Handlebars::new();
+        // The mails are plain text, no html escaping.
+        templates.register_escape_fn(handlebars::no_escape);
+        register_templates(&mut templates, Path::new(&cfg.template_dir))?;
+        Ok(Self {
+            cfg: Clone::clone(cfg),
+            transport,
+            templates,
+        })
+    }
+
+    /// Send a plain text mail with `subject` and `body` to `to`.
+    pub fn send<T, S, B>(&self, to: T, subject: S, body: B) -> Result<()>
+    where
+        T: AsRef<str>,
+        S: Into<String>,
+        B: Into<String>,
+    {
+        let email = EmailBuilder::new()
+            .from(self.cfg.from.as_str())
+            .to(to.as_ref())
+            .subject(subject)
+            .text(body)
+            .build()?;
+        self.transport.send(email.into())
+    }
+
+    /// Send a mail rendered from the template `name` with `context` to `to`.
+    ///
+    /// The subject and the body are rendered from `<name>_subject.txt.hbs` and
+    /// `<name>_body.txt.hbs` in the template directory, `base_url` is always
+    /// available in the templates.
+    pub fn send_template<T, C>(&self, to: T, name: &str, context: &C) -> Result<()>
+    where
+        T: AsRef<str>,
+        C: Serialize,
+    {
+        let mut data = handlebars::to_json(context);
+        if let Some(x) = data.as_object_mut() {
+            x.insert(
+                "base_url".to_string(),
+                handlebars::to_json(&self.cfg.base_url),
+            );
+        }
+        let subject = self.templates.render(&format!("{}_subject", name), &data)?;
+        let body = self.templates.render(&format!("{}_body", name), &data)?;
+        self.send(to, subject.trim(), body)
+    }
+}
+
+/// Register all mail templates in `dir` to `registry`.
+fn register_templates(registry: &mut Handlebars, dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = match path.file_name().and_then(|x| x.to_str()) {
+            Some(v) => v,
+            None => continue,
+        };
+        let name = match file_name.strip_suffix(TEMPLATE_EXT) {
+            Some(v) => v,
+            None => continue,
+        };
+        registry.register_template_string(name, fs::read_to_string(&path)?)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MailConfig {
+    /// The transport to deliver mails, `smtp` or `file`.
+    pub transport: String,
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    pub username: String,
+    pub password: String,
+    pub from: String,
+    /// The public url of the site, used to build links in the mails.
+    pub base_url: String,
+    /// The directory to write `.eml` files by the `file` transport.
+    pub dir: String,
+    /// The directory of the mail templates, must not be under the web template root.
+    pub template_dir: String,
+}
+
+impl From<&BTreeMap<String, Value>> for MailConfig {
+    fn from(table: &BTreeMap<String, Value>) -> Self {
+        Self {
+            transport: table_get_string(table, "transport", DEFAULT_TRANSPORT),
+            host: table_get_string(table, "host", DEFAULT_HOST),
+            port: table_get_int(table, "port", DEFAULT_PORT) as u16,
+            starttls: table_get_bool(table, "starttls", false),
+            username: table_get_string(table, "username", ""),
+            password: table_get_string(table, "password", ""),
+            from: table_get_string(table, "from", DEFAULT_FROM),
+            base_url: table_get_string(table, "base_url", DEFAULT_BASE_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            dir: table_get_string(table, "dir", DEFAULT_DIR),
+            template_dir: table_get_string(table, "template_dir", DEFAULT_TEMPLATE_DIR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::process;
+
+    #[test]
+    fn test_file_transport() {
+        let dir = std::env::temp_dir().join(format!("lamager-mailer-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut table = BTreeMap::new();
+        table.insert("transport".to_string(), Value::from("file"));
+        table.insert("from".to_string(), Value::from("noreply@example.com"));
+        table.insert(
+            "base_url".to_string(),
+            Value::from("https://id.example.com/"),
+        );
+        table.insert(
+            "dir".to_string(),
+            Value::from(dir.to_string_lossy().as_ref()),
+        );
+        let mailer = Mailer::new(&MailConfig::from(&table)).unwrap();
+        let mut context = HashMap::new();
+        context.insert("uid", "bob");
+        context.insert("link", "/register/verify/token?a=1&b=2");
+        context.insert("ttl", "30");
+        mailer
+            .send_template("bob@example.com", "register", &context)
+            .unwrap();
+
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let eml = fs::read_to_string(&files[0]).unwrap();
+        let (headers, body) = eml.split_at(eml.find("\r\n\r\n").unwrap());
+        let header = |name: &str| {
+            headers
+                .lines()
+                .find(|x| x.starts_with(name))
+                .map(|x| x[name.len()..].trim().to_string())
+        };
+        assert!(header("To:").unwrap().contains("bob@example.com"));
+        assert!(header("From:").unwrap().contains("noreply@example.com"));
+        assert!(header("Subject:").map_or(false, |x| !x.is_empty()));
+        // The link is built with `base_url` and the text is not escaped.
+        assert!(body.contains("https://id.example.com/register/verify/token?a=1&b=2"));
+        assert!(body.contains("bob"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,37 @@
+use super::{MailConfig, MailTransport, Result};
+use lettre::smtp::authentication::Credentials;
+use lettre::smtp::ClientSecurity;
+use lettre::{ClientTlsParameters, SendableEmail, SmtpClient, Transport};
+use native_tls::TlsConnector;
+
+/// The transport to deliver mails to a SMTP server, with optional STARTTLS.
+pub struct SmtpMailTransport {
+    client: SmtpClient,
+}
+
+impl SmtpMailTransport {
+    /// Construct a new SMTP transport.
+    pub fn new(cfg: &MailConfig) -> Result<Self> {
+        let security = if cfg.starttls {
+            let connector = TlsConnector::new()?;
+            ClientSecurity::Required(ClientTlsParameters::new(Clone::clone(&cfg.host), connector))
+        } else {
+            ClientSecurity::None
+        };
+        let mut client = SmtpClient::new((cfg.host.as_str(), cfg.port), security)?;
+        if !cfg.username.is_empty() {
+            client = client.credentials(Credentials::new(
+                Clone::clone(&cfg.username),
+                Clone::clone(&cfg.password),
+            ));
+        }
+        Ok(Self { client })
+    }
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&self, email: SendableEmail) -> Result<()> {
+        Clone::clone(&self.client).transport().send(email)?;
+        Ok(())
+    }
+}
@@ -1,25 +1,293 @@
 #![feature(proc_macro_hygiene, decl_macro, never_type)]
+// The route handlers take the guards as the arguments, and fail with `Flash` responses.
+#![allow(clippy::too_many_arguments, clippy::result_large_err)]
 #[macro_use]
 extern crate rocket;
 extern crate rocket_contrib;
-use crate::ldap::LdapConfig;
-use crate::models::SessionManager;
+use crate::csrf::CsrfFairing;
+use crate::directory::DirectoryBackend;
+#[cfg(any(test, feature = "demo"))]
+use crate::directory::MemoryDirectory;
+use crate::ldap::{LdapCluster, LdapConfig};
+use crate::mail::{MailConfig, Mailer};
+use crate::models::{
+    InviteManager, LoginThrottle, PasswordExpiryConfig, PasswordExpiryManager, RecoverManager,
+    RegistrationManager, RegistrationMode, SessionConfig, SessionManager, ThrottleConfig,
+    TokenSigner, TotpManager, TrustedProxies,
+};
+use crate::policy::{PasswordPolicy, PasswordPolicyConfig};
+use chrono::Duration;
 use rocket::fairing::AdHoc;
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
+use std::collections::BTreeMap;
+use std::thread;
 
+mod config;
+mod csrf;
+mod directory;
+mod error;
 mod ldap;
+mod mail;
 mod models;
+mod policy;
 mod routes;
+mod store;
+#[cfg(test)]
+mod tests;
+
+/// Open the directory backend specified by `ldap`, the errors are printed.
+fn open_directory(ldap: &mut LdapConfig) -> Option<DirectoryBackend> {
+    match ldap.backend.as_str() {
+        "ldap" => {
+            if let Err(err) = ldap.load_tls() {
+                println!("Failed to load the TLS settings of LDAP: {}", err);
+                return None;
+            }
+            if !ldap.uses_tls() {
+                println!(
+                    "The LDAP connections are not encrypted, the passwords are sent in cleartext"
+                );
+            }
+            Some(DirectoryBackend::Ldap(LdapCluster::new(ldap)))
+        }
+        #[cfg(any(test, feature = "demo"))]
+        "memory" => {
+            let memory = MemoryDirectory::new(Clone::clone(ldap));
+            if !ldap.ldif.is_empty() {
+                let loaded = std::fs::read_to_string(&ldap.ldif)
+                    .map_err(|err| err.to_string())
+                    .and_then(|x| memory.load_ldif(&x).map_err(|err| err.to_string()));
+                if let Err(err) = loaded {
+                    println!("Failed to load {}: {}", ldap.ldif, err);
+                    return None;
+                }
+            }
+            Some(DirectoryBackend::Memory(memory))
+        }
+        #[cfg(not(any(test, feature = "demo")))]
+        "memory" => {
+            println!("The memory backend requires the `demo` feature");
+            None
+        }
+        other => {
+            println!("Unknown directory backend: {}", other);
+            None
+        }
+    }
+}
 
 fn rocket() -> rocket::Rocket {
-    rocket::ignite()
+    mount(rocket::ignite())
+}
+
+/// Attach the fairings and mount the routes to `rocket`.
+fn mount(rocket: rocket::Rocket) -> rocket::Rocket {
+    rocket
         .attach(Template::fairing())
         .attach(AdHoc::on_attach("Ldap Config", |rocket| {
-            let ldap = LdapConfig::from(rocket.config().get_table("ldap").unwrap());
-            Ok(rocket.manage(ldap))
+            let table = rocket.config().get_table("ldap").unwrap();
+            if let Err(err) = LdapConfig::validate(table) {
+                println!("{}", err);
+                return Err(rocket);
+            }
+            let mut ldap = LdapConfig::from(table);
+            match open_directory(&mut ldap) {
+                Some(backend) => Ok(rocket.manage(ldap).manage(backend)),
+                None => Err(rocket),
+            }
+        }))
+        .attach(AdHoc::on_attach("Trusted Proxies", |rocket| {
+            // The X-Real-IP header is ignored if no proxy is trusted.
+            let proxies = match rocket.config().get_slice("trusted_proxies") {
+                Ok(values) => match TrustedProxies::parse(values) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        println!("{}", err);
+                        return Err(rocket);
+                    }
+                },
+                Err(_) => TrustedProxies::default(),
+            };
+            Ok(rocket.manage(proxies))
+        }))
+        .attach(AdHoc::on_attach("Mail Config", |rocket| {
+            let mail = MailConfig::from(rocket.config().get_table("mail").unwrap());
+            match Mailer::new(&mail) {
+                Ok(mailer) => Ok(rocket.manage(mailer)),
+                Err(err) => {
+                    println!("Failed to initialize the mailer: {}", err);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Session Config", |rocket| {
+            let mut session = rocket
+                .config()
+                .get_table("session")
+                .map(SessionConfig::from)
+                .unwrap_or_else(|_| SessionConfig::from(&BTreeMap::new()));
+            session.secure_cookie |= rocket.config().tls_enabled();
+            match SessionManager::new(session) {
+                Ok(manager) => Ok(rocket.manage(manager)),
+                Err(err) => {
+                    println!("Failed to open the session store: {}", err);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Token Key", |rocket| {
+            let signer = match rocket.config().get_str("token_key") {
+                Ok(key) => match TokenSigner::from_base64(key) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        println!("Invalid token_key: {}", err);
+                        return Err(rocket);
+                    }
+                },
+                Err(_) if rocket.config().environment.is_prod() => {
+                    println!("The token_key must be configured in production");
+                    return Err(rocket);
+                }
+                Err(_) => {
+                    println!(
+                        "The token_key is not configured, the links sent in mails are invalid after restart"
+                    );
+                    TokenSigner::random()
+                }
+            };
+            Ok(rocket.manage(signer))
+        }))
+        .attach(AdHoc::on_attach("Recover Config", |rocket| {
+            let ttl = rocket.config().get_int("recover_token_ttl").unwrap_or(30);
+            // The tickets are kept along with the sessions.
+            let manager = match (
+                rocket.state::<TokenSigner>(),
+                rocket.state::<SessionManager>(),
+            ) {
+                (Some(signer), Some(session_manager)) => RecoverManager::new(
+                    signer,
+                    Duration::minutes(ttl),
+                    session_manager.records(),
+                ),
+                _ => return Err(rocket),
+            };
+            Ok(rocket.manage(manager))
+        }))
+        .attach(AdHoc::on_attach("Register Config", |rocket| {
+            let ttl = rocket
+                .config()
+                .get_int("register_token_ttl")
+                .unwrap_or(1440);
+            let mode = rocket.config().get_str("register_mode").unwrap_or("open");
+            let mode = match mode.parse::<RegistrationMode>() {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("{}", err);
+                    return Err(rocket);
+                }
+            };
+            // The registrations and invites are kept along with the sessions.
+            let (registration_manager, records) = match (
+                rocket.state::<TokenSigner>(),
+                rocket.state::<SessionManager>(),
+            ) {
+                (Some(signer), Some(session_manager)) => {
+                    if mode == RegistrationMode::Approval && session_manager.cfg.store == "memory"
+                    {
+                        println!(
+                            "The registrations waiting for approval are lost on restart with the memory session store"
+                        );
+                    }
+                    let records = session_manager.records();
+                    (
+                        RegistrationManager::new(
+                            mode,
+                            signer,
+                            Duration::minutes(ttl),
+                            Clone::clone(&records),
+                        ),
+                        records,
+                    )
+                }
+                _ => return Err(rocket),
+            };
+            Ok(rocket
+                .manage(registration_manager)
+                .manage(InviteManager::new(records)))
+        }))
+        .attach(AdHoc::on_attach("Totp Config", |rocket| {
+            let issuer = rocket
+                .config()
+                .get_str("totp_issuer")
+                .unwrap_or("lamager")
+                .to_string();
+            // The pending logins and enrollments are kept along with the sessions.
+            let records = match rocket.state::<SessionManager>() {
+                Some(session_manager) => session_manager.records(),
+                None => return Err(rocket),
+            };
+            Ok(rocket.manage(TotpManager::new(issuer, records)))
+        }))
+        .attach(AdHoc::on_attach("Throttle Config", |rocket| {
+            // The throttle is enabled with the defaults if not configured.
+            let throttle = rocket
+                .config()
+                .get_table("throttle")
+                .map(ThrottleConfig::from)
+                .unwrap_or_else(|_| ThrottleConfig::from(&BTreeMap::new()));
+            // The failures are kept along with the sessions.
+            let records = match rocket.state::<SessionManager>() {
+                Some(session_manager) => session_manager.records(),
+                None => return Err(rocket),
+            };
+            Ok(rocket.manage(LoginThrottle::new(throttle, records)))
+        }))
+        .attach(AdHoc::on_attach("Password Policy", |rocket| {
+            let policy = rocket
+                .config()
+                .get_table("password_policy")
+                .map(PasswordPolicyConfig::from)
+                .unwrap_or_else(|_| PasswordPolicyConfig::from(&BTreeMap::new()));
+            match PasswordPolicy::new(policy) {
+                Ok(policy) => Ok(rocket.manage(policy)),
+                Err(err) => {
+                    println!("Failed to load the password policy: {}", err);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Password Expiry", |rocket| {
+            let expiry = rocket
+                .config()
+                .get_table("password_expiry")
+                .map(PasswordExpiryConfig::from)
+                .unwrap_or_else(|_| PasswordExpiryConfig::from(&BTreeMap::new()));
+            Ok(rocket.manage(PasswordExpiryManager::new(expiry)))
+        }))
+        .attach(AdHoc::on_launch("Ldap Health Check", |rocket| {
+            if let Some(DirectoryBackend::Ldap(cluster)) = rocket.state::<DirectoryBackend>() {
+                if let Some(interval) = cluster.cfg().health_check_interval() {
+                    let cluster = Clone::clone(cluster);
+                    thread::spawn(move || loop {
+                        thread::sleep(interval);
+                        cluster.health_check();
+                    });
+                }
+            }
+        }))
+        .attach(AdHoc::on_launch("Session Sweeper", |rocket| {
+            if let Some(manager) = rocket.state::<SessionManager>().cloned() {
+                let interval = manager.cfg.sweep_interval();
+                thread::spawn(move || loop {
+                    thread::sleep(interval);
+                    manager.sweep();
+                });
+            }
         }))
-        .manage(SessionManager::new())
+        .attach(CsrfFairing)
+        .register(routes::error::catchers())
+        .mount("/", routes::error::routes())
         .mount("/", routes::index::routes())
         .mount("/index", routes::index::routes())
         .mount("/", routes::login::routes())
@@ -27,6 +295,7 @@ fn rocket() -> rocket::Rocket {
         .mount("/", routes::profile::routes())
         .mount("/", routes::recover::routes())
         .mount("/", routes::register::routes())
+        .mount("/", routes::admin::routes())
         .mount("/assets", StaticFiles::from("assets"))
         .mount("/favicon.ico", StaticFiles::from("assets/favicon.ico"))
 }
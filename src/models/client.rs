@@ -0,0 +1,72 @@
+use rocket::config::Value;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::State;
+use std::net::IpAddr;
+
+/// The informations of the client sent the request.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: String,
+}
+
+impl ClientInfo {
+    /// Returns the ip address of the client as string.
+    pub fn ip_string(&self) -> String {
+        self.ip
+            .map_or_else(|| "unknown".to_string(), |x| x.to_string())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = !;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, !> {
+        Outcome::Success(Self {
+            ip: client_ip(request),
+            user_agent: request
+                .headers()
+                .get_one("User-Agent")
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+}
+
+/// The reverse proxies trusted to report the ip of the clients by `X-Real-IP`,
+/// configured by `trusted_proxies`.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// Parse the addresses of `values`, returns error if any is invalid.
+    pub fn parse(values: &[Value]) -> Result<Self, String> {
+        values
+            .iter()
+            .map(|x| {
+                x.as_str()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| format!("Invalid address of trusted_proxies: {}", x))
+            })
+            .collect::<Result<Vec<IpAddr>, String>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// Returns the ip address of the client sent `request`, the `X-Real-IP` header
+/// can be forged by anyone, so it is honoured only if the request comes from a
+/// trusted proxy.
+pub fn client_ip(request: &Request) -> Option<IpAddr> {
+    let remote = request.remote().map(|x| x.ip());
+    let trusted = request
+        .guard::<State<TrustedProxies>>()
+        .succeeded()
+        .map_or(false, |proxies| {
+            remote.map_or(false, |ip| proxies.0.contains(&ip))
+        });
+    if trusted {
+        request.real_ip().or(remote)
+    } else {
+        remote
+    }
+}
@@ -0,0 +1,224 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha512};
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// The alphabet of crypt(3) for the salts and the encoded hashes.
+pub const CRYPT_ALPHABET: &[u8] =
+    b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// The rounds of SHA-512 crypt if not specified.
+const SHA512_CRYPT_DEFAULT_ROUNDS: u32 = 5000;
+const SHA512_CRYPT_MIN_ROUNDS: u32 = 1000;
+const SHA512_CRYPT_MAX_ROUNDS: u32 = 999_999_999;
+/// The maximum length of the salt of SHA-512 crypt.
+const SHA512_CRYPT_SALT_LEN: usize = 16;
+/// The order of the bytes to encode the SHA-512 crypt hash, 3 bytes a group.
+const SHA512_CRYPT_ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42),
+    (22, 43, 1),
+    (44, 2, 23),
+    (3, 24, 45),
+    (25, 46, 4),
+    (47, 5, 26),
+    (6, 27, 48),
+    (28, 49, 7),
+    (50, 8, 29),
+    (9, 30, 51),
+    (31, 52, 10),
+    (53, 11, 32),
+    (12, 33, 54),
+    (34, 55, 13),
+    (56, 14, 35),
+    (15, 36, 57),
+    (37, 58, 16),
+    (59, 17, 38),
+    (18, 39, 60),
+    (40, 61, 19),
+    (62, 20, 41),
+];
+
+/// Derive `out.len()` bytes from `password` and `salt` with PBKDF2-HMAC-SHA512 (RFC 8018).
+pub fn pbkdf2_sha512(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let prf = HmacSha512::new_from_slice(password).expect("HMAC can take key of any size");
+    for (i, chunk) in out.chunks_mut(64).enumerate() {
+        let mut mac = Clone::clone(&prf);
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u = mac.finalize().into_bytes();
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut mac = Clone::clone(&prf);
+            mac.update(&u);
+            u = mac.finalize().into_bytes();
+            t.iter_mut().zip(u.iter()).for_each(|(a, b)| *a ^= b);
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+/// Returns `len` bytes of `digest` repeated.
+fn repeat_bytes(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().cycle().take(len).cloned().collect()
+}
+
+/// Returns the raw SHA-512 crypt hash, see <https://www.akkadia.org/drepper/SHA-crypt.txt>.
+fn sha512_crypt_raw(password: &[u8], salt: &[u8], rounds: u32) -> Vec<u8> {
+    let b = Sha512::new()
+        .chain(password)
+        .chain(salt)
+        .chain(password)
+        .finalize();
+    let mut a = Sha512::new().chain(password).chain(salt);
+    a.update(repeat_bytes(&b, password.len()));
+    let mut n = password.len();
+    while n > 0 {
+        if n & 1 == 1 {
+            a.update(b);
+        } else {
+            a.update(password);
+        }
+        n >>= 1;
+    }
+    let a = a.finalize();
+    let mut dp = Sha512::new();
+    for _ in 0..password.len() {
+        dp.update(password);
+    }
+    let p = repeat_bytes(&dp.finalize(), password.len());
+    let mut ds = Sha512::new();
+    for _ in 0..16 + a[0] as usize {
+        ds.update(salt);
+    }
+    let s = repeat_bytes(&ds.finalize(), salt.len());
+    let mut c = a.to_vec();
+    for i in 0..rounds {
+        let mut ctx = Sha512::new();
+        if i % 2 == 1 {
+            ctx.update(&p);
+        } else {
+            ctx.update(&c);
+        }
+        if i % 3 != 0 {
+            ctx.update(&s);
+        }
+        if i % 7 != 0 {
+            ctx.update(&p);
+        }
+        if i % 2 == 1 {
+            ctx.update(&c);
+        } else {
+            ctx.update(&p);
+        }
+        c = ctx.finalize().to_vec();
+    }
+    c
+}
+
+/// Returns the crypt(3) base64 encoding of `w`, `n` characters.
+fn encode_24bit(out: &mut String, w: u32, n: usize) {
+    let mut w = w;
+    for _ in 0..n {
+        out.push(CRYPT_ALPHABET[(w & 0x3f) as usize] as char);
+        w >>= 6;
+    }
+}
+
+/// Returns the SHA-512 crypt of `password` with `salt`, e.g. `$6$salt$hash`.
+///
+/// The `rounds` is written in the result only if specified.
+pub fn sha512_crypt(password: &str, salt: &str, rounds: Option<u32>) -> String {
+    let salt: String = salt.chars().take(SHA512_CRYPT_SALT_LEN).collect();
+    let rounds = rounds.map(|x| x.clamp(SHA512_CRYPT_MIN_ROUNDS, SHA512_CRYPT_MAX_ROUNDS));
+    let hash = sha512_crypt_raw(
+        password.as_bytes(),
+        salt.as_bytes(),
+        rounds.unwrap_or(SHA512_CRYPT_DEFAULT_ROUNDS),
+    );
+    let mut out = match rounds {
+        Some(v) => format!("$6$rounds={}${}$", v, salt),
+        None => format!("$6${}$", salt),
+    };
+    for &(x, y, z) in SHA512_CRYPT_ORDER.iter() {
+        let w = (hash[x] as u32) << 16 | (hash[y] as u32) << 8 | hash[z] as u32;
+        encode_24bit(&mut out, w, 4);
+    }
+    encode_24bit(&mut out, hash[63] as u32, 2);
+    out
+}
+
+/// Returns true if the `password` matches the SHA-512 crypt `hash`, e.g. `$6$salt$hash`.
+pub fn verify_sha512_crypt(password: &str, hash: &str) -> bool {
+    let setting = match hash.strip_prefix("$6$") {
+        Some(v) => v,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = setting.split('$').collect();
+    let rounds = match parts.first().and_then(|x| x.strip_prefix("rounds=")) {
+        Some(v) => match v.parse::<u32>() {
+            Ok(v) => {
+                parts.remove(0);
+                Some(v)
+            }
+            Err(_) => return false,
+        },
+        None => None,
+    };
+    match parts.as_slice() {
+        [salt, _] => constant_time_eq(
+            sha512_crypt(password, salt, rounds).as_bytes(),
+            hash.as_bytes(),
+        ),
+        _ => false,
+    }
+}
+
+/// Returns true if `a` equals `b`, compared in constant time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+
+    #[test]
+    fn test_pbkdf2_sha512() {
+        let mut out = [0u8; 64];
+        pbkdf2_sha512(b"password", b"salt", 1, &mut out);
+        let hex = out.iter().fold(String::new(), |mut s, x| {
+            let _ = write!(s, "{:02x}", x);
+            s
+        });
+        assert_eq!(
+            hex,
+            "867f70cf1ade02cff3752599a3a53dc4af34c7a669815ae5d513554e1c8cf252\
+             c02d470a285a0501bad999bfe943c08f050235d7d68b1da55e63f73b60a57fce"
+        );
+    }
+
+    #[test]
+    fn test_sha512_crypt() {
+        // The test vectors of the specification.
+        assert_eq!(
+            sha512_crypt("Hello world!", "saltstring", None),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJu\
+             esI68u4OTLiBFdcbYEdFCoEOfaS35inz1"
+        );
+        assert_eq!(
+            sha512_crypt("Hello world!", "saltstringsaltstring", Some(10000)),
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHb\
+             bMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v."
+        );
+        let hash = "$6$saltsalt$qFmFH.bQmmtXzyBY0s9v7Oicd2z4XSIecDzlB5KiA2/jctKu9YterLp8ww\
+                    nSq.qc.eoxqOmSuNp2xS0ktL3nh/";
+        assert!(verify_sha512_crypt("password", hash));
+        assert!(!verify_sha512_crypt("Password", hash));
+        assert!(!verify_sha512_crypt("password", "$6$saltsalt"));
+        assert!(!verify_sha512_crypt("password", "$5$saltsalt$abc"));
+    }
+}
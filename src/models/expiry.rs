@@ -0,0 +1,270 @@
+use super::Role;
+use crate::config::table_get_int;
+use crate::ldap::ppolicy::{PolicyError, PolicyResponse, PolicyWarning};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use rocket::config::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+const DEFAULT_MAX_AGE: i64 = 0;
+const DEFAULT_WARNING_DAYS: i64 = 14;
+/// The minutes to change the password after login.
+const PENDING_CHANGE_TTL: i64 = 10;
+/// The maximum attempts to change the password after login.
+const PENDING_CHANGE_ATTEMPTS: u32 = 5;
+
+/// The attribute of the time the password changed, set by the password policy overlay.
+pub const PWD_CHANGED_TIME: &str = "pwdChangedTime";
+/// The attribute of the times of the grace logins after the password expired.
+pub const PWD_GRACE_USE_TIME: &str = "pwdGraceUseTime";
+/// The attribute set by the password policy overlay if the password was reset by admin.
+pub const PWD_RESET: &str = "pwdReset";
+/// The days since epoch the password changed of `shadowAccount`.
+pub const SHADOW_LAST_CHANGE: &str = "shadowLastChange";
+/// The maximum days the password is valid of `shadowAccount`.
+pub const SHADOW_MAX: &str = "shadowMax";
+
+#[derive(Clone, Debug)]
+pub struct PasswordExpiryConfig {
+    /// The days the password is valid after `pwdChangedTime`, 0 for never expires.
+    pub max_age: i64,
+    /// The days before expiration to warn the user.
+    pub warning_days: i64,
+}
+
+impl From<&BTreeMap<String, Value>> for PasswordExpiryConfig {
+    fn from(table: &BTreeMap<String, Value>) -> Self {
+        Self {
+            max_age: table_get_int(table, "max_age", DEFAULT_MAX_AGE).max(0),
+            warning_days: table_get_int(table, "warning_days", DEFAULT_WARNING_DAYS).max(0),
+        }
+    }
+}
+
+/// The expiration of a password.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PasswordExpiry {
+    /// The time the password expires, None for never.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The password must be changed before anything else, e.g. reset by admin.
+    pub must_change: bool,
+}
+
+impl PasswordExpiry {
+    /// Returns the expiration by the attributes of the user entry.
+    pub fn from_attrs(attrs: &HashMap<String, Vec<String>>, cfg: &PasswordExpiryConfig) -> Self {
+        let attr = |name: &str| attrs.get(name).and_then(|x| x.first());
+        let mut expiry = Self::default();
+        if cfg.max_age > 0 {
+            if let Some(changed) = attr(PWD_CHANGED_TIME).and_then(|x| parse_generalized_time(x)) {
+                expiry.expire_at(changed + Duration::days(cfg.max_age));
+            }
+        }
+        let last_change = attr(SHADOW_LAST_CHANGE).and_then(|x| x.parse::<i64>().ok());
+        let max_days = attr(SHADOW_MAX).and_then(|x| x.parse::<i64>().ok());
+        match (last_change, max_days) {
+            // The shadow convention to force the change at next login.
+            (Some(0), _) => expiry.must_change = true,
+            (Some(days), Some(max)) if days > 0 && (0..99999).contains(&max) => {
+                expiry.expire_at(Utc.timestamp(0, 0) + Duration::days(days + max));
+            }
+            _ => {}
+        }
+        if attr(PWD_RESET).map_or(false, |x| x.eq_ignore_ascii_case("TRUE"))
+            || attr(PWD_GRACE_USE_TIME).is_some()
+        {
+            expiry.must_change = true;
+        }
+        expiry
+    }
+
+    /// Merge the password policy response of the bind at `now`.
+    pub fn apply_policy(&mut self, response: &PolicyResponse, now: DateTime<Utc>) {
+        match response.warning {
+            Some(PolicyWarning::TimeBeforeExpiration(secs)) => {
+                self.expire_at(now + Duration::seconds(secs as i64))
+            }
+            // Bound by a grace login, the password is already expired.
+            Some(PolicyWarning::GraceAuthNsRemaining(_)) => self.must_change = true,
+            None => {}
+        }
+        if matches!(
+            response.error,
+            Some(PolicyError::ChangeAfterReset) | Some(PolicyError::PasswordExpired)
+        ) {
+            self.must_change = true;
+        }
+    }
+
+    /// Set the expiration to `time` if earlier.
+    fn expire_at(&mut self, time: DateTime<Utc>) {
+        self.expires_at = Some(self.expires_at.map_or(time, |x| x.min(time)));
+    }
+
+    /// Returns true if the password must be changed at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.must_change || self.expires_at.map_or(false, |x| x <= now)
+    }
+
+    /// Returns the days left before expiration at `now`, rounded up.
+    pub fn days_left(&self, now: DateTime<Utc>) -> Option<i64> {
+        let secs = (self.expires_at? - now).num_seconds().max(0);
+        Some((secs + 86399) / 86400)
+    }
+}
+
+/// Returns the time of the LDAP GeneralizedTime `value`, e.g. `20210101120000Z`.
+pub(crate) fn parse_generalized_time(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S%.fZ")
+        .ok()
+        .map(|x| DateTime::from_utc(x, Utc))
+}
+
+/// The login which the password verified but expired, waiting for the change.
+#[derive(Clone, Debug)]
+pub struct PendingChange {
+    pub dn: String,
+    pub uid: String,
+    pub roles: Vec<Role>,
+    pub attempts: u32,
+    pub expires: DateTime<Utc>,
+}
+
+/// The manager of the logins which must change the password first.
+pub struct PasswordExpiryManager {
+    pub cfg: PasswordExpiryConfig,
+    pending: RwLock<HashMap<String, PendingChange>>,
+}
+
+impl PasswordExpiryManager {
+    /// Construct a new password expiry manager.
+    pub fn new(cfg: PasswordExpiryConfig) -> Self {
+        Self {
+            cfg,
+            pending: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add a pending change, returns the key of it.
+    pub fn add(&self, dn: String, uid: String, roles: Vec<Role>) -> String {
+        let key = base64::encode_config(
+            (0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let change = PendingChange {
+            dn,
+            uid,
+            roles,
+            attempts: 0,
+            expires: Utc::now() + Duration::minutes(PENDING_CHANGE_TTL),
+        };
+        if let Ok(mut x) = self.pending.write() {
+            let now = Utc::now();
+            x.retain(|_, v| v.expires > now);
+            x.insert(Clone::clone(&key), change);
+        }
+        key
+    }
+
+    /// Returns the pending change specified by `k` if not expired, the attempts
+    /// of it will be increased, and removed if exceeds the limit.
+    pub fn attempt(&self, k: &str) -> Option<PendingChange> {
+        let mut pending = self.pending.write().ok()?;
+        let change = pending.get_mut(k)?;
+        change.attempts += 1;
+        if change.expires < Utc::now() || change.attempts > PENDING_CHANGE_ATTEMPTS {
+            pending.remove(k);
+            return None;
+        }
+        Some(Clone::clone(change))
+    }
+
+    /// Returns true if the pending change specified by `k` exists.
+    pub fn has(&self, k: &str) -> bool {
+        self.pending
+            .read()
+            .map(|x| x.get(k).map_or(false, |x| x.expires > Utc::now()))
+            .unwrap_or(false)
+    }
+
+    /// Remove the pending change specified by `k`.
+    pub fn remove(&self, k: &str) {
+        if let Ok(mut x) = self.pending.write() {
+            x.remove(k);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attrs(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_attrs() {
+        let cfg = PasswordExpiryConfig {
+            max_age: 90,
+            warning_days: 14,
+        };
+        let now = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let expiry =
+            PasswordExpiry::from_attrs(&attrs(&[(PWD_CHANGED_TIME, "20210101000000Z")]), &cfg);
+        assert_eq!(
+            expiry.expires_at,
+            Some(Utc.ymd(2021, 4, 1).and_hms(0, 0, 0))
+        );
+        assert_eq!(expiry.days_left(now), Some(31));
+        assert!(!expiry.is_expired(now));
+        // 18628 is 2021-01-01.
+        let expiry = PasswordExpiry::from_attrs(
+            &attrs(&[
+                (PWD_CHANGED_TIME, "20210101000000.123Z"),
+                (SHADOW_LAST_CHANGE, "18628"),
+                (SHADOW_MAX, "30"),
+            ]),
+            &cfg,
+        );
+        assert_eq!(
+            expiry.expires_at,
+            Some(Utc.ymd(2021, 1, 31).and_hms(0, 0, 0))
+        );
+        assert!(expiry.is_expired(now));
+        assert_eq!(expiry.days_left(now), Some(0));
+        let expiry = PasswordExpiry::from_attrs(&attrs(&[(SHADOW_LAST_CHANGE, "0")]), &cfg);
+        assert!(expiry.must_change);
+        let expiry = PasswordExpiry::from_attrs(&attrs(&[(PWD_RESET, "TRUE")]), &cfg);
+        assert!(expiry.is_expired(now));
+        let expiry = PasswordExpiry::from_attrs(&attrs(&[]), &cfg);
+        assert_eq!(expiry, PasswordExpiry::default());
+        assert_eq!(expiry.days_left(now), None);
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let now = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let mut expiry = PasswordExpiry::default();
+        expiry.apply_policy(
+            &PolicyResponse {
+                warning: Some(PolicyWarning::TimeBeforeExpiration(86400 * 3 - 60)),
+                error: None,
+            },
+            now,
+        );
+        assert_eq!(expiry.days_left(now), Some(3));
+        assert!(!expiry.is_expired(now));
+        expiry.apply_policy(
+            &PolicyResponse {
+                warning: None,
+                error: Some(PolicyError::ChangeAfterReset),
+            },
+            now,
+        );
+        assert!(expiry.is_expired(now));
+    }
+}
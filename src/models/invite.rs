@@ -0,0 +1,316 @@
+use crate::store::RecordStore;
+use chrono::{DateTime, Duration, Local, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The length of the invite token in bytes before encoding.
+const INVITE_TOKEN_LEN: usize = 24;
+/// The default lifetime of the invites in hours.
+const DEFAULT_INVITE_TTL: i64 = 72;
+/// The kind of the invites in the record store.
+const INVITE_KIND: &str = "invite";
+
+/// The invitation to register, created by administrators.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub token: String,
+    /// The mail the invited must register with, any if `None`.
+    pub mail: Option<String>,
+    /// The dn of the OU to create the user in, `base_dn` if `None`.
+    pub ou: Option<String>,
+    /// The dn of the groups to add the user to.
+    pub groups: Vec<String>,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub created_by: String,
+    pub expires: DateTime<Utc>,
+    /// The local time of `expires` for displaying.
+    pub expires_at: String,
+}
+
+impl Invite {
+    /// Returns true if the invite can still be used at `now`.
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.expires > now && self.uses < self.max_uses
+    }
+
+    /// Returns true if the invite accepts the registration of `mail`.
+    pub fn accepts(&self, mail: &str) -> bool {
+        self.mail
+            .as_ref()
+            .map_or(true, |x| x.eq_ignore_ascii_case(mail.trim()))
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub struct NewInvite {
+    pub mail: Option<String>,
+    pub ou: Option<String>,
+    /// The dn of the groups, one per line.
+    pub groups: Option<String>,
+    /// The lifetime in hours.
+    pub ttl: Option<i64>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct RevokeInvite {
+    pub token: String,
+}
+
+/// Returns the trimmed `value` if it's not empty.
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value
+        .as_ref()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+}
+
+/// The manager of the invites, kept in the record store to be shared by the
+/// processes.
+pub struct InviteManager {
+    records: Arc<dyn RecordStore>,
+}
+
+impl InviteManager {
+    /// Construct a new invite manager on `records`.
+    pub fn new(records: Arc<dyn RecordStore>) -> Self {
+        Self { records }
+    }
+
+    /// Save the `invite`, the errors are printed.
+    fn save(&self, invite: &Invite) -> bool {
+        match self
+            .records
+            .put_json(INVITE_KIND, &invite.token, invite, invite.expires)
+        {
+            Ok(_) => true,
+            Err(err) => {
+                println!("Failed to save the invite: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Create an invite from `new` by `created_by`, returns the invite.
+    pub fn create(&self, new: &NewInvite, created_by: &str) -> Option<Invite> {
+        let mut bytes = [0u8; INVITE_TOKEN_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        let expires = Utc::now() + Duration::hours(new.ttl.unwrap_or(DEFAULT_INVITE_TTL).max(1));
+        let invite = Invite {
+            token: base64::encode_config(bytes, base64::URL_SAFE_NO_PAD),
+            mail: non_empty(&new.mail),
+            ou: non_empty(&new.ou),
+            groups: new
+                .groups
+                .as_ref()
+                .map(|x| {
+                    x.lines()
+                        .map(|v| v.trim())
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_uses: new.max_uses.unwrap_or(1).max(1),
+            uses: 0,
+            created_by: created_by.to_string(),
+            expires,
+            expires_at: expires
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+        };
+        if self.save(&invite) {
+            Some(invite)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the valid invites, latest expiring last.
+    pub fn list(&self) -> Vec<Invite> {
+        let now = Utc::now();
+        let mut invites: Vec<Invite> = self
+            .records
+            .list_json::<Invite>(INVITE_KIND)
+            .unwrap_or_else(|err| {
+                println!("Failed to list the invites: {}", err);
+                Vec::new()
+            })
+            .into_iter()
+            .filter(|v| v.is_valid(now))
+            .collect();
+        invites.sort_by(|a, b| a.expires.cmp(&b.expires));
+        invites
+    }
+
+    /// Returns the invite specified by `token` if it's still valid.
+    pub fn peek(&self, token: &str) -> Option<Invite> {
+        let now = Utc::now();
+        self.records
+            .get_json::<Invite>(INVITE_KIND, token)
+            .unwrap_or_else(|err| {
+                println!("Failed to look up the invite: {}", err);
+                None
+            })
+            .filter(|x| x.is_valid(now))
+    }
+
+    /// Use the invite specified by `token` for `mail` once, returns the invite if accepted.
+    pub fn consume(&self, token: &str, mail: &str) -> Option<Invite> {
+        let now = Utc::now();
+        self.update(token, |invite| {
+            if !invite.is_valid(now) || !invite.accepts(mail) {
+                return false;
+            }
+            invite.uses += 1;
+            true
+        })
+    }
+
+    /// Give back the use of `invite` returned by `consume`, e.g. failed to
+    /// create the account. Nothing is done if it has been revoked.
+    pub fn release(&self, invite: &Invite) {
+        self.update(&invite.token, |invite| {
+            invite.uses = invite.uses.saturating_sub(1);
+            true
+        });
+    }
+
+    /// Apply `f` to the invite specified by `token` if `f` returns true, returns
+    /// the updated invite. The invite is replaced by compare-and-swap, so the
+    /// concurrent registrations can't take the same use.
+    fn update<F>(&self, token: &str, f: F) -> Option<Invite>
+    where
+        F: Fn(&mut Invite) -> bool,
+    {
+        loop {
+            let old = match self.records.get(INVITE_KIND, token) {
+                Ok(v) => v?,
+                Err(err) => {
+                    println!("Failed to look up the invite: {}", err);
+                    return None;
+                }
+            };
+            let mut invite: Invite = serde_json::from_str(&old).ok()?;
+            if !f(&mut invite) {
+                return None;
+            }
+            let value = serde_json::to_string(&invite).ok()?;
+            match self
+                .records
+                .replace(INVITE_KIND, token, &old, &value, invite.expires)
+            {
+                Ok(true) => return Some(invite),
+                Ok(false) => continue,
+                Err(err) => {
+                    println!("Failed to save the invite: {}", err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Revoke the invite specified by `token`, returns true if it's existing.
+    pub fn revoke(&self, token: &str) -> bool {
+        self.records
+            .remove(INVITE_KIND, token)
+            .unwrap_or_else(|err| {
+                println!("Failed to remove the invite: {}", err);
+                false
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+    use std::thread;
+
+    fn new_invite(mail: Option<&str>, max_uses: u32) -> NewInvite {
+        NewInvite {
+            mail: mail.map(String::from),
+            ou: Some(" ".to_string()),
+            groups: Some("cn=a,dc=example\n\ncn=b,dc=example".to_string()),
+            ttl: None,
+            max_uses: Some(max_uses),
+        }
+    }
+
+    #[test]
+    fn test_invite_consume() {
+        let manager = InviteManager::new(Arc::new(MemoryRecordStore::new()));
+        let invite = manager.create(&new_invite(None, 2), "admin").unwrap();
+        assert_eq!(invite.ou, None);
+        assert_eq!(invite.groups, vec!["cn=a,dc=example", "cn=b,dc=example"]);
+        assert_eq!(manager.list().len(), 1);
+
+        assert_eq!(
+            manager
+                .consume(&invite.token, "a@example.com")
+                .unwrap()
+                .uses,
+            1
+        );
+        assert_eq!(manager.peek(&invite.token).unwrap().uses, 1);
+        let last = manager.consume(&invite.token, "b@example.com").unwrap();
+        assert_eq!(last.uses, 2);
+        assert!(manager.peek(&invite.token).is_none());
+        assert!(manager.consume(&invite.token, "c@example.com").is_none());
+
+        // The released use can be taken again.
+        manager.release(&last);
+        let last = manager.consume(&invite.token, "c@example.com").unwrap();
+        assert!(manager.consume("unknown", "c@example.com").is_none());
+
+        // The revoked invite is not brought back by the release.
+        assert!(manager.revoke(&invite.token));
+        manager.release(&last);
+        assert!(manager.peek(&invite.token).is_none());
+        assert!(manager.list().is_empty());
+    }
+
+    #[test]
+    fn test_invite_concurrent() {
+        let manager = Arc::new(InviteManager::new(Arc::new(MemoryRecordStore::new())));
+        let invite = manager.create(&new_invite(None, 3), "admin").unwrap();
+        let threads: Vec<_> = (0..16)
+            .map(|i| {
+                let manager = Clone::clone(&manager);
+                let token = Clone::clone(&invite.token);
+                thread::spawn(move || {
+                    manager
+                        .consume(&token, &format!("{}@example.com", i))
+                        .is_some()
+                })
+            })
+            .collect();
+        let accepted = threads
+            .into_iter()
+            .filter_map(|x| x.join().ok())
+            .filter(|x| *x)
+            .count();
+        assert_eq!(accepted, 3);
+    }
+
+    #[test]
+    fn test_invite_mail() {
+        let manager = InviteManager::new(Arc::new(MemoryRecordStore::new()));
+        let invite = manager
+            .create(&new_invite(Some("Bob@Example.com"), 1), "admin")
+            .unwrap();
+        assert!(manager.consume(&invite.token, "eve@example.com").is_none());
+        assert!(manager.consume(&invite.token, " bob@example.com").is_some());
+
+        let invite = manager.create(&new_invite(None, 1), "admin").unwrap();
+        assert!(manager.revoke(&invite.token));
+        assert!(!manager.revoke(&invite.token));
+        assert!(manager.list().is_empty());
+    }
+}
@@ -1,17 +1,38 @@
 mod api_message;
+mod client;
+mod crypt;
+mod expiry;
+mod invite;
 mod login;
 mod new_password;
 mod new_user;
 mod person;
 mod prelude;
+mod recover;
+mod registration;
+mod role;
 mod session;
+mod throttle;
+mod token;
+mod totp;
 mod user;
+mod user_list;
 
 pub use api_message::*;
+pub use client::*;
+pub use expiry::*;
+pub use invite::*;
 pub use login::*;
 pub use new_password::*;
 pub use new_user::*;
 pub use person::*;
 pub use prelude::*;
+pub use recover::*;
+pub use registration::*;
+pub use role::*;
 pub use session::*;
+pub use throttle::*;
+pub use token::*;
+pub use totp::*;
 pub use user::*;
+pub use user_list::*;
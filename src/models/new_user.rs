@@ -8,6 +8,8 @@ pub struct NewUser {
     pub mail: String,
     pub password: String,
     pub password_confirm: String,
+    /// The token of the invite to register with.
+    pub invite: Option<String>,
 }
 
 impl PasswordDigest for NewUser {
@@ -1,7 +1,74 @@
-use sha2::{Digest, Sha256};
+use super::crypt::{
+    constant_time_eq, pbkdf2_sha512, sha512_crypt, verify_sha512_crypt, CRYPT_ALPHABET,
+};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::str::FromStr;
 
 /// The default length of the salt.
 const SSHA256_SLAT_LEN: usize = 8;
+/// The length of the salt of `{SSHA512}`.
+const SSHA512_SALT_LEN: usize = 8;
+/// The length of the salt of `{PBKDF2-SHA512}`, same as the OpenLDAP pw-pbkdf2 module.
+const PBKDF2_SALT_LEN: usize = 16;
+/// The length of the derived key of `{PBKDF2-SHA512}`.
+const PBKDF2_DK_LEN: usize = 64;
+/// The iterations of `{PBKDF2-SHA512}`, same as the OpenLDAP pw-pbkdf2 module.
+const PBKDF2_ITERATIONS: u32 = 10000;
+/// The length of the salt of `{CRYPT}$6$`.
+const CRYPT_SALT_LEN: usize = 16;
+
+/// The schemes of the `userPassword` values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PasswordScheme {
+    #[default]
+    Ssha256,
+    Ssha512,
+    Pbkdf2Sha512,
+    Argon2,
+    CryptSha512,
+}
+
+impl PasswordScheme {
+    /// Returns the name of the scheme, e.g. `SSHA256`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PasswordScheme::Ssha256 => "SSHA256",
+            PasswordScheme::Ssha512 => "SSHA512",
+            PasswordScheme::Pbkdf2Sha512 => "PBKDF2-SHA512",
+            PasswordScheme::Argon2 => "ARGON2",
+            PasswordScheme::CryptSha512 => "CRYPT",
+        }
+    }
+}
+
+impl fmt::Display for PasswordScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PasswordScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s
+            .trim_matches(|c| c == '{' || c == '}')
+            .to_uppercase()
+            .as_str()
+        {
+            "SSHA256" => Ok(PasswordScheme::Ssha256),
+            "SSHA512" => Ok(PasswordScheme::Ssha512),
+            "PBKDF2-SHA512" => Ok(PasswordScheme::Pbkdf2Sha512),
+            "ARGON2" => Ok(PasswordScheme::Argon2),
+            "CRYPT" | "CRYPT-SHA512" => Ok(PasswordScheme::CryptSha512),
+            other => Err(format!("Unknown password scheme: {}", other)),
+        }
+    }
+}
 
 /// A trait to generate password digests.
 pub trait PasswordDigest {
@@ -16,15 +83,154 @@ pub trait PasswordDigest {
 
     /// Returns base64 password digest with SHA256+SALT algo.
     fn ssha256(&self) -> String {
-        let salt = make_salt(SSHA256_SLAT_LEN);
-        let mut buffer = Vec::new();
-        buffer.extend(self.password().as_bytes());
-        buffer.extend(&salt);
-        let mut hash = Vec::new();
-        hash.extend(Sha256::digest(&buffer));
-        hash.extend(salt);
-        base64::encode(hash)
+        ssha256_with_salt(self.password(), &make_salt(SSHA256_SLAT_LEN))
     }
+
+    /// Returns base64 password digest with SHA512+SALT algo.
+    fn ssha512(&self) -> String {
+        ssha512_with_salt(self.password(), &make_salt(SSHA512_SALT_LEN))
+    }
+
+    /// Returns password digest with PBKDF2-HMAC-SHA512, `<iterations>$<salt>$<dk>`
+    /// in the adapted base64 of the OpenLDAP pw-pbkdf2 module.
+    fn pbkdf2_sha512(&self) -> String {
+        pbkdf2_sha512_with_salt(
+            self.password(),
+            &make_salt(PBKDF2_SALT_LEN),
+            PBKDF2_ITERATIONS,
+        )
+    }
+
+    /// Returns password digest with Argon2id in the PHC string format.
+    fn argon2(&self) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(self.password().as_bytes(), &salt)
+            .expect("Argon2 can hash with the default params")
+            .to_string()
+    }
+
+    /// Returns password digest with SHA-512 crypt, `$6$<salt>$<hash>`.
+    fn crypt_sha512(&self) -> String {
+        let salt: String = (0..CRYPT_SALT_LEN)
+            .map(|_| CRYPT_ALPHABET[rand::random::<usize>() % CRYPT_ALPHABET.len()] as char)
+            .collect();
+        sha512_crypt(self.password(), &salt, None)
+    }
+
+    /// Returns the `userPassword` value with `scheme`, e.g. `{SSHA256}...`.
+    fn digest(&self, scheme: PasswordScheme) -> String {
+        let digest = match scheme {
+            PasswordScheme::Ssha256 => self.ssha256(),
+            PasswordScheme::Ssha512 => self.ssha512(),
+            PasswordScheme::Pbkdf2Sha512 => self.pbkdf2_sha512(),
+            PasswordScheme::Argon2 => self.argon2(),
+            PasswordScheme::CryptSha512 => self.crypt_sha512(),
+        };
+        format!("{{{}}}{}", scheme, digest)
+    }
+}
+
+impl PasswordDigest for str {
+    fn password(&self) -> &str {
+        self
+    }
+}
+
+/// Returns base64 digest of `password` with SHA256+SALT algo and the specified `salt`.
+fn ssha256_with_salt(password: &str, salt: &[u8]) -> String {
+    let mut buffer = Vec::new();
+    buffer.extend(password.as_bytes());
+    buffer.extend(salt);
+    let mut hash = Vec::new();
+    hash.extend(Sha256::digest(&buffer));
+    hash.extend(salt);
+    base64::encode(hash)
+}
+
+/// Returns base64 digest of `password` with SHA512+SALT algo and the specified `salt`.
+fn ssha512_with_salt(password: &str, salt: &[u8]) -> String {
+    let mut hash = Vec::new();
+    hash.extend(
+        Sha512::new()
+            .chain(password.as_bytes())
+            .chain(salt)
+            .finalize(),
+    );
+    hash.extend(salt);
+    base64::encode(hash)
+}
+
+/// Returns the adapted base64 of the OpenLDAP pw-pbkdf2 module, `.` for `+` without padding.
+fn ab64_encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::STANDARD_NO_PAD).replace('+', ".")
+}
+
+fn ab64_decode(value: &str) -> Option<Vec<u8>> {
+    base64::decode_config(value.replace('.', "+"), base64::STANDARD_NO_PAD).ok()
+}
+
+/// Returns `{PBKDF2-SHA512}` digest of `password` with the specified `salt` and `iterations`.
+fn pbkdf2_sha512_with_salt(password: &str, salt: &[u8], iterations: u32) -> String {
+    let mut dk = [0u8; PBKDF2_DK_LEN];
+    pbkdf2_sha512(password.as_bytes(), salt, iterations, &mut dk);
+    format!("{}${}${}", iterations, ab64_encode(salt), ab64_encode(&dk))
+}
+
+/// Returns true if the `password` matches the `userPassword` value `digest`,
+/// prefixed by any of the supported schemes.
+pub fn verify_digest(password: &str, digest: &str) -> bool {
+    let (scheme, value) = match digest
+        .strip_prefix('{')
+        .and_then(|x| x.find('}').map(|i| (&x[..i], &x[i + 1..])))
+    {
+        Some(v) => v,
+        None => return false,
+    };
+    match scheme.parse::<PasswordScheme>() {
+        Ok(PasswordScheme::Ssha256) => verify_ssha256(password, digest),
+        Ok(PasswordScheme::Ssha512) => match base64::decode(value) {
+            Ok(hash) if hash.len() > 64 => constant_time_eq(
+                ssha512_with_salt(password, &hash[64..]).as_bytes(),
+                value.as_bytes(),
+            ),
+            _ => false,
+        },
+        Ok(PasswordScheme::Pbkdf2Sha512) => {
+            let parts: Vec<&str> = value.split('$').collect();
+            match parts.as_slice() {
+                [iterations, salt, _] => match (iterations.parse::<u32>(), ab64_decode(salt)) {
+                    (Ok(iterations), Some(salt)) if iterations > 0 => constant_time_eq(
+                        pbkdf2_sha512_with_salt(password, &salt, iterations).as_bytes(),
+                        value.as_bytes(),
+                    ),
+                    _ => false,
+                },
+                _ => false,
+            }
+        }
+        Ok(PasswordScheme::Argon2) => PasswordHash::new(value)
+            .map(|x| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &x)
+                    .is_ok()
+            })
+            .unwrap_or(false),
+        Ok(PasswordScheme::CryptSha512) => verify_sha512_crypt(password, value),
+        Err(_) => false,
+    }
+}
+
+/// Returns true if the `password` matches the `{SSHA256}` prefixed `digest`.
+pub fn verify_ssha256(password: &str, digest: &str) -> bool {
+    let hash = match digest
+        .strip_prefix("{SSHA256}")
+        .and_then(|x| base64::decode(x).ok())
+    {
+        Some(v) if v.len() > 32 => v,
+        _ => return false,
+    };
+    ssha256_with_salt(password, &hash[32..]) == base64::encode(&hash)
 }
 
 /// Returns a randomize salt.
@@ -38,12 +244,6 @@ mod tests {
 
     #[test]
     fn test_password_digest() {
-        // Implements trait for str
-        impl PasswordDigest for &str {
-            fn password(&self) -> &str {
-                self
-            }
-        }
         // Test empty string
         assert_eq!("".sha256(), "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
         assert_ne!("".ssha256(), "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
@@ -66,4 +266,69 @@ mod tests {
             "8S+xwsrFP7DnznPchhI+KTKtCeqloabKNTkcch+lIZA="
         );
     }
+
+    #[test]
+    fn test_password_scheme() {
+        assert_eq!("ssha512".parse(), Ok(PasswordScheme::Ssha512));
+        assert_eq!("{PBKDF2-SHA512}".parse(), Ok(PasswordScheme::Pbkdf2Sha512));
+        assert_eq!("CRYPT".parse(), Ok(PasswordScheme::CryptSha512));
+        assert!("MD5".parse::<PasswordScheme>().is_err());
+        for scheme in &[
+            PasswordScheme::Ssha256,
+            PasswordScheme::Ssha512,
+            PasswordScheme::Pbkdf2Sha512,
+            PasswordScheme::Argon2,
+            PasswordScheme::CryptSha512,
+        ] {
+            let digest = "password".digest(*scheme);
+            assert!(digest.starts_with(&format!("{{{}}}", scheme)));
+            assert!(verify_digest("password", &digest), "{}", digest);
+            assert!(!verify_digest("Password", &digest), "{}", digest);
+        }
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        // The format of `slappasswd -h {SSHA512}`, salted by `saltsalt`.
+        assert!(verify_digest(
+            "password",
+            "{SSHA512}9ZxHVj4YomwqqFiYKcIjExMLx2ZblYfXRGc4KMqbgvHq2+HOgwiTIi+eO/Uam/8D0beDAkGpvx\
+             14+UFlfBskLnNhbHRzYWx0"
+        ));
+        // The format of `slappasswd -o module-load=pw-pbkdf2 -h {PBKDF2-SHA512}`.
+        assert!(verify_digest(
+            "password",
+            "{PBKDF2-SHA512}10000$AAECAwQFBgcICQoLDA0ODw$XlmEypBaVSQjizWYccEtwiA2kxgcZYBAF.jF/8AH\
+             NfPRXfSq5eBDZPKXNy2LQpkFuMNF46FIAIq5QhReD4xVuw"
+        ));
+        // The example of the Argon2 reference implementation, which the argon2
+        // module of OpenLDAP is built on: `argon2 somesalt -t 2 -m 16 -p 4`.
+        assert!(verify_digest(
+            "password",
+            "{ARGON2}$argon2i$v=19$m=65536,t=2,p=4$c29tZXNhbHQ$RdescudvJCsgt3ub+b+dWRWJTmaaJObG"
+        ));
+        // The format of `slappasswd -h {CRYPT} -c '$6$%.16s'`, salted by `saltsalt`.
+        assert!(verify_digest(
+            "password",
+            "{CRYPT}$6$saltsalt$qFmFH.bQmmtXzyBY0s9v7Oicd2z4XSIecDzlB5KiA2/jctKu9YterLp8wwnSq.qc.\
+             eoxqOmSuNp2xS0ktL3nh/"
+        ));
+        assert_eq!(
+            ssha512_with_salt("password", b"saltsalt"),
+            "9ZxHVj4YomwqqFiYKcIjExMLx2ZblYfXRGc4KMqbgvHq2+HOgwiTIi+eO/Uam/8D0beDAkGpvx14+UFlfBsk\
+             LnNhbHRzYWx0"
+        );
+        assert!(!verify_digest("password", "{MD5}X03MO1qnZdYdgyfeuILPmQ=="));
+        assert!(!verify_digest("password", "password"));
+        assert!(!verify_digest("password", "{PBKDF2-SHA512}0$AAEC$AAEC"));
+    }
+
+    #[test]
+    fn test_verify_ssha256() {
+        let digest = format!("{{SSHA256}}{}", "password".ssha256());
+        assert!(verify_ssha256("password", &digest));
+        assert!(!verify_ssha256("Password", &digest));
+        assert!(!verify_ssha256("password", "password"));
+        assert!(!verify_ssha256("password", "{SSHA256}"));
+    }
 }
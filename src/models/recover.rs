@@ -0,0 +1,209 @@
+use super::{PasswordDigest, TokenSigner};
+use crate::store::RecordStore;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// The length of the random nonce in the token.
+const RECOVER_NONCE_LEN: usize = 16;
+/// The kind of the tickets in the record store.
+const RECOVER_KIND: &str = "recover";
+
+#[derive(Debug, FromForm)]
+pub struct RecoverRequest {
+    pub username: String,
+}
+
+#[derive(Clone, Debug, FromForm)]
+pub struct ResetPassword {
+    pub new_password: String,
+    pub new_password_confirm: String,
+}
+
+impl PasswordDigest for ResetPassword {
+    fn password(&self) -> &str {
+        &self.new_password
+    }
+}
+
+/// The pending password recovery of an user.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecoverTicket {
+    /// The key of the ticket in the record store.
+    pub id: String,
+    pub dn: String,
+    pub uid: String,
+    pub expires: DateTime<Utc>,
+}
+
+/// The manager of the pending password recoveries.
+///
+/// Each ticket is referenced by a signed token, the token is only valid before
+/// the ticket expired and can be used only once. The tickets are kept in the
+/// record store to be shared by the processes.
+pub struct RecoverManager {
+    signer: TokenSigner,
+    ttl: Duration,
+    records: Arc<dyn RecordStore>,
+}
+
+impl RecoverManager {
+    /// Construct a new recover manager signing with `signer` on `records`,
+    /// the tokens will expire after `ttl`.
+    pub fn new(signer: &TokenSigner, ttl: Duration, records: Arc<dyn RecordStore>) -> Self {
+        Self {
+            signer: signer.derive(RECOVER_KIND),
+            ttl,
+            records,
+        }
+    }
+
+    /// Returns the lifetime of the tickets.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issue a new ticket for `dn` and `uid`, returns the token of it.
+    pub fn issue(&self, dn: String, uid: String) -> Option<String> {
+        let expires = Utc::now() + self.ttl;
+        let mut payload: Vec<u8> = (0..RECOVER_NONCE_LEN)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        payload.extend(&expires.timestamp().to_be_bytes());
+        let ticket = RecoverTicket {
+            id: base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            dn,
+            uid,
+            expires,
+        };
+        // Only the latest ticket of the user is valid.
+        let saved = self
+            .records
+            .list_json::<RecoverTicket>(RECOVER_KIND)
+            .and_then(|tickets| {
+                for x in tickets.iter().filter(|x| x.dn == ticket.dn) {
+                    self.records.remove(RECOVER_KIND, &x.id)?;
+                }
+                self.records
+                    .put_json(RECOVER_KIND, &ticket.id, &ticket, expires)
+            });
+        match saved {
+            Ok(_) => Some(self.signer.sign(&payload)),
+            Err(err) => {
+                println!(
+                    "Failed to save the recover ticket of {}: {}",
+                    ticket.uid, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the ticket referenced by `token` if it is still valid.
+    pub fn peek(&self, token: &str) -> Option<RecoverTicket> {
+        let id = self.verify(token)?;
+        self.records
+            .get_json::<RecoverTicket>(RECOVER_KIND, &id)
+            .unwrap_or_else(|err| {
+                println!("Failed to look up the recover ticket: {}", err);
+                None
+            })
+    }
+
+    /// Remove and returns the ticket referenced by `token` if it is still valid.
+    pub fn consume(&self, token: &str) -> Option<RecoverTicket> {
+        let ticket = self.peek(token)?;
+        match self.records.remove(RECOVER_KIND, &ticket.id) {
+            Ok(true) => Some(ticket),
+            Ok(false) => None,
+            Err(err) => {
+                println!("Failed to remove the recover ticket: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Returns the id of the ticket referenced by `token` if the signature
+    /// matched and not expired.
+    fn verify(&self, token: &str) -> Option<String> {
+        let payload = self.signer.verify(token)?;
+        if payload.len() != RECOVER_NONCE_LEN + 8 {
+            return None;
+        }
+        let secs = i64::from_be_bytes(payload[RECOVER_NONCE_LEN..].try_into().ok()?);
+        if Utc.timestamp(secs, 0) < Utc::now() {
+            return None;
+        }
+        Some(base64::encode_config(&payload, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+
+    fn new_manager(ttl: Duration) -> RecoverManager {
+        RecoverManager::new(
+            &TokenSigner::random(),
+            ttl,
+            Arc::new(MemoryRecordStore::new()),
+        )
+    }
+
+    #[test]
+    fn test_recover_ticket() {
+        let manager = new_manager(Duration::minutes(30));
+        let token = manager
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        assert_eq!(manager.peek(&token).unwrap().uid, "alice");
+        // Peeking doesn't use the ticket.
+        assert_eq!(manager.peek(&token).unwrap().dn, "uid=alice");
+        assert_eq!(manager.consume(&token).unwrap().uid, "alice");
+        assert!(manager.peek(&token).is_none());
+        assert!(manager.consume(&token).is_none());
+
+        // Only the latest ticket of the user is valid.
+        let first = manager
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        let second = manager
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        assert!(manager.peek(&first).is_none());
+        assert!(manager.peek(&second).is_some());
+    }
+
+    #[test]
+    fn test_recover_expired() {
+        let manager = new_manager(Duration::minutes(-1));
+        let token = manager
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        assert!(manager.peek(&token).is_none());
+        assert!(manager.consume(&token).is_none());
+    }
+
+    #[test]
+    fn test_recover_tampered() {
+        let manager = new_manager(Duration::minutes(30));
+        let token = manager
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        let mut tampered = token.into_bytes();
+        tampered[3] = if tampered[3] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(manager.peek(&tampered).is_none());
+        assert!(manager.peek("").is_none());
+
+        // The tokens signed by other keys are rejected.
+        let other = new_manager(Duration::minutes(30));
+        let token = other
+            .issue("uid=alice".to_string(), "alice".to_string())
+            .unwrap();
+        assert!(manager.peek(&token).is_none());
+    }
+}
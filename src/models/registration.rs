@@ -0,0 +1,431 @@
+use super::{Invite, NewUser, PasswordDigest, PasswordScheme, TokenSigner};
+use crate::store::RecordStore;
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The length of the random nonce in the token.
+const REGISTRATION_NONCE_LEN: usize = 16;
+/// The kind of the registrations waiting for the confirmation in the record store.
+const PENDING_KIND: &str = "registration";
+/// The kind of the registrations waiting for approval in the record store.
+const APPROVAL_KIND: &str = "approval";
+/// The days to keep the registrations waiting for approval.
+const APPROVAL_TTL: i64 = 90;
+
+/// The policy of the self-registration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Anyone can register.
+    Open,
+    /// The registrations must be approved by administrators.
+    Approval,
+    /// Only the invited can register.
+    InviteOnly,
+    /// No one can register.
+    Closed,
+}
+
+impl RegistrationMode {
+    /// Returns the name of the mode.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistrationMode::Open => "open",
+            RegistrationMode::Approval => "approval",
+            RegistrationMode::InviteOnly => "invite_only",
+            RegistrationMode::Closed => "closed",
+        }
+    }
+}
+
+impl fmt::Display for RegistrationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationMode::Open),
+            "approval" => Ok(RegistrationMode::Approval),
+            "invite_only" => Ok(RegistrationMode::InviteOnly),
+            "closed" => Ok(RegistrationMode::Closed),
+            other => Err(format!("Unknown registration mode: {}", other)),
+        }
+    }
+}
+
+/// The registration waiting for the confirmation of the mail.
+///
+/// Only the digest of the password is kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingRegistration {
+    pub uid: String,
+    pub cn: String,
+    pub mail: String,
+    /// The value of `userPassword`, e.g. `{SSHA256}...`.
+    pub password_digest: String,
+    /// The dn of the OU to create the user in, `base_dn` if `None`.
+    pub ou: Option<String>,
+    /// The dn of the groups to add the user to.
+    pub groups: Vec<String>,
+    /// The token of the invite registered with, which needs no approval.
+    /// The invite is used only when the account created.
+    pub invite: Option<String>,
+    pub expires: DateTime<Utc>,
+}
+
+impl PendingRegistration {
+    /// Construct a new registration of `user`, the password digested with `scheme`.
+    pub fn new(user: &NewUser, scheme: PasswordScheme) -> Self {
+        Self {
+            uid: Clone::clone(&user.uid),
+            cn: Clone::clone(&user.cn),
+            mail: Clone::clone(&user.mail),
+            password_digest: user.digest(scheme),
+            ou: None,
+            groups: Vec::new(),
+            invite: None,
+            expires: Utc::now(),
+        }
+    }
+
+    /// Apply the placement of `invite` to the registration.
+    pub fn with_invite(mut self, invite: &Invite) -> Self {
+        self.ou = Clone::clone(&invite.ou);
+        self.groups = Clone::clone(&invite.groups);
+        self.invite = Some(Clone::clone(&invite.token));
+        self
+    }
+}
+
+/// The registration confirmed the mail, waiting for the approval of administrators.
+#[derive(Clone, Debug, Serialize)]
+pub struct RegistrationApproval {
+    pub id: String,
+    pub uid: String,
+    pub cn: String,
+    pub mail: String,
+    pub submitted: String,
+    #[serde(skip)]
+    pub registration: PendingRegistration,
+    #[serde(skip)]
+    submitted_at: DateTime<Utc>,
+}
+
+/// The registration in the record store with the key of it.
+#[derive(Serialize, Deserialize)]
+struct RegistrationRecord {
+    id: String,
+    submitted: DateTime<Utc>,
+    registration: PendingRegistration,
+}
+
+impl From<RegistrationRecord> for RegistrationApproval {
+    fn from(record: RegistrationRecord) -> Self {
+        let registration = record.registration;
+        Self {
+            id: record.id,
+            uid: Clone::clone(&registration.uid),
+            cn: Clone::clone(&registration.cn),
+            mail: Clone::clone(&registration.mail),
+            submitted: record
+                .submitted
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            registration,
+            submitted_at: record.submitted,
+        }
+    }
+}
+
+/// The confirmation of the registration referenced by `token`.
+#[derive(Debug, FromForm)]
+pub struct ConfirmRegistration {
+    pub token: String,
+}
+
+#[derive(Debug, FromForm)]
+pub struct ReviewRegistration {
+    pub id: String,
+    /// The reason to reject, sent to the applicant.
+    pub reason: Option<String>,
+}
+
+/// The manager of the pending registrations.
+///
+/// Each registration is referenced by a signed token sent to the mail, the
+/// entry is created only after the token used, the abandoned registrations
+/// are removed once expired. The registrations are kept in the record store
+/// to be shared by the processes and survive restarts.
+pub struct RegistrationManager {
+    pub mode: RegistrationMode,
+    signer: TokenSigner,
+    ttl: Duration,
+    records: Arc<dyn RecordStore>,
+}
+
+impl RegistrationManager {
+    /// Construct a new registration manager in `mode` signing with `signer`
+    /// on `records`, the tokens will expire after `ttl`.
+    pub fn new(
+        mode: RegistrationMode,
+        signer: &TokenSigner,
+        ttl: Duration,
+        records: Arc<dyn RecordStore>,
+    ) -> Self {
+        Self {
+            mode,
+            signer: signer.derive("registration"),
+            ttl,
+            records,
+        }
+    }
+
+    /// Returns the lifetime of the registrations.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Add a pending `registration`, returns the token of it.
+    pub fn issue(&self, mut registration: PendingRegistration) -> Option<String> {
+        let now = Utc::now();
+        registration.expires = now + self.ttl;
+        let mut payload: Vec<u8> = (0..REGISTRATION_NONCE_LEN)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        payload.extend(&registration.expires.timestamp().to_be_bytes());
+        let record = RegistrationRecord {
+            id: base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            submitted: now,
+            registration,
+        };
+        // The latest registration of the same account and mail replaces the previous,
+        // the others can't cancel it without the mail, the first confirmed wins.
+        let saved = self
+            .records
+            .list_json::<RegistrationRecord>(PENDING_KIND)
+            .and_then(|records| {
+                let registration = &record.registration;
+                for x in records.iter().filter(|x| {
+                    x.registration.uid == registration.uid
+                        && x.registration.mail == registration.mail
+                }) {
+                    self.records.remove(PENDING_KIND, &x.id)?;
+                }
+                self.records
+                    .put_json(PENDING_KIND, &record.id, &record, registration.expires)
+            });
+        match saved {
+            Ok(_) => Some(self.signer.sign(&payload)),
+            Err(err) => {
+                println!(
+                    "Failed to save the registration of {}: {}",
+                    record.registration.uid, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Add the confirmed `registration` to the approval queue, returns true if added.
+    pub fn submit_approval(&self, registration: PendingRegistration) -> bool {
+        let id: Vec<u8> = (0..REGISTRATION_NONCE_LEN)
+            .map(|_| rand::random::<u8>())
+            .collect();
+        let now = Utc::now();
+        let record = RegistrationRecord {
+            id: base64::encode_config(id, base64::URL_SAFE_NO_PAD),
+            submitted: now,
+            registration,
+        };
+        self.save_approval(&record)
+    }
+
+    /// Save the registration waiting for approval, the errors are printed.
+    fn save_approval(&self, record: &RegistrationRecord) -> bool {
+        let expires = record.submitted + Duration::days(APPROVAL_TTL);
+        match self
+            .records
+            .put_json(APPROVAL_KIND, &record.id, record, expires)
+        {
+            Ok(_) => true,
+            Err(err) => {
+                println!(
+                    "Failed to save the registration of {}: {}",
+                    record.registration.uid, err
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the registrations waiting for approval, oldest first.
+    pub fn approvals(&self) -> Vec<RegistrationApproval> {
+        let mut approvals: Vec<RegistrationApproval> = self
+            .records
+            .list_json::<RegistrationRecord>(APPROVAL_KIND)
+            .unwrap_or_else(|err| {
+                println!("Failed to list the registrations: {}", err);
+                Vec::new()
+            })
+            .into_iter()
+            .map(RegistrationApproval::from)
+            .collect();
+        approvals.sort_by(|a, b| a.submitted_at.cmp(&b.submitted_at));
+        approvals
+    }
+
+    /// Remove and returns the registration waiting for approval specified by `id`.
+    pub fn take_approval(&self, id: &str) -> Option<RegistrationApproval> {
+        let record = self
+            .records
+            .get_json::<RegistrationRecord>(APPROVAL_KIND, id)
+            .ok()
+            .flatten()?;
+        // Only one of the concurrent reviews takes it.
+        match self.records.remove(APPROVAL_KIND, id) {
+            Ok(true) => Some(RegistrationApproval::from(record)),
+            _ => None,
+        }
+    }
+
+    /// Put back the registration taken by `take_approval`, e.g. failed to approve.
+    pub fn restore_approval(&self, approval: RegistrationApproval) {
+        self.save_approval(&RegistrationRecord {
+            id: approval.id,
+            submitted: approval.submitted_at,
+            registration: approval.registration,
+        });
+    }
+
+    /// Returns the registration referenced by `token` if it is still valid.
+    pub fn peek(&self, token: &str) -> Option<PendingRegistration> {
+        let id = self.verify(token)?;
+        self.records
+            .get_json::<RegistrationRecord>(PENDING_KIND, &id)
+            .unwrap_or_else(|err| {
+                println!("Failed to look up the registration: {}", err);
+                None
+            })
+            .map(|x| x.registration)
+    }
+
+    /// Remove and returns the registration referenced by `token` if it is still valid.
+    pub fn consume(&self, token: &str) -> Option<PendingRegistration> {
+        let registration = self.peek(token)?;
+        let id = self.verify(token)?;
+        match self.records.remove(PENDING_KIND, &id) {
+            Ok(true) => Some(registration),
+            Ok(false) => None,
+            Err(err) => {
+                println!("Failed to remove the registration: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Returns the id of the registration referenced by `token` if the
+    /// signature matched and not expired.
+    fn verify(&self, token: &str) -> Option<String> {
+        let payload = self.signer.verify(token)?;
+        if payload.len() != REGISTRATION_NONCE_LEN + 8 {
+            return None;
+        }
+        let secs = i64::from_be_bytes(payload[REGISTRATION_NONCE_LEN..].try_into().ok()?);
+        if Utc.timestamp(secs, 0) < Utc::now() {
+            return None;
+        }
+        Some(base64::encode_config(&payload, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+
+    fn registration(uid: &str, mail: &str) -> PendingRegistration {
+        PendingRegistration {
+            uid: uid.to_string(),
+            cn: uid.to_string(),
+            mail: mail.to_string(),
+            password_digest: "{SSHA256}digest".to_string(),
+            ou: None,
+            groups: Vec::new(),
+            invite: None,
+            expires: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_registration_mode() {
+        for name in &["open", "approval", "invite_only", "closed"] {
+            assert_eq!(name.parse::<RegistrationMode>().unwrap().as_str(), *name);
+        }
+        assert_eq!(
+            "approval".parse::<RegistrationMode>(),
+            Ok(RegistrationMode::Approval)
+        );
+        assert!("Open".parse::<RegistrationMode>().is_err());
+        assert!("".parse::<RegistrationMode>().is_err());
+    }
+
+    #[test]
+    fn test_registration_approval() {
+        let records: Arc<dyn RecordStore> = Arc::new(MemoryRecordStore::new());
+        let signer = TokenSigner::random();
+        let manager = RegistrationManager::new(
+            RegistrationMode::Approval,
+            &signer,
+            Duration::minutes(30),
+            Clone::clone(&records),
+        );
+        let first = manager
+            .issue(registration("bob", "bob@example.com"))
+            .unwrap();
+        let second = manager
+            .issue(registration("bob", "bob@example.com"))
+            .unwrap();
+        assert!(manager.peek(&first).is_none());
+        // The registration of the same account with another mail doesn't replace it.
+        let other_mail = manager
+            .issue(registration("bob", "eve@example.com"))
+            .unwrap();
+        assert!(manager.peek(&second).is_some());
+        assert!(manager.peek(&other_mail).is_some());
+        let registration = manager.consume(&second).unwrap();
+        assert!(manager.consume(&second).is_none());
+
+        assert!(manager.submit_approval(registration));
+        // The queue is shared by the managers on the same store.
+        let other = RegistrationManager::new(
+            RegistrationMode::Approval,
+            &signer,
+            Duration::minutes(30),
+            records,
+        );
+        let approvals = other.approvals();
+        assert_eq!(approvals.len(), 1);
+        assert_eq!(approvals[0].uid, "bob");
+        assert_eq!(approvals[0].registration.password_digest, "{SSHA256}digest");
+
+        // Approving or rejecting takes it once.
+        let approval = manager.take_approval(&approvals[0].id).unwrap();
+        assert!(other.take_approval(&approvals[0].id).is_none());
+        assert!(manager.approvals().is_empty());
+        // Failed to approve, put it back.
+        manager.restore_approval(approval);
+        assert_eq!(other.approvals().len(), 1);
+        assert!(other.take_approval(&approvals[0].id).is_some());
+        assert!(manager.approvals().is_empty());
+    }
+}
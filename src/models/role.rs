@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+
+/// The role of an user, granted by the group membership in the directory, the
+/// roles are configured by `roles` of `[ldap]`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Role(Cow<'static, str>);
+
+impl Role {
+    /// Manage all users and settings.
+    pub const ADMIN: Role = Role(Cow::Borrowed("admin"));
+    /// Assist users, e.g. view users and reset passwords.
+    pub const HELPDESK: Role = Role(Cow::Borrowed("helpdesk"));
+    /// Every authenticated user.
+    pub const USER: Role = Role(Cow::Borrowed("user"));
+
+    /// Returns the name of the role.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the display name of the role, the name if not a builtin role.
+    pub fn title(&self) -> &str {
+        match self.as_str() {
+            "admin" => "管理员",
+            "helpdesk" => "技术支持",
+            "user" => "普通用户",
+            name => name,
+        }
+    }
+}
+
+impl From<&str> for Role {
+    fn from(name: &str) -> Self {
+        Role(Cow::Owned(name.to_string()))
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
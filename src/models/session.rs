@@ -1,43 +1,86 @@
-use rand::Rng;
-use rocket::http::Status;
+use super::{ClientInfo, Role};
+use crate::config::{table_get_bool, table_get_int, table_get_string};
+use crate::csrf::{make_token, CSRF_COOKIE};
+use crate::directory::DirectoryRef;
+use crate::store::{self, open_record_store, open_session_store, RecordStore, SessionStore};
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::config::Value;
+use rocket::http::{Cookie, Cookies, SameSite, Status};
 use rocket::request::{FromRequest, Outcome, Request};
-use rocket::State;
-use std::collections::HashMap;
+use rocket::{FromForm, State};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
-#[derive(Clone, Debug, Default)]
+const DEFAULT_ABSOLUTE_TIMEOUT: i64 = 720;
+const DEFAULT_IDLE_TIMEOUT: i64 = 30;
+const DEFAULT_SWEEP_INTERVAL: i64 = 60;
+const DEFAULT_STORE: &str = "memory";
+const DEFAULT_PATH: &str = "sessions.db";
+/// The length of the session id in bytes before encoding.
+const SSID_LEN: usize = 32;
+
+/// The name of the cookie to carry the session id.
+pub const SESSION_COOKIE: &str = "ssid";
+/// The minimum interval in seconds to refresh the `last_seen` of sessions,
+/// avoids locking the table for writing on every request.
+const TOUCH_INTERVAL: i64 = 30;
+
+#[derive(Clone, Debug)]
 pub struct Session {
     pub ssid: String,
     pub dn: String,
     pub uid: String,
+    pub roles: Vec<Role>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: String,
+    pub ip: String,
 }
 
 impl Session {
-    /// Make a randomized session id.
+    /// Make a randomized session id from the CSPRNG of the operating system.
     fn make_ssid() -> String {
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                abcdefghijklmnopqrstuvwxyz\
-                                0123456789";
-        const KEY_LEN: usize = 8;
-        let mut rng = rand::thread_rng();
-
-        (0..KEY_LEN)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect()
-    }
-
-    /// Construct a new session with `dn` and `uid`.
-    pub fn new(dn: String, uid: String) -> Self {
+        let mut bytes = [0u8; SSID_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Construct a new session with `dn`, `uid` and `roles` for the `client`.
+    pub fn new(dn: String, uid: String, roles: Vec<Role>, client: &ClientInfo) -> Self {
+        let now = Utc::now();
         Self {
             ssid: Self::make_ssid(),
             dn,
             uid,
+            roles,
+            created_at: now,
+            last_seen: now,
+            user_agent: Clone::clone(&client.user_agent),
+            ip: client.ip_string(),
         }
     }
+
+    /// Returns the id to reference the session in pages, the session id can't
+    /// be derived from it.
+    pub fn public_id(&self) -> String {
+        let digest = Sha256::digest(self.ssid.as_bytes());
+        base64::encode_config(&digest[..12], base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Returns true if the session has the `role`.
+    pub fn has_role(&self, role: &Role) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Returns true if the session is expired at `now` by the timeouts of `cfg`.
+    pub fn is_expired(&self, cfg: &SessionConfig, now: DateTime<Utc>) -> bool {
+        self.created_at + cfg.absolute_timeout() < now || self.last_seen + cfg.idle_timeout() < now
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -45,6 +88,8 @@ pub enum SessionError {
     NoCookie,
     NoManager,
     NotFound,
+    Forbidden,
+    NoDirectory,
 }
 
 // impl From<()> for SessionError {
@@ -57,9 +102,9 @@ pub enum SessionError {
 pub struct SessionRef(pub Arc<Session>);
 
 impl SessionRef {
-    /// Construct a new session with `dn` and `uid`.
-    pub fn new(dn: String, uid: String) -> Self {
-        Self(Arc::new(Session::new(dn, uid)))
+    /// Construct a new session with `dn`, `uid` and `roles` for the `client`.
+    pub fn new(dn: String, uid: String, roles: Vec<Role>, client: &ClientInfo) -> Self {
+        Self(Arc::new(Session::new(dn, uid, roles, client)))
     }
 }
 
@@ -80,7 +125,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for SessionRef {
             .map_failure(|_| (Status::BadRequest, SessionError::NoManager))?;
         let session = request
             .cookies()
-            .get_private("ssid")
+            .get_private(SESSION_COOKIE)
             .ok_or(SessionError::NoCookie)
             .and_then(|cookie| manager.get(cookie.value()).ok_or(SessionError::NotFound));
         match session {
@@ -90,38 +135,363 @@ impl<'a, 'r> FromRequest<'a, 'r> for SessionRef {
     }
 }
 
-pub struct SessionManager {
-    sessions: RwLock<HashMap<String, SessionRef>>,
+/// Returns the session of the request if it has any of the `roles`, the roles
+/// are looked up again since the membership may be changed after login.
+///
+/// Forwards if there is no session, fails with `Forbidden` if the session lacks the roles,
+/// or `ServiceUnavailable` if failed to look up the roles.
+fn session_with_roles(request: &Request<'_>, roles: &[Role]) -> Outcome<SessionRef, SessionError> {
+    let session = request.guard::<SessionRef>()?;
+    let manager = request
+        .guard::<State<SessionManager>>()
+        .map_failure(|_| (Status::BadRequest, SessionError::NoManager))?;
+    let mut ldap = request
+        .guard::<DirectoryRef>()
+        .map_failure(|_| (Status::ServiceUnavailable, SessionError::NoDirectory))?;
+    let current = match ldap.roles_of(&session.dn) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Failed to look up the roles of {}: {}", session.dn, err);
+            return Outcome::Failure((Status::ServiceUnavailable, SessionError::NoDirectory));
+        }
+    };
+    if current == session.roles {
+        return if roles.iter().any(|x| session.has_role(x)) {
+            Outcome::Success(session)
+        } else {
+            Outcome::Failure((Status::Forbidden, SessionError::Forbidden))
+        };
+    }
+    let session = manager.update_roles(&session, current);
+    if roles.iter().any(|x| session.has_role(x)) {
+        // The privileges changed, the cookies are dropped from the failure
+        // responses so the id is rotated only if the request is accepted.
+        Outcome::Success(manager.rotate(&mut request.cookies(), &session))
+    } else {
+        Outcome::Failure((Status::Forbidden, SessionError::Forbidden))
+    }
 }
 
-impl SessionManager {
-    /// Construct a new session manager.
-    pub fn new() -> Self {
+/// The session of an user has the `admin` role.
+#[derive(Clone, Debug)]
+pub struct AdminSession(pub SessionRef);
+
+impl Deref for AdminSession {
+    type Target = SessionRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminSession {
+    type Error = SessionError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        session_with_roles(request, &[Role::ADMIN]).map(AdminSession)
+    }
+}
+
+/// The session of an user has the `helpdesk` or `admin` role.
+#[derive(Clone, Debug)]
+pub struct HelpdeskSession(pub SessionRef);
+
+impl Deref for HelpdeskSession {
+    type Target = SessionRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for HelpdeskSession {
+    type Error = SessionError;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        session_with_roles(request, &[Role::ADMIN, Role::HELPDESK]).map(HelpdeskSession)
+    }
+}
+
+/// The summary of a session shown to users.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_agent: String,
+    pub ip: String,
+    pub created_at: String,
+    pub last_seen: String,
+    /// True if it is the session of current request.
+    pub current: bool,
+}
+
+impl SessionInfo {
+    /// Construct a new summary of `session`, `current` is the session id of current request.
+    pub fn new(session: &Session, current: &str) -> Self {
+        let format = |x: &DateTime<Utc>| {
+            x.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        };
         Self {
-            sessions: RwLock::new(HashMap::new()),
+            id: session.public_id(),
+            user_agent: Clone::clone(&session.user_agent),
+            ip: Clone::clone(&session.ip),
+            created_at: format(&session.created_at),
+            last_seen: format(&session.last_seen),
+            current: session.ssid == current,
         }
     }
+}
 
-    /// Add a session to table.
+#[derive(Debug, FromForm)]
+pub struct RevokeSession {
+    /// The public id of the session to revoke, all sessions if not specified.
+    pub id: Option<String>,
+    /// The dn of the user, only used by administrators.
+    pub dn: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// The lifetime in minutes of sessions since created.
+    pub absolute_timeout: i64,
+    /// The lifetime in minutes of sessions since last seen.
+    pub idle_timeout: i64,
+    /// The interval in seconds to evict the expired sessions.
+    pub sweep_interval: i64,
+    /// The backend to store sessions, `memory` or `sqlite`.
+    pub store: String,
+    /// The database file of the `sqlite` backend.
+    pub path: String,
+    /// Send the cookies only over HTTPS, enabled if TLS of Rocket is enabled.
+    pub secure_cookie: bool,
+}
+
+impl SessionConfig {
+    pub fn absolute_timeout(&self) -> Duration {
+        Duration::minutes(self.absolute_timeout)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::minutes(self.idle_timeout)
+    }
+
+    pub fn sweep_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.sweep_interval.max(1) as u64)
+    }
+}
+
+impl From<&BTreeMap<String, Value>> for SessionConfig {
+    fn from(table: &BTreeMap<String, Value>) -> Self {
+        Self {
+            absolute_timeout: table_get_int(table, "absolute_timeout", DEFAULT_ABSOLUTE_TIMEOUT),
+            idle_timeout: table_get_int(table, "idle_timeout", DEFAULT_IDLE_TIMEOUT),
+            sweep_interval: table_get_int(table, "sweep_interval", DEFAULT_SWEEP_INTERVAL),
+            store: table_get_string(table, "store", DEFAULT_STORE),
+            path: table_get_string(table, "path", DEFAULT_PATH),
+            secure_cookie: table_get_bool(table, "secure_cookie", false),
+        }
+    }
+}
+
+/// The manager of the sessions, the clones share the same store.
+#[derive(Clone)]
+pub struct SessionManager {
+    pub cfg: SessionConfig,
+    store: Arc<dyn SessionStore>,
+    /// The records stored along with the sessions, shared by other managers.
+    records: Arc<dyn RecordStore>,
+}
+
+impl SessionManager {
+    /// Construct a new session manager with the store specified in `cfg`.
+    pub fn new(cfg: SessionConfig) -> store::Result<Self> {
+        let store = open_session_store(&cfg)?;
+        let records = open_record_store(&cfg)?;
+        Ok(Self {
+            cfg,
+            store: Arc::from(store),
+            records: Arc::from(records),
+        })
+    }
+
+    /// Returns the record store along with the sessions.
+    pub fn records(&self) -> Arc<dyn RecordStore> {
+        Clone::clone(&self.records)
+    }
+
+    /// Add a session to store.
     pub fn add(&self, session: SessionRef) {
-        if let Ok(mut x) = self.sessions.write() {
-            x.insert(Clone::clone(&session.ssid), session);
+        if let Err(err) = self.store.insert(&session) {
+            println!("Failed to add the session: {}", err);
         }
     }
 
-    /// Returns the reference of the session in the table specified by `k`.
+    /// Returns the cookie named `name` with `value`, the attributes are same as the session cookie.
+    pub fn cookie(&self, name: &'static str, value: String) -> Cookie<'static> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .secure(self.cfg.secure_cookie)
+            .finish()
+    }
+
+    /// Start the `session` and set the cookie of it, the previous session
+    /// carried by `cookies` will be removed to prevent fixation.
+    pub fn start(&self, cookies: &mut Cookies, session: SessionRef) {
+        if let Some(previous) = cookies.get_private(SESSION_COOKIE) {
+            self.remove(previous.value());
+        }
+        cookies.add_private(self.cookie(SESSION_COOKIE, Clone::clone(&session.ssid)));
+        self.renew_csrf(cookies);
+        self.add(session);
+    }
+
+    /// End the session of `ssid` and remove the cookie of it.
+    pub fn end(&self, cookies: &mut Cookies, ssid: &str) {
+        cookies.remove_private(Cookie::named(SESSION_COOKIE));
+        self.renew_csrf(cookies);
+        self.remove(ssid);
+    }
+
+    /// Replace the CSRF token with a new one, so the tokens issued before the
+    /// session started or rotated can't be used in it.
+    pub fn renew_csrf(&self, cookies: &mut Cookies) {
+        cookies.add_private(self.cookie(CSRF_COOKIE, make_token()));
+    }
+
+    /// Replace the id of `session` with a new one and set the cookie of it,
+    /// returns the rotated session.
+    pub fn rotate(&self, cookies: &mut Cookies, session: &Session) -> SessionRef {
+        let mut rotated = Session::clone(session);
+        rotated.ssid = Session::make_ssid();
+        let rotated = SessionRef(Arc::new(rotated));
+        self.add(Clone::clone(&rotated));
+        self.remove(&session.ssid);
+        cookies.add_private(self.cookie(SESSION_COOKIE, Clone::clone(&rotated.ssid)));
+        self.renew_csrf(cookies);
+        rotated
+    }
+
+    /// Replace the roles of `session` in the store, returns the updated session.
+    pub fn update_roles(&self, session: &Session, roles: Vec<Role>) -> SessionRef {
+        let mut updated = Session::clone(session);
+        updated.roles = roles;
+        let updated = SessionRef(Arc::new(updated));
+        self.add(Clone::clone(&updated));
+        updated
+    }
+
+    /// Returns the reference of the session in the store specified by `k`.
+    ///
+    /// The expired session will be removed, otherwise the `last_seen` of it
+    /// will be refreshed.
     pub fn get(&self, k: &str) -> Option<SessionRef> {
-        self.sessions
-            .read()
-            .map(|x| x.get(k).map(|x| Clone::clone(x)))
-            .ok()
-            .flatten()
+        let now = Utc::now();
+        let mut session = self.store.get(k).ok().flatten()?;
+        if session.is_expired(&self.cfg, now) {
+            self.remove(k);
+            return None;
+        }
+        if session.last_seen + Duration::seconds(TOUCH_INTERVAL) < now
+            && self.store.touch(k, now).is_ok()
+        {
+            session.last_seen = now;
+        }
+        Some(SessionRef(Arc::new(session)))
     }
 
-    /// Remove the session in the table specified by `k`.
+    /// Remove the session in the store specified by `k`.
     pub fn remove(&self, k: &str) {
-        if let Ok(mut x) = self.sessions.write() {
-            x.remove(k);
+        if let Err(err) = self.store.remove(k) {
+            println!("Failed to remove the session: {}", err);
+        }
+    }
+
+    /// Returns the live sessions of the user specified by `dn`, most recently seen first.
+    pub fn sessions_of(&self, dn: &str) -> Vec<Session> {
+        let now = Utc::now();
+        let mut sessions: Vec<Session> = self
+            .store
+            .list_by_dn(dn)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| !x.is_expired(&self.cfg, now))
+            .collect();
+        sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        sessions
+    }
+
+    /// Remove the session of the user specified by `dn` with the public `id`,
+    /// returns true if removed.
+    pub fn revoke(&self, dn: &str, id: &str) -> bool {
+        match self
+            .sessions_of(dn)
+            .into_iter()
+            .find(|x| x.public_id() == id)
+        {
+            Some(session) => {
+                self.remove(&session.ssid);
+                true
+            }
+            None => false,
         }
     }
+
+    /// Remove all sessions of the user specified by `dn` except `keep`,
+    /// returns the number of them.
+    pub fn revoke_all(&self, dn: &str, keep: Option<&str>) -> usize {
+        self.store.remove_by_dn(dn, keep).unwrap_or_else(|err| {
+            println!("Failed to remove the sessions: {}", err);
+            0
+        })
+    }
+
+    /// Remove the expired sessions and records, returns the number of the sessions.
+    pub fn sweep(&self) -> usize {
+        let now = Utc::now();
+        if let Err(err) = self.records.remove_expired(now) {
+            println!("Failed to remove the expired records: {}", err);
+        }
+        self.store
+            .remove_expired(
+                now - self.cfg.absolute_timeout(),
+                now - self.cfg.idle_timeout(),
+            )
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_make_ssid() {
+        let ssids: HashSet<String> = (0..64).map(|_| Session::make_ssid()).collect();
+        assert_eq!(ssids.len(), 64);
+        for ssid in &ssids {
+            let bytes = base64::decode_config(ssid, base64::URL_SAFE_NO_PAD).unwrap();
+            assert_eq!(bytes.len(), SSID_LEN);
+            assert!(bytes.len() * 8 >= 128);
+        }
+    }
+
+    #[test]
+    fn test_cookie() {
+        let mut cfg = SessionConfig::from(&BTreeMap::new());
+        let manager = SessionManager::new(Clone::clone(&cfg)).unwrap();
+        let cookie = manager.cookie(SESSION_COOKIE, "v".to_string());
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_ne!(cookie.secure(), Some(true));
+
+        cfg.secure_cookie = true;
+        let manager = SessionManager::new(cfg).unwrap();
+        let cookie = manager.cookie(SESSION_COOKIE, "v".to_string());
+        assert_eq!(cookie.secure(), Some(true));
+    }
 }
@@ -0,0 +1,456 @@
+use super::parse_generalized_time;
+use crate::config::{table_get_bool, table_get_int};
+use crate::store::RecordStore;
+use chrono::{DateTime, Duration, Utc};
+use rocket::config::Value;
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+
+const DEFAULT_WINDOW: i64 = 900;
+const DEFAULT_FREE_ATTEMPTS: i64 = 3;
+const DEFAULT_BASE_DELAY: i64 = 2;
+const DEFAULT_MAX_DELAY: i64 = 300;
+const DEFAULT_LOCKOUT_THRESHOLD: i64 = 10;
+const DEFAULT_LOCKOUT_DURATION: i64 = 1800;
+/// The kind of the failures per username in the record store.
+const USER_KIND: &str = "throttle_user";
+/// The kind of the failures per client ip in the record store.
+const IP_KIND: &str = "throttle_ip";
+/// The `pwdAccountLockedTime` of the accounts locked permanently by the password policy.
+const PWD_LOCKED_PERMANENTLY: &str = "000001010000Z";
+
+#[derive(Clone, Debug)]
+pub struct ThrottleConfig {
+    /// The sliding window in seconds to count the failures.
+    pub window: i64,
+    /// The failures allowed in the window before backoff.
+    pub free_attempts: u32,
+    /// The delay in seconds after the first failure exceeds `free_attempts`,
+    /// doubled on each further failure.
+    pub base_delay: i64,
+    /// The maximum delay in seconds.
+    pub max_delay: i64,
+    /// Lock the account temporarily after too many failures.
+    pub lockout: bool,
+    /// The failures in the window to lock the account.
+    pub lockout_threshold: u32,
+    /// The duration in seconds of the lockout.
+    pub lockout_duration: i64,
+}
+
+impl From<&BTreeMap<String, Value>> for ThrottleConfig {
+    fn from(table: &BTreeMap<String, Value>) -> Self {
+        Self {
+            window: table_get_int(table, "window", DEFAULT_WINDOW).max(1),
+            free_attempts: table_get_int(table, "free_attempts", DEFAULT_FREE_ATTEMPTS)
+                .clamp(0, u32::MAX as i64) as u32,
+            base_delay: table_get_int(table, "base_delay", DEFAULT_BASE_DELAY).max(0),
+            max_delay: table_get_int(table, "max_delay", DEFAULT_MAX_DELAY).max(0),
+            lockout: table_get_bool(table, "lockout", true),
+            lockout_threshold: table_get_int(table, "lockout_threshold", DEFAULT_LOCKOUT_THRESHOLD)
+                .clamp(1, u32::MAX as i64) as u32,
+            lockout_duration: table_get_int(table, "lockout_duration", DEFAULT_LOCKOUT_DURATION)
+                .max(0),
+        }
+    }
+}
+
+/// The reasons of a rejected login attempt.
+#[derive(Clone, Copy, Debug)]
+pub enum ThrottleError {
+    /// Too many failures, retry after the seconds.
+    TooManyAttempts(i64),
+    /// The account is locked until the time.
+    Locked(DateTime<Utc>),
+}
+
+impl ThrottleError {
+    /// Returns the message to show to the user.
+    pub fn message(&self) -> String {
+        match self {
+            ThrottleError::TooManyAttempts(secs) => {
+                format!("尝试次数过多，请在 {} 秒后重试！", secs)
+            }
+            ThrottleError::Locked(until) => format!(
+                "账号因多次登录失败已被临时锁定，请在 {} 后重试或与管理员联系！",
+                until
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct FailureRecord {
+    /// The username or the client ip.
+    key: String,
+    failures: VecDeque<DateTime<Utc>>,
+    blocked_until: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl FailureRecord {
+    /// Returns the time the record is no longer needed after, by the `window`.
+    fn expires(&self, window: Duration) -> DateTime<Utc> {
+        self.failures
+            .back()
+            .map(|x| *x + window)
+            .into_iter()
+            .chain(self.blocked_until)
+            .chain(self.locked_until)
+            .max()
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+/// The account locked by the throttle.
+#[derive(Clone, Debug, Serialize)]
+pub struct LockedAccount {
+    pub username: String,
+    pub locked_until: String,
+}
+
+/// The account locked by the password policy of the directory.
+#[derive(Clone, Debug, Serialize)]
+pub struct PolicyLockedAccount {
+    pub dn: String,
+    pub uid: String,
+    pub locked_time: String,
+}
+
+#[derive(Debug, FromForm)]
+pub struct UnlockRequest {
+    /// The username locked by the throttle.
+    pub username: Option<String>,
+    /// The dn locked by the password policy.
+    pub dn: Option<String>,
+}
+
+/// The limiter of the login attempts per username and per client ip, the
+/// failures are kept in the record store to be shared by the processes.
+pub struct LoginThrottle {
+    cfg: ThrottleConfig,
+    records: Arc<dyn RecordStore>,
+}
+
+impl LoginThrottle {
+    /// Construct a new login throttle on `records`.
+    pub fn new(cfg: ThrottleConfig, records: Arc<dyn RecordStore>) -> Self {
+        Self { cfg, records }
+    }
+
+    /// Returns error if the login of `username` from `ip` should be rejected.
+    pub fn check(&self, username: &str, ip: &str) -> Result<(), ThrottleError> {
+        self.check_at(username, ip, Utc::now())
+    }
+
+    /// Returns error if the login of `username` from `ip` should be rejected at `now`.
+    fn check_at(&self, username: &str, ip: &str, now: DateTime<Utc>) -> Result<(), ThrottleError> {
+        if let Some(record) = self.record(USER_KIND, &normalize(username)) {
+            if let Some(until) = record.locked_until.filter(|x| *x > now) {
+                return Err(ThrottleError::Locked(until));
+            }
+            if let Some(until) = record.blocked_until.filter(|x| *x > now) {
+                return Err(ThrottleError::TooManyAttempts(
+                    (until - now).num_seconds() + 1,
+                ));
+            }
+        }
+        if let Some(until) = self
+            .record(IP_KIND, ip)
+            .and_then(|x| x.blocked_until)
+            .filter(|x| *x > now)
+        {
+            return Err(ThrottleError::TooManyAttempts(
+                (until - now).num_seconds() + 1,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Record a failed login of `username` from `ip`.
+    pub fn record_failure(&self, username: &str, ip: &str) {
+        self.record_failure_at(username, ip, Utc::now());
+    }
+
+    /// Record a failed login of `username` from `ip` at `now`.
+    fn record_failure_at(&self, username: &str, ip: &str, now: DateTime<Utc>) {
+        self.update(USER_KIND, &normalize(username), |record| {
+            self.push_failure(record, true, now)
+        });
+        self.update(IP_KIND, ip, |record| self.push_failure(record, false, now));
+    }
+
+    /// Record a succeeded login of `username`, the failures of it will be cleared.
+    pub fn record_success(&self, username: &str) {
+        if let Err(err) = self.records.remove(USER_KIND, &normalize(username)) {
+            println!("Failed to clear the login failures: {}", err);
+        }
+    }
+
+    /// Returns the accounts currently locked.
+    pub fn locked_accounts(&self) -> Vec<LockedAccount> {
+        let now = Utc::now();
+        let records: Vec<FailureRecord> = match self.records.list_json(USER_KIND) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Failed to list the login failures: {}", err);
+                Vec::new()
+            }
+        };
+        let mut accounts: Vec<LockedAccount> = records
+            .into_iter()
+            .filter_map(|x| {
+                let key = x.key;
+                x.locked_until
+                    .filter(|t| *t > now)
+                    .map(|until| LockedAccount {
+                        username: key,
+                        locked_until: until
+                            .with_timezone(&chrono::Local)
+                            .format("%Y-%m-%d %H:%M:%S")
+                            .to_string(),
+                    })
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.username.cmp(&b.username));
+        accounts
+    }
+
+    /// Unlock the account of `username`.
+    pub fn unlock(&self, username: &str) {
+        self.record_success(username);
+    }
+
+    /// Returns the record of `kind` specified by `key`.
+    fn record(&self, kind: &str, key: &str) -> Option<FailureRecord> {
+        match self.records.get_json(kind, key) {
+            Ok(v) => v,
+            Err(err) => {
+                println!("Failed to look up the login failures: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Apply `f` to the record of `kind` specified by `key`, the record is replaced
+    /// by compare-and-swap since the other processes may update it concurrently.
+    fn update<F>(&self, kind: &str, key: &str, f: F)
+    where
+        F: Fn(&mut FailureRecord),
+    {
+        let window = Duration::seconds(self.cfg.window);
+        loop {
+            let old = match self.records.get(kind, key) {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("Failed to look up the login failures: {}", err);
+                    return;
+                }
+            };
+            let mut record = old
+                .as_ref()
+                .and_then(|x| serde_json::from_str(x).ok())
+                .unwrap_or_else(|| FailureRecord {
+                    key: key.to_string(),
+                    ..Default::default()
+                });
+            f(&mut record);
+            let value = match serde_json::to_string(&record) {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("Failed to save the login failures: {}", err);
+                    return;
+                }
+            };
+            let expires = record.expires(window);
+            let saved = match &old {
+                Some(old) => self.records.replace(kind, key, old, &value, expires),
+                None => self.records.insert(kind, key, &value, expires),
+            };
+            match saved {
+                Ok(true) => return,
+                Ok(false) => continue,
+                Err(err) => {
+                    println!("Failed to save the login failures: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn push_failure(&self, record: &mut FailureRecord, lockable: bool, now: DateTime<Utc>) {
+        let window_start = now - Duration::seconds(self.cfg.window);
+        while record.failures.front().map_or(false, |x| *x < window_start) {
+            record.failures.pop_front();
+        }
+        record.failures.push_back(now);
+        let count = record.failures.len() as u32;
+        if count > self.cfg.free_attempts {
+            let exp = (count - self.cfg.free_attempts - 1).min(16);
+            let delay = (self.cfg.base_delay << exp).min(self.cfg.max_delay);
+            record.blocked_until = Some(now + Duration::seconds(delay));
+        }
+        if lockable && self.cfg.lockout && count >= self.cfg.lockout_threshold {
+            record.locked_until = Some(now + Duration::seconds(self.cfg.lockout_duration));
+            record.failures.clear();
+        }
+    }
+}
+
+/// Returns the `username` normalized as the key of records.
+fn normalize(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Returns true if the account is locked by the password policy at `now`, by the
+/// values of `pwdAccountLockedTime` and the `pwdLockoutDuration` in seconds, the
+/// account is locked until unlocked by the administrators if the duration is 0.
+pub fn is_policy_locked(locked_time: &[String], duration: i64, now: DateTime<Utc>) -> bool {
+    locked_time.iter().any(|x| {
+        x == PWD_LOCKED_PERMANENTLY
+            || duration <= 0
+            || parse_generalized_time(x).map_or(true, |t| t + Duration::seconds(duration) > now)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+
+    fn throttle() -> LoginThrottle {
+        let records: Arc<dyn RecordStore> = Arc::new(MemoryRecordStore::new());
+        LoginThrottle::new(
+            ThrottleConfig {
+                window: 900,
+                free_attempts: 3,
+                base_delay: 2,
+                max_delay: 300,
+                lockout: true,
+                lockout_threshold: 10,
+                lockout_duration: 1800,
+            },
+            records,
+        )
+    }
+
+    #[test]
+    fn test_throttle_backoff() {
+        let throttle = throttle();
+        let now = Utc::now();
+        for _ in 0..3 {
+            throttle.record_failure_at("alice", "10.0.0.1", now);
+        }
+        assert!(throttle.check_at("alice", "10.0.0.1", now).is_ok());
+        throttle.record_failure_at("Alice", "10.0.0.1", now);
+        match throttle.check_at("alice", "10.0.0.2", now) {
+            Err(ThrottleError::TooManyAttempts(secs)) => assert_eq!(secs, 3),
+            other => panic!("unexpected {:?}", other),
+        }
+        // The delay is doubled on each further failure.
+        throttle.record_failure_at("alice", "10.0.0.1", now);
+        match throttle.check_at("alice", "10.0.0.2", now) {
+            Err(ThrottleError::TooManyAttempts(secs)) => assert_eq!(secs, 5),
+            other => panic!("unexpected {:?}", other),
+        }
+        // The client ip is limited for any username.
+        assert!(throttle.check_at("bob", "10.0.0.1", now).is_err());
+        assert!(throttle
+            .check_at("alice", "10.0.0.2", now + Duration::seconds(5))
+            .is_ok());
+        // The failures out of the window are not counted.
+        let later = now + Duration::seconds(901);
+        throttle.record_failure_at("alice", "10.0.0.3", later);
+        assert!(throttle.check_at("alice", "10.0.0.3", later).is_ok());
+    }
+
+    #[test]
+    fn test_throttle_lockout() {
+        let throttle = throttle();
+        let now = Utc::now();
+        for i in 0..10 {
+            throttle.record_failure_at("alice", &format!("10.0.0.{}", i), now);
+        }
+        match throttle.check_at("alice", "10.0.1.1", now + Duration::seconds(600)) {
+            Err(ThrottleError::Locked(until)) => {
+                assert_eq!(until, now + Duration::seconds(1800))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(throttle.locked_accounts().len(), 1);
+        assert!(throttle
+            .check_at("alice", "10.0.1.1", now + Duration::seconds(1801))
+            .is_ok());
+        throttle.unlock("alice");
+        assert!(throttle.check_at("alice", "10.0.1.1", now).is_ok());
+        assert!(throttle.locked_accounts().is_empty());
+    }
+
+    #[test]
+    fn test_throttle_success() {
+        let throttle = throttle();
+        let now = Utc::now();
+        for _ in 0..4 {
+            throttle.record_failure_at("alice", "10.0.0.1", now);
+        }
+        assert!(throttle.check_at("alice", "10.0.0.2", now).is_err());
+        throttle.record_success("alice");
+        assert!(throttle.check_at("alice", "10.0.0.2", now).is_ok());
+    }
+
+    #[test]
+    fn test_throttle_shared() {
+        // Two throttles on the same store stand for two processes.
+        let records: Arc<dyn RecordStore> = Arc::new(MemoryRecordStore::new());
+        let cfg = ThrottleConfig::from(&BTreeMap::new());
+        let first = LoginThrottle::new(Clone::clone(&cfg), Clone::clone(&records));
+        let second = LoginThrottle::new(cfg, records);
+        let now = Utc::now();
+        for i in 0..10 {
+            let throttle = if i % 2 == 0 { &first } else { &second };
+            throttle.record_failure_at("alice", "10.0.0.1", now);
+        }
+        assert!(first.check_at("alice", "10.0.0.2", now).is_err());
+        assert_eq!(second.locked_accounts().len(), 1);
+        first.unlock("alice");
+        assert!(second.locked_accounts().is_empty());
+        assert!(second.check_at("alice", "10.0.0.2", now).is_ok());
+    }
+
+    #[test]
+    fn test_throttle_config() {
+        let mut table = BTreeMap::new();
+        table.insert("free_attempts".to_string(), Value::from(-1));
+        table.insert("lockout_threshold".to_string(), Value::from(0));
+        table.insert("window".to_string(), Value::from(-1));
+        let cfg = ThrottleConfig::from(&table);
+        assert_eq!(cfg.free_attempts, 0);
+        assert_eq!(cfg.lockout_threshold, 1);
+        assert_eq!(cfg.window, 1);
+    }
+
+    #[test]
+    fn test_policy_locked() {
+        let now = Utc::now();
+        let locked = |x: DateTime<Utc>| vec![x.format("%Y%m%d%H%M%SZ").to_string()];
+        assert!(!is_policy_locked(&[], 600, now));
+        assert!(is_policy_locked(&locked(now), 0, now + Duration::days(365)));
+        assert!(is_policy_locked(
+            &locked(now),
+            600,
+            now + Duration::seconds(599)
+        ));
+        assert!(!is_policy_locked(
+            &locked(now),
+            600,
+            now + Duration::seconds(601)
+        ));
+        let permanent = vec![PWD_LOCKED_PERMANENTLY.to_string()];
+        assert!(is_policy_locked(&permanent, 600, now));
+        assert!(is_policy_locked(&["invalid".to_string()], 600, now));
+    }
+}
@@ -0,0 +1,107 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The minimum length of the signing key.
+const SIGNER_KEY_LEN: usize = 32;
+
+/// A signer to make the tamper-proof tokens.
+///
+/// The token is `base64url(payload).base64url(hmac_sha256(payload))`.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    /// Construct a new token signer with the `key`.
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+
+    /// Construct a new token signer with a randomized key, the tokens are
+    /// invalid in other processes or after restart.
+    pub fn random() -> Self {
+        Self::new((0..SIGNER_KEY_LEN).map(|_| rand::random::<u8>()).collect())
+    }
+
+    /// Construct a new token signer with the base64 encoded `key`, e.g. the
+    /// `token_key` in the config, at least 32 bytes after decoded.
+    pub fn from_base64(key: &str) -> Result<Self, String> {
+        let key = base64::decode(key.trim()).map_err(|err| err.to_string())?;
+        if key.len() < SIGNER_KEY_LEN {
+            return Err(format!("The key must be at least {} bytes", SIGNER_KEY_LEN));
+        }
+        Ok(Self::new(key))
+    }
+
+    /// Returns the signer with the key derived for `purpose`, the tokens of
+    /// different purposes can't be used for each other.
+    pub fn derive(&self, purpose: &str) -> Self {
+        let mut mac = self.mac();
+        mac.update(purpose.as_bytes());
+        Self::new(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC can take key of any size")
+    }
+
+    /// Returns the signed token of the `payload`.
+    pub fn sign(&self, payload: &[u8]) -> String {
+        let mut mac = self.mac();
+        mac.update(payload);
+        let tag = mac.finalize().into_bytes();
+        format!(
+            "{}.{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns the payload of the `token` if the signature matched.
+    pub fn verify(&self, token: &str) -> Option<Vec<u8>> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts
+            .next()
+            .and_then(|x| base64::decode_config(x, base64::URL_SAFE_NO_PAD).ok())?;
+        let tag = parts
+            .next()
+            .and_then(|x| base64::decode_config(x, base64::URL_SAFE_NO_PAD).ok())?;
+        let mut mac = self.mac();
+        mac.update(&payload);
+        mac.verify(&tag).ok().map(|_| payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_signer() {
+        let signer = TokenSigner::random();
+        let token = signer.sign(b"payload");
+        assert_eq!(signer.verify(&token), Some(b"payload".to_vec()));
+        assert_eq!(TokenSigner::random().verify(&token), None);
+        assert_eq!(signer.derive("recover").verify(&token), None);
+
+        // The payload can't be replaced without the key.
+        let (_, tag) = token.split_at(token.find('.').unwrap());
+        let forged = format!(
+            "{}{}",
+            base64::encode_config(b"PAYLOAD", base64::URL_SAFE_NO_PAD),
+            tag
+        );
+        assert_eq!(signer.verify(&forged), None);
+        assert_eq!(signer.verify("garbage"), None);
+
+        let key = base64::encode([7u8; 32]);
+        let token = TokenSigner::from_base64(&key).unwrap().sign(b"payload");
+        let other = TokenSigner::from_base64(&key).unwrap();
+        assert_eq!(other.verify(&token), Some(b"payload".to_vec()));
+        assert!(TokenSigner::from_base64(&base64::encode([7u8; 16])).is_err());
+        assert!(TokenSigner::from_base64("not base64!").is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use super::Role;
+use crate::store::{RecordStore, StoreError};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::sync::Arc;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// The length of the secret in bytes.
+const TOTP_SECRET_LEN: usize = 20;
+/// The time step in seconds.
+const TOTP_STEP: i64 = 30;
+/// The number of digits of the codes.
+const TOTP_DIGITS: u32 = 6;
+/// The number of steps accepted before and after current.
+const TOTP_SKEW: i64 = 1;
+/// The number of recovery codes generated on enrollment.
+const RECOVERY_CODE_COUNT: usize = 8;
+/// The lifetime of the pending logins.
+const PENDING_LOGIN_TTL: i64 = 5;
+/// The maximum attempts of the second factor per pending login.
+const PENDING_LOGIN_ATTEMPTS: u32 = 5;
+/// The lifetime of the pending enrollments in minutes.
+const PENDING_ENROLLMENT_TTL: i64 = 60;
+/// The kind of the pending logins in the record store.
+const PENDING_LOGIN_KIND: &str = "totp_login";
+/// The kind of the pending enrollments in the record store.
+const PENDING_ENROLLMENT_KIND: &str = "totp_enrollment";
+/// The kind of the last accepted steps in the record store.
+const TOTP_STEP_KIND: &str = "totp_step";
+
+/// The time-based one-time password generator (RFC 6238).
+#[derive(Clone, Debug)]
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    /// Construct a new generator with a randomized secret.
+    pub fn generate() -> Self {
+        Self {
+            secret: (0..TOTP_SECRET_LEN).map(|_| rand::random::<u8>()).collect(),
+        }
+    }
+
+    /// Construct a new generator from the base32 encoded `secret`.
+    pub fn from_base32(secret: &str) -> Option<Self> {
+        base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+            .filter(|x| !x.is_empty())
+            .map(|secret| Self { secret })
+    }
+
+    /// Returns the base32 encoded secret.
+    pub fn to_base32(&self) -> String {
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &self.secret)
+    }
+
+    /// Returns the `otpauth://` uri to provision authenticator apps.
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            self.to_base32(),
+            percent_encode(issuer),
+            TOTP_DIGITS,
+            TOTP_STEP
+        )
+    }
+
+    /// Returns the code of the time step `counter` (RFC 4226).
+    pub fn code_at(&self, counter: u64) -> u32 {
+        let mut mac =
+            HmacSha1::new_from_slice(&self.secret).expect("HMAC can take key of any size");
+        mac.update(&counter.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        binary % 10u32.pow(TOTP_DIGITS)
+    }
+
+    /// Returns the time step matched if the `code` matches at unix time `now`.
+    pub fn verify(&self, code: &str, now: i64) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize {
+            return None;
+        }
+        let code = code.parse::<u32>().ok()?;
+        let step = now / TOTP_STEP;
+        (-TOTP_SKEW..=TOTP_SKEW)
+            .map(|x| step + x)
+            .filter(|x| *x >= 0)
+            .map(|x| x as u64)
+            .find(|x| self.code_at(*x) == code)
+    }
+}
+
+/// Returns the `value` percent-encoded for the uri.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Returns the randomized recovery codes, formatted like `abcde-12345`.
+pub fn make_recovery_codes() -> Vec<String> {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let half = || -> String {
+                (0..5)
+                    .map(|_| CHARSET[rand::random::<usize>() % CHARSET.len()] as char)
+                    .collect()
+            };
+            format!("{}-{}", half(), half())
+        })
+        .collect()
+}
+
+#[derive(Debug, FromForm)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, FromForm)]
+pub struct TotpEnroll {
+    pub code: String,
+    pub password: String,
+}
+
+#[derive(Debug, FromForm)]
+pub struct TotpDisable {
+    pub password: String,
+}
+
+/// The login which the password verified, waiting for the second factor.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub dn: String,
+    pub uid: String,
+    pub roles: Vec<Role>,
+    pub secret: String,
+    pub recovery_codes: Vec<String>,
+    /// The password expired, must be changed after the second factor.
+    pub must_change_password: bool,
+    pub attempts: u32,
+    pub expires: DateTime<Utc>,
+}
+
+/// The enrollment waiting for the first code to confirm.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingEnrollment {
+    pub secret: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// The manager of the pending logins and enrollments of two-factor authentication,
+/// kept in the record store to be shared by the processes.
+pub struct TotpManager {
+    /// The issuer shown in the authenticator apps.
+    pub issuer: String,
+    records: Arc<dyn RecordStore>,
+}
+
+impl TotpManager {
+    /// Construct a new totp manager on `records`.
+    pub fn new(issuer: String, records: Arc<dyn RecordStore>) -> Self {
+        Self { issuer, records }
+    }
+
+    /// Add a pending login, returns the key of it.
+    pub fn add_login(
+        &self,
+        dn: String,
+        uid: String,
+        roles: Vec<Role>,
+        secret: String,
+        recovery_codes: Vec<String>,
+        must_change_password: bool,
+    ) -> String {
+        let key = base64::encode_config(
+            (0..32).map(|_| rand::random::<u8>()).collect::<Vec<u8>>(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let login = PendingLogin {
+            dn,
+            uid,
+            roles,
+            secret,
+            recovery_codes,
+            must_change_password,
+            attempts: 0,
+            expires: Utc::now() + Duration::minutes(PENDING_LOGIN_TTL),
+        };
+        if let Err(err) = self
+            .records
+            .put_json(PENDING_LOGIN_KIND, &key, &login, login.expires)
+        {
+            println!("Failed to save the pending login: {}", err);
+        }
+        key
+    }
+
+    /// Returns the pending login specified by `k` if not expired, the attempts
+    /// of it will be increased, and removed if exceeds the limit.
+    pub fn attempt_login(&self, k: &str) -> Option<PendingLogin> {
+        // The attempts are counted by compare-and-swap, the processes may
+        // receive the attempts of the same login concurrently.
+        loop {
+            let value = match self.records.get(PENDING_LOGIN_KIND, k) {
+                Ok(v) => v?,
+                Err(err) => {
+                    println!("Failed to look up the pending login: {}", err);
+                    return None;
+                }
+            };
+            let mut login: PendingLogin = serde_json::from_str(&value).ok()?;
+            login.attempts += 1;
+            if login.attempts > PENDING_LOGIN_ATTEMPTS {
+                self.remove_login(k);
+                return None;
+            }
+            let updated = serde_json::to_string(&login).ok()?;
+            match self
+                .records
+                .replace(PENDING_LOGIN_KIND, k, &value, &updated, login.expires)
+            {
+                Ok(true) => return Some(login),
+                Ok(false) => continue,
+                Err(err) => {
+                    println!("Failed to update the pending login: {}", err);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the pending login specified by `k` exists.
+    pub fn has_login(&self, k: &str) -> bool {
+        self.records
+            .get(PENDING_LOGIN_KIND, k)
+            .map_or(false, |x| x.is_some())
+    }
+
+    /// Remove the pending login specified by `k`.
+    pub fn remove_login(&self, k: &str) {
+        if let Err(err) = self.records.remove(PENDING_LOGIN_KIND, k) {
+            println!("Failed to remove the pending login: {}", err);
+        }
+    }
+
+    /// Returns the pending enrollment of session `ssid`, a new one will be made if not exists.
+    pub fn enrollment(&self, ssid: &str) -> PendingEnrollment {
+        loop {
+            match self
+                .records
+                .get_json::<PendingEnrollment>(PENDING_ENROLLMENT_KIND, ssid)
+            {
+                Ok(Some(v)) => return v,
+                Ok(None) => {}
+                Err(err) => println!("Failed to look up the pending enrollment: {}", err),
+            }
+            let enrollment = PendingEnrollment {
+                secret: Totp::generate().to_base32(),
+                recovery_codes: make_recovery_codes(),
+            };
+            let expires = Utc::now() + Duration::minutes(PENDING_ENROLLMENT_TTL);
+            // The one made by the other process first wins.
+            let inserted = serde_json::to_string(&enrollment)
+                .map_err(StoreError::from)
+                .and_then(|x| {
+                    self.records
+                        .insert(PENDING_ENROLLMENT_KIND, ssid, &x, expires)
+                });
+            match inserted {
+                Ok(true) => return enrollment,
+                Ok(false) => continue,
+                Err(err) => {
+                    println!("Failed to save the pending enrollment: {}", err);
+                    return enrollment;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the time `step` is later than the last one accepted for the
+    /// user specified by `dn`, then it will be the last one, so the codes can't be
+    /// replayed in the skew.
+    pub fn accept_step(&self, dn: &str, step: u64) -> bool {
+        // The step is kept until the codes of it are out of the skew.
+        let expires = Utc::now() + Duration::seconds(TOTP_STEP * (2 * TOTP_SKEW + 2));
+        let value = step.to_string();
+        loop {
+            let last = match self.records.get(TOTP_STEP_KIND, dn) {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("Failed to look up the last step of {}: {}", dn, err);
+                    return false;
+                }
+            };
+            let accepted = match &last {
+                Some(last) if last.parse::<u64>().map_or(true, |x| x >= step) => return false,
+                Some(last) => self
+                    .records
+                    .replace(TOTP_STEP_KIND, dn, last, &value, expires),
+                None => self.records.insert(TOTP_STEP_KIND, dn, &value, expires),
+            };
+            match accepted {
+                Ok(true) => return true,
+                Ok(false) => continue,
+                Err(err) => {
+                    println!("Failed to save the last step of {}: {}", dn, err);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Remove and returns the pending enrollment of session `ssid`.
+    pub fn take_enrollment(&self, ssid: &str) -> Option<PendingEnrollment> {
+        let enrollment = self
+            .records
+            .get_json(PENDING_ENROLLMENT_KIND, ssid)
+            .ok()??;
+        match self.records.remove(PENDING_ENROLLMENT_KIND, ssid) {
+            Ok(true) => Some(enrollment),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+
+    #[test]
+    fn test_totp_rfc6238() {
+        // Test vectors of RFC 6238 for SHA1, truncated to 6 digits.
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        assert_eq!(totp.code_at(59 / 30), 287082);
+        assert_eq!(totp.code_at(1111111109 / 30), 81804);
+        assert_eq!(totp.code_at(1234567890 / 30), 5924);
+        assert_eq!(totp.code_at(2000000000 / 30), 279037);
+        assert_eq!(totp.verify("287082", 59), Some(1));
+        assert_eq!(totp.verify("287082", 59 + 30), Some(1));
+        assert_eq!(totp.verify("287082", 59 + 90), None);
+        assert_eq!(totp.verify("081804", 1111111109), Some(1111111109 / 30));
+        assert_eq!(totp.verify("81804", 1111111109), None);
+    }
+
+    #[test]
+    fn test_totp_replay() {
+        let records: Arc<dyn RecordStore> = Arc::new(MemoryRecordStore::new());
+        let manager = TotpManager::new("test".to_string(), records);
+        assert!(manager.accept_step("uid=alice", 100));
+        assert!(!manager.accept_step("uid=alice", 100));
+        assert!(!manager.accept_step("uid=alice", 99));
+        assert!(manager.accept_step("uid=bob", 100));
+        assert!(manager.accept_step("uid=alice", 101));
+        assert!(!manager.accept_step("uid=alice", 101));
+    }
+
+    #[test]
+    fn test_totp_shared() {
+        // Two managers on the same store stand for two processes.
+        let records: Arc<dyn RecordStore> = Arc::new(MemoryRecordStore::new());
+        let first = TotpManager::new("test".to_string(), Clone::clone(&records));
+        let second = TotpManager::new("test".to_string(), records);
+        let key = first.add_login(
+            "uid=alice".to_string(),
+            "alice".to_string(),
+            vec![Role::USER],
+            "secret".to_string(),
+            Vec::new(),
+            false,
+        );
+        assert!(second.has_login(&key));
+        for attempts in 1..=PENDING_LOGIN_ATTEMPTS {
+            let manager = if attempts % 2 == 0 { &first } else { &second };
+            let login = manager.attempt_login(&key).unwrap();
+            assert_eq!(login.uid, "alice");
+            assert_eq!(login.attempts, attempts);
+        }
+        assert!(first.attempt_login(&key).is_none());
+        assert!(!second.has_login(&key));
+
+        let enrollment = first.enrollment("ssid");
+        assert_eq!(second.enrollment("ssid").secret, enrollment.secret);
+        assert!(second.take_enrollment("ssid").is_some());
+        assert!(first.take_enrollment("ssid").is_none());
+
+        assert!(first.accept_step("uid=alice", 100));
+        assert!(!second.accept_step("uid=alice", 100));
+    }
+
+    #[test]
+    fn test_totp_base32() {
+        let totp = Totp {
+            secret: b"12345678901234567890".to_vec(),
+        };
+        assert_eq!(totp.to_base32(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        let decoded = Totp::from_base32(&totp.to_base32()).unwrap();
+        assert_eq!(decoded.secret, totp.secret);
+        assert!(Totp::from_base32("").is_none());
+        assert!(Totp::from_base32("!!!").is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use ldap3::SearchEntry;
+use rocket::FromForm;
+use serde::Serialize;
+
+/// The default number of users per page.
+const DEFAULT_PER_PAGE: usize = 20;
+/// The maximum number of users per page.
+const MAX_PER_PAGE: usize = 100;
+
+#[derive(Debug, Default, FromForm)]
+pub struct UserQuery {
+    /// The keyword to search in `uid`, `cn` and `mail`.
+    pub q: Option<String>,
+    /// The field to sort by, `uid`, `cn`, `mail` or `created`.
+    pub sort: Option<String>,
+    /// The order to sort, `asc` or `desc`.
+    pub order: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+impl UserQuery {
+    /// Returns the field to sort by.
+    pub fn sort(&self) -> &str {
+        match self.sort.as_deref() {
+            Some("cn") => "cn",
+            Some("mail") => "mail",
+            Some("created") => "created",
+            _ => "uid",
+        }
+    }
+
+    /// Returns the attribute of the field to sort by.
+    pub fn sort_attr(&self) -> &'static str {
+        match self.sort() {
+            "cn" => "cn",
+            "mail" => "mail",
+            "created" => "createTimestamp",
+            _ => "uid",
+        }
+    }
+
+    /// Returns the requested page, starts from 1.
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    /// Returns true if sort in descending order.
+    pub fn desc(&self) -> bool {
+        self.order.as_deref() == Some("desc")
+    }
+
+    /// Returns the number of users per page.
+    pub fn per_page(&self) -> usize {
+        self.per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    /// Returns the `users` sorted and paginated by the query.
+    pub fn apply(&self, mut users: Vec<UserSummary>) -> (Vec<UserSummary>, UserListMeta) {
+        match self.sort() {
+            "cn" => users.sort_by(|a, b| a.cn.cmp(&b.cn)),
+            "mail" => users.sort_by(|a, b| a.mail.cmp(&b.mail)),
+            "created" => users.sort_by(|a, b| a.created.cmp(&b.created)),
+            _ => users.sort_by(|a, b| a.uid.cmp(&b.uid)),
+        }
+        if self.desc() {
+            users.reverse();
+        }
+        let total = users.len();
+        let per_page = self.per_page();
+        let pages = ((total + per_page - 1) / per_page).max(1);
+        let page = self.page().min(pages);
+        let users = users
+            .into_iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect();
+        (users, self.meta(total, page, pages, false))
+    }
+
+    /// Returns the `users` of `page` fetched in order from the server, `total`
+    /// is the estimate of the server if any, `more` is true if the server has
+    /// more users after the page.
+    pub fn paged(
+        &self,
+        users: Vec<UserSummary>,
+        page: usize,
+        total: Option<usize>,
+        more: bool,
+    ) -> (Vec<UserSummary>, UserListMeta) {
+        let per_page = self.per_page();
+        let seen = (page - 1) * per_page + users.len();
+        let (total, pages, more) = match total.filter(|x| *x >= seen) {
+            Some(v) => (v, ((v + per_page - 1) / per_page).max(page), false),
+            // The total is unknown, only the next page can be linked.
+            None => (seen, page + more as usize, more),
+        };
+        (users, self.meta(total, page, pages, more))
+    }
+
+    fn meta(&self, total: usize, page: usize, pages: usize, more: bool) -> UserListMeta {
+        UserListMeta {
+            q: self.q.clone().unwrap_or_default(),
+            sort: self.sort().to_string(),
+            order: if self.desc() { "desc" } else { "asc" }.to_string(),
+            total,
+            more,
+            page,
+            per_page: self.per_page(),
+            pages,
+        }
+    }
+}
+
+/// The summary of an user in the listing.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserSummary {
+    pub dn: String,
+    pub uid: String,
+    pub cn: String,
+    pub mail: String,
+    pub created: String,
+}
+
+impl From<SearchEntry> for UserSummary {
+    fn from(entry: SearchEntry) -> Self {
+        let attr = |name: &str| {
+            entry
+                .attrs
+                .get(name)
+                .and_then(|x| x.first())
+                .map_or_else(String::new, |x| x.to_string())
+        };
+        let created =
+            chrono::NaiveDate::parse_from_str(&attr("createTimestamp"), "%Y%m%d%H%M%S%.3fZ")
+                .map_or_else(|_| String::new(), |x| x.to_string());
+        Self {
+            uid: attr("uid"),
+            cn: attr("cn"),
+            mail: attr("mail"),
+            created,
+            dn: entry.dn,
+        }
+    }
+}
+
+/// The pagination of the listing.
+#[derive(Clone, Debug, Serialize)]
+pub struct UserListMeta {
+    pub q: String,
+    pub sort: String,
+    pub order: String,
+    pub total: usize,
+    /// True if `total` is a lower bound, the server didn't estimate it.
+    pub more: bool,
+    pub page: usize,
+    pub per_page: usize,
+    pub pages: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(uid: &str, cn: &str, created: &str) -> UserSummary {
+        UserSummary {
+            dn: format!("uid={},dc=example,dc=com", uid),
+            uid: uid.to_string(),
+            cn: cn.to_string(),
+            mail: format!("{}@example.com", uid),
+            created: created.to_string(),
+        }
+    }
+
+    fn users() -> Vec<UserSummary> {
+        vec![
+            user("carol", "Carol", "2021-03-01"),
+            user("alice", "Zoe", "2021-01-01"),
+            user("bob", "Bob", "2021-02-01"),
+        ]
+    }
+
+    fn query(sort: &str, order: &str, page: Option<usize>, per_page: usize) -> UserQuery {
+        UserQuery {
+            q: None,
+            sort: Some(sort.to_string()),
+            order: Some(order.to_string()),
+            page,
+            per_page: Some(per_page),
+        }
+    }
+
+    fn uids(users: &[UserSummary]) -> Vec<&str> {
+        users.iter().map(|x| x.uid.as_str()).collect()
+    }
+
+    #[test]
+    fn test_user_query_sort() {
+        let (list, _) = query("uid", "asc", None, 10).apply(users());
+        assert_eq!(uids(&list), vec!["alice", "bob", "carol"]);
+        let (list, _) = query("cn", "asc", None, 10).apply(users());
+        assert_eq!(uids(&list), vec!["bob", "carol", "alice"]);
+        let (list, meta) = query("created", "desc", None, 10).apply(users());
+        assert_eq!(uids(&list), vec!["carol", "bob", "alice"]);
+        assert_eq!(
+            (meta.sort.as_str(), meta.order.as_str()),
+            ("created", "desc")
+        );
+        // The unknown fields are sorted by uid.
+        let (list, meta) = query("userPassword", "random", None, 10).apply(users());
+        assert_eq!(uids(&list), vec!["alice", "bob", "carol"]);
+        assert_eq!((meta.sort.as_str(), meta.order.as_str()), ("uid", "asc"));
+    }
+
+    #[test]
+    fn test_user_query_bounds() {
+        let (list, meta) = query("uid", "asc", Some(2), 2).apply(users());
+        assert_eq!(uids(&list), vec!["carol"]);
+        assert_eq!((meta.total, meta.page, meta.pages), (3, 2, 2));
+        // The page out of range is clamped.
+        let (list, meta) = query("uid", "asc", Some(9), 2).apply(users());
+        assert_eq!(uids(&list), vec!["carol"]);
+        assert_eq!(meta.page, 2);
+        let (_, meta) = query("uid", "asc", Some(0), 2).apply(users());
+        assert_eq!(meta.page, 1);
+        // The size of pages is limited.
+        assert_eq!(query("uid", "asc", None, 0).per_page(), 1);
+        assert_eq!(query("uid", "asc", None, 1000).per_page(), MAX_PER_PAGE);
+        let (list, meta) = query("uid", "asc", None, 2).apply(Vec::new());
+        assert!(list.is_empty());
+        assert_eq!((meta.total, meta.page, meta.pages), (0, 1, 1));
+    }
+
+    #[test]
+    fn test_user_query_paged() {
+        let q = query("uid", "asc", Some(2), 2);
+        let (_, meta) = q.paged(vec![user("carol", "Carol", "")], 2, Some(3), false);
+        assert_eq!((meta.total, meta.pages, meta.more), (3, 2, false));
+        // Without the estimate, only the next page is known.
+        let page = vec![user("carol", "Carol", ""), user("dave", "Dave", "")];
+        let (_, meta) = q.paged(page, 2, None, true);
+        assert_eq!((meta.total, meta.pages, meta.more), (4, 3, true));
+        let (_, meta) = q.paged(vec![user("carol", "Carol", "")], 2, None, false);
+        assert_eq!((meta.total, meta.pages, meta.more), (3, 2, false));
+    }
+}
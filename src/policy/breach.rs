@@ -0,0 +1,235 @@
+//! The offline check of the passwords against the breach corpora.
+//!
+//! Two formats are supported:
+//!
+//! * The SHA-1 hash list of Have I Been Pwned, ordered by hash, one
+//!   `<HEX>:<COUNT>` per line. It's searched in place by binary search, so
+//!   the whole list is never loaded into memory.
+//! * A prebuilt Bloom filter, the magic `LMBLOOM1`, the number of hashes `k`
+//!   as u32 LE, the number of bits `m` as u64 LE, then `ceil(m / 8)` bytes of
+//!   bits. The bit `i` is at byte `i / 8`, mask `1 << (i % 8)`. The indexes
+//!   of a password are `(h1 + j * h2) % m` for `j` in `0..k`, where `h1` and
+//!   `h2` are the first and the second u64 LE of its SHA-1 digest.
+
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// The magic of the Bloom filter files.
+const BLOOM_MAGIC: &[u8] = b"LMBLOOM1";
+/// The length of the header of the Bloom filter files.
+const BLOOM_HEADER_LEN: usize = 20;
+
+/// Returns the uppercase hex SHA-1 digest of `password`.
+pub fn sha1_hex(password: &str) -> String {
+    Sha1::digest(password.as_bytes())
+        .iter()
+        .fold(String::new(), |mut s, x| {
+            let _ = write!(s, "{:02X}", x);
+            s
+        })
+}
+
+/// The SHA-1 hash list in the HIBP format.
+pub struct HashList {
+    path: PathBuf,
+    len: u64,
+    /// The hashes seen less than this count are ignored.
+    min_count: u64,
+}
+
+impl HashList {
+    /// Open the hash list at `path`.
+    pub fn open<P: Into<PathBuf>>(path: P, min_count: u64) -> io::Result<Self> {
+        let path = path.into();
+        let len = fs::metadata(&path)?.len();
+        Ok(Self {
+            path,
+            len,
+            min_count,
+        })
+    }
+
+    /// Returns true if `password` is in the list.
+    pub fn contains(&self, password: &str) -> io::Result<bool> {
+        let hash = sha1_hex(password);
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut line = String::new();
+        // Search the start of the line in [lo, hi).
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            // Find the first line starting at or after `mid`.
+            let mut start = mid;
+            if mid > 0 {
+                reader.seek(SeekFrom::Start(mid - 1))?;
+                line.clear();
+                start = mid - 1 + reader.read_line(&mut line)? as u64;
+            } else {
+                reader.seek(SeekFrom::Start(0))?;
+            }
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+            line.clear();
+            let n = reader.read_line(&mut line)? as u64;
+            if n == 0 {
+                hi = mid;
+                continue;
+            }
+            let mut fields = line.trim().splitn(2, ':');
+            let candidate = fields.next().unwrap_or_default();
+            match candidate.to_ascii_uppercase().as_str().cmp(hash.as_str()) {
+                Ordering::Equal => {
+                    let count = fields
+                        .next()
+                        .and_then(|x| x.trim().parse::<u64>().ok())
+                        .unwrap_or(1);
+                    return Ok(count >= self.min_count);
+                }
+                Ordering::Less => lo = start + n,
+                Ordering::Greater => hi = mid,
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// The Bloom filter of the breached passwords.
+pub struct BloomFilter {
+    hashes: u32,
+    bits: u64,
+    data: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// Load the Bloom filter from `path`.
+    pub fn load<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut data = fs::read(path.into())?;
+        if data.len() < BLOOM_HEADER_LEN || &data[..BLOOM_MAGIC.len()] != BLOOM_MAGIC {
+            return Err(invalid("not a Bloom filter file"));
+        }
+        let mut hashes = [0u8; 4];
+        hashes.copy_from_slice(&data[8..12]);
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(&data[12..20]);
+        let hashes = u32::from_le_bytes(hashes);
+        let bits = u64::from_le_bytes(bits);
+        data.drain(..BLOOM_HEADER_LEN);
+        if hashes == 0 || bits == 0 || (data.len() as u64) < bits.div_ceil(8) {
+            return Err(invalid("truncated Bloom filter file"));
+        }
+        Ok(Self { hashes, bits, data })
+    }
+
+    /// Returns the bit indexes of `password`.
+    fn indexes(hashes: u32, bits: u64, password: &str) -> impl Iterator<Item = u64> {
+        let digest = Sha1::digest(password.as_bytes());
+        let mut h1 = [0u8; 8];
+        h1.copy_from_slice(&digest[..8]);
+        let mut h2 = [0u8; 8];
+        h2.copy_from_slice(&digest[8..16]);
+        let (h1, h2) = (u64::from_le_bytes(h1), u64::from_le_bytes(h2));
+        (0..hashes as u64).map(move |j| h1.wrapping_add(j.wrapping_mul(h2)) % bits)
+    }
+
+    /// Returns true if `password` may be in the filter.
+    pub fn contains(&self, password: &str) -> bool {
+        Self::indexes(self.hashes, self.bits, password)
+            .all(|i| self.data[(i / 8) as usize] & (1 << (i % 8)) != 0)
+    }
+}
+
+/// The corpus of the breached passwords.
+pub enum BreachCorpus {
+    HashList(HashList),
+    BloomFilter(BloomFilter),
+}
+
+impl BreachCorpus {
+    /// Returns true if `password` is in the corpus, or error if unable to search it.
+    pub fn contains(&self, password: &str) -> io::Result<bool> {
+        match self {
+            BreachCorpus::HashList(list) => list.contains(password),
+            BreachCorpus::BloomFilter(filter) => Ok(filter.contains(password)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lamager-{}-{}", std::process::id(), name));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_sha1_hex() {
+        assert_eq!(
+            sha1_hex("password"),
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8"
+        );
+    }
+
+    #[test]
+    fn test_hash_list() {
+        let mut hashes: Vec<String> = ["password", "123456", "qwerty", "letmein", "dragon"]
+            .iter()
+            .map(|x| sha1_hex(x))
+            .collect();
+        hashes.sort();
+        let text = hashes
+            .iter()
+            .enumerate()
+            .fold(String::new(), |mut s, (i, x)| {
+                let _ = write!(s, "{}:{}\r\n", x, i + 1);
+                s
+            });
+        let path = temp_file("hibp.txt", text.as_bytes());
+        let list = HashList::open(&path, 1).unwrap();
+        for x in &["password", "123456", "qwerty", "letmein", "dragon"] {
+            assert!(list.contains(x).unwrap(), "{}", x);
+        }
+        assert!(!list.contains("Tr0ub4dor&3x").unwrap());
+        assert!(!list.contains("").unwrap());
+        let list = HashList::open(&path, 6).unwrap();
+        assert!(!list.contains("password").unwrap());
+        fs::remove_file(path).unwrap();
+        // The errors are reported instead of treating the password as safe.
+        assert!(BreachCorpus::HashList(list).contains("password").is_err());
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let (hashes, bits) = (7u32, 1024u64);
+        let mut data = Vec::new();
+        data.extend(BLOOM_MAGIC);
+        data.extend(&hashes.to_le_bytes());
+        data.extend(&bits.to_le_bytes());
+        let mut bytes = vec![0u8; (bits / 8) as usize];
+        for x in &["password", "123456"] {
+            for i in BloomFilter::indexes(hashes, bits, x) {
+                bytes[(i / 8) as usize] |= 1 << (i % 8);
+            }
+        }
+        data.extend(bytes);
+        let path = temp_file("bloom.bin", &data);
+        let filter = BloomFilter::load(&path).unwrap();
+        assert!(filter.contains("password"));
+        assert!(filter.contains("123456"));
+        assert!(!filter.contains("Tr0ub4dor&3x"));
+        fs::remove_file(&path).unwrap();
+        let path = temp_file("bloom.bad", b"LMBLOOM1");
+        assert!(BloomFilter::load(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
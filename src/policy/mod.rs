@@ -0,0 +1,343 @@
+use crate::config::{table_get_bool, table_get_int, table_get_string};
+use ldap3::SearchEntry;
+use rocket::config::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+
+pub mod breach;
+pub mod strength;
+
+use breach::{BloomFilter, BreachCorpus, HashList};
+
+const DEFAULT_MIN_LENGTH: i64 = 8;
+const DEFAULT_MAX_LENGTH: i64 = 128;
+const DEFAULT_MIN_CLASSES: i64 = 3;
+const DEFAULT_MAX_REPEATS: i64 = 3;
+const DEFAULT_MIN_SCORE: i64 = 2;
+/// The shortest part of the user info forbidden in the passwords.
+const MIN_USER_INFO_LEN: usize = 3;
+
+/// The most common passwords, always in the dictionary.
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "123456",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwerty",
+    "qwertyuiop",
+    "abc123",
+    "111111",
+    "000000",
+    "123123",
+    "654321",
+    "iloveyou",
+    "admin",
+    "administrator",
+    "welcome",
+    "letmein",
+    "monkey",
+    "dragon",
+    "master",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "superman",
+    "shadow",
+    "trustno1",
+    "passw0rd",
+    "changeme",
+    "secret",
+    "zaq12wsx",
+    "1qaz2wsx",
+    "qazwsx",
+    "asdfgh",
+    "zxcvbn",
+    "woaini",
+    "5201314",
+    "aini1314",
+    "root",
+    "login",
+    "default",
+];
+
+#[derive(Clone, Debug)]
+pub struct PasswordPolicyConfig {
+    /// The minimum length in characters.
+    pub min_length: usize,
+    /// The maximum length in characters.
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// The minimum classes of lowercase, uppercase, digit and symbol.
+    pub min_classes: usize,
+    /// The maximum consecutive identical characters, 0 for unlimited.
+    pub max_repeats: usize,
+    /// Forbid the passwords containing the uid, cn or mail of the user.
+    pub forbid_user_info: bool,
+    /// The path of the dictionary file, one word per line, extends the common passwords.
+    pub dictionary: String,
+    /// The minimum strength score from 0 to 4.
+    pub min_score: u8,
+    /// The path of the SHA-1 hash list of the breached passwords in the HIBP format.
+    pub breach_list: String,
+    /// The path of the prebuilt Bloom filter of the breached passwords.
+    pub breach_filter: String,
+    /// The hashes in `breach_list` seen less than this count are ignored.
+    pub breach_min_count: u64,
+}
+
+impl From<&BTreeMap<String, Value>> for PasswordPolicyConfig {
+    fn from(table: &BTreeMap<String, Value>) -> Self {
+        Self {
+            min_length: table_get_int(table, "min_length", DEFAULT_MIN_LENGTH) as usize,
+            max_length: table_get_int(table, "max_length", DEFAULT_MAX_LENGTH) as usize,
+            require_lowercase: table_get_bool(table, "require_lowercase", false),
+            require_uppercase: table_get_bool(table, "require_uppercase", false),
+            require_digit: table_get_bool(table, "require_digit", false),
+            require_symbol: table_get_bool(table, "require_symbol", false),
+            min_classes: table_get_int(table, "min_classes", DEFAULT_MIN_CLASSES) as usize,
+            max_repeats: table_get_int(table, "max_repeats", DEFAULT_MAX_REPEATS) as usize,
+            forbid_user_info: table_get_bool(table, "forbid_user_info", true),
+            dictionary: table_get_string(table, "dictionary", ""),
+            min_score: table_get_int(table, "min_score", DEFAULT_MIN_SCORE).clamp(0, 4) as u8,
+            breach_list: table_get_string(table, "breach_list", ""),
+            breach_filter: table_get_string(table, "breach_filter", ""),
+            breach_min_count: table_get_int(table, "breach_min_count", 1).max(1) as u64,
+        }
+    }
+}
+
+/// The user the password is checked for.
+#[derive(Clone, Debug, Default)]
+pub struct PasswordOwner<'a> {
+    pub uid: &'a str,
+    pub cn: &'a str,
+    pub mail: &'a str,
+}
+
+impl<'a> From<&'a SearchEntry> for PasswordOwner<'a> {
+    fn from(entry: &'a SearchEntry) -> Self {
+        let attr = |name: &str| {
+            entry
+                .attrs
+                .get(name)
+                .and_then(|x| x.first())
+                .map_or("", |x| x.as_str())
+        };
+        Self {
+            uid: attr("uid"),
+            cn: attr("cn"),
+            mail: attr("mail"),
+        }
+    }
+}
+
+/// The password policy, checks the passwords against the rules.
+pub struct PasswordPolicy {
+    pub cfg: PasswordPolicyConfig,
+    dictionary: HashSet<String>,
+    breaches: Vec<BreachCorpus>,
+}
+
+impl PasswordPolicy {
+    /// Construct a new password policy, loading the dictionary and the breach
+    /// corpora specified in `cfg`.
+    pub fn new(cfg: PasswordPolicyConfig) -> io::Result<Self> {
+        let mut dictionary: HashSet<String> = COMMON_PASSWORDS
+            .iter()
+            .map(|x| strength::normalize(x))
+            .collect();
+        if !cfg.dictionary.is_empty() {
+            let text = fs::read_to_string(&cfg.dictionary)?;
+            dictionary.extend(
+                text.lines()
+                    .map(|x| strength::normalize(x.trim()))
+                    .filter(|x| !x.is_empty()),
+            );
+        }
+        let mut breaches = Vec::new();
+        if !cfg.breach_list.is_empty() {
+            let list = HashList::open(&cfg.breach_list, cfg.breach_min_count)?;
+            breaches.push(BreachCorpus::HashList(list));
+        }
+        if !cfg.breach_filter.is_empty() {
+            let filter = BloomFilter::load(&cfg.breach_filter)?;
+            breaches.push(BreachCorpus::BloomFilter(filter));
+        }
+        Ok(Self {
+            cfg,
+            dictionary,
+            breaches,
+        })
+    }
+
+    /// Check `password` of `owner`, returns the messages of the violated rules.
+    pub fn check(&self, password: &str, owner: &PasswordOwner) -> Result<(), Vec<String>> {
+        let cfg = &self.cfg;
+        let mut errors = Vec::new();
+        let length = password.chars().count();
+        if length < cfg.min_length {
+            errors.push(format!("密码长度不能少于 {} 个字符", cfg.min_length));
+        }
+        if length > cfg.max_length {
+            errors.push(format!("密码长度不能多于 {} 个字符", cfg.max_length));
+        }
+        let lowercase = password.chars().any(|c| c.is_lowercase());
+        let uppercase = password.chars().any(|c| c.is_uppercase());
+        let digit = password.chars().any(|c| c.is_ascii_digit());
+        let symbol = password.chars().any(|c| !c.is_alphanumeric());
+        if cfg.require_lowercase && !lowercase {
+            errors.push("密码必须包含小写字母".to_string());
+        }
+        if cfg.require_uppercase && !uppercase {
+            errors.push("密码必须包含大写字母".to_string());
+        }
+        if cfg.require_digit && !digit {
+            errors.push("密码必须包含数字".to_string());
+        }
+        if cfg.require_symbol && !symbol {
+            errors.push("密码必须包含特殊符号".to_string());
+        }
+        let classes = [lowercase, uppercase, digit, symbol]
+            .iter()
+            .filter(|&&x| x)
+            .count();
+        if classes < cfg.min_classes {
+            errors.push(format!(
+                "密码必须包含小写字母、大写字母、数字和特殊符号中的至少 {} 种",
+                cfg.min_classes
+            ));
+        }
+        if cfg.max_repeats > 0 && max_repeats(password) > cfg.max_repeats {
+            errors.push(format!(
+                "密码不能包含超过 {} 个连续相同的字符",
+                cfg.max_repeats
+            ));
+        }
+        if cfg.forbid_user_info && contains_user_info(password, owner) {
+            errors.push("密码不能包含用户名称、真实姓名或电子邮箱".to_string());
+        }
+        if self.is_dictionary_word(password) {
+            errors.push("密码过于常见，请更换一个不易猜测的密码".to_string());
+        } else if strength::score(password, &self.dictionary) < cfg.min_score {
+            errors.push("密码强度不足，请使用更长或更不规则的密码".to_string());
+        }
+        for breach in &self.breaches {
+            match breach.contains(password) {
+                Ok(false) => continue,
+                Ok(true) => {
+                    errors.push("密码已出现在公开泄露的密码库中，请更换其他密码".to_string())
+                }
+                // The password can't be accepted without searching all of the corpora.
+                Err(err) => {
+                    println!("Failed to search the breached passwords: {}", err);
+                    errors.push("暂时无法验证密码是否已泄露，请稍后重试".to_string());
+                }
+            }
+            break;
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns true if `password` is a dictionary word, ignoring the leading
+    /// and trailing digits and symbols.
+    fn is_dictionary_word(&self, password: &str) -> bool {
+        let normalized = strength::normalize(password);
+        let trimmed = strength::normalize(password.trim_matches(|c: char| !c.is_alphabetic()));
+        self.dictionary.contains(&normalized) || self.dictionary.contains(&trimmed)
+    }
+}
+
+/// Returns the maximum consecutive identical characters in `password`.
+fn max_repeats(password: &str) -> usize {
+    let mut max = 0;
+    let mut count = 0;
+    let mut last = None;
+    for c in password.chars() {
+        if Some(c) == last {
+            count += 1;
+        } else {
+            count = 1;
+            last = Some(c);
+        }
+        max = max.max(count);
+    }
+    max
+}
+
+/// Returns true if `password` contains the uid, cn or mail of `owner`.
+fn contains_user_info(password: &str, owner: &PasswordOwner) -> bool {
+    let password = password.to_lowercase();
+    let local_part = owner.mail.split('@').next().unwrap_or_default();
+    [owner.uid, owner.cn, local_part]
+        .iter()
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| x.chars().count() >= MIN_USER_INFO_LEN)
+        .any(|x| password.contains(&x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new(PasswordPolicyConfig::from(&BTreeMap::new())).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = policy();
+        let owner = PasswordOwner {
+            uid: "A1024",
+            cn: "Alice",
+            mail: "alice@example.com",
+        };
+        assert!(policy.check("Tr0ub4dor&3x", &owner).is_ok());
+        assert_eq!(policy.check("abc", &owner).unwrap_err().len(), 3);
+        assert!(policy.check("Alice#2021x", &owner).is_err());
+        assert!(policy.check("Password1!", &owner).is_err());
+        assert!(policy.check("P@ssw0rd2021", &owner).is_err());
+        assert!(policy.check("Aaaaa1234!xyz", &owner).is_err());
+    }
+
+    #[test]
+    fn test_check_breach() {
+        let path = std::env::temp_dir().join(format!("lamager-{}-policy.txt", std::process::id()));
+        std::fs::write(&path, format!("{}:3\n", breach::sha1_hex("Tr0ub4dor&3x"))).unwrap();
+        let mut table = BTreeMap::new();
+        table.insert(
+            "breach_list".to_string(),
+            Value::from(path.to_string_lossy().as_ref()),
+        );
+        let policy = PasswordPolicy::new(PasswordPolicyConfig::from(&table)).unwrap();
+        let owner = PasswordOwner {
+            uid: "A1024",
+            cn: "Alice",
+            mail: "alice@example.com",
+        };
+        let errors = policy.check("Tr0ub4dor&3x", &owner).unwrap_err();
+        assert!(errors[0].contains("泄露"));
+        assert!(policy.check("Correct-Horse-Battery-42", &owner).is_ok());
+        std::fs::remove_file(&path).unwrap();
+        let errors = policy
+            .check("Correct-Horse-Battery-42", &owner)
+            .unwrap_err();
+        assert!(errors[0].contains("无法验证"));
+    }
+
+    #[test]
+    fn test_max_repeats() {
+        assert_eq!(max_repeats(""), 0);
+        assert_eq!(max_repeats("abc"), 1);
+        assert_eq!(max_repeats("abbbc"), 3);
+    }
+}
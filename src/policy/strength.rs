@@ -0,0 +1,183 @@
+//! A zxcvbn-style estimator of the password strength.
+//!
+//! The password is scanned once, a run of characters continuing a repeat, a
+//! sequence or a keyboard walk is charged by its length only, and the
+//! dictionary words are charged as a single guess among the dictionary. The
+//! total guesses are mapped to a score from 0 (too guessable) to 4 (very
+//! unguessable).
+
+use std::collections::HashSet;
+
+/// The rows of the keyboard for walks like `qwerty` and `asdf`.
+const KEYBOARD_ROWS: [&str; 4] = [
+    "`1234567890-=",
+    "qwertyuiop[]\\",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+];
+/// The shortest dictionary word matched inside the password.
+const MIN_WORD_LEN: usize = 4;
+
+/// Returns the lowercase `c` with the common leet substitutions reverted.
+pub fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '8' => 'b',
+        '(' => 'c',
+        '3' => 'e',
+        '6' | '9' => 'g',
+        '1' | '!' | '|' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' | '+' => 't',
+        '2' => 'z',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+/// Returns the password normalized for the dictionary lookups.
+pub fn normalize(password: &str) -> String {
+    password.chars().map(unleet).collect()
+}
+
+/// Returns the size of the character set used by `password`.
+fn charset_size(password: &str) -> f64 {
+    let mut size = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        size += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        size += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        size += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        size += 33;
+    }
+    if !password.is_ascii() {
+        size += 100;
+    }
+    size.max(10) as f64
+}
+
+/// Returns the position of `c` on the keyboard as (row, column).
+fn keyboard_position(c: char) -> Option<(usize, usize)> {
+    let c = c.to_ascii_lowercase();
+    KEYBOARD_ROWS
+        .iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.chars().position(|k| k == c).map(|col| (row, col)))
+}
+
+/// Returns true if `b` follows `a` by a repeat, a sequence or a keyboard walk.
+fn continues_pattern(a: char, b: char) -> bool {
+    let (la, lb) = (a.to_ascii_lowercase(), b.to_ascii_lowercase());
+    if la == lb {
+        return true;
+    }
+    let delta = lb as i64 - la as i64;
+    if (delta == 1 || delta == -1) && la.is_ascii_alphanumeric() && lb.is_ascii_alphanumeric() {
+        return true;
+    }
+    match (keyboard_position(a), keyboard_position(b)) {
+        (Some((ra, ca)), Some((rb, cb))) => ra == rb && (ca as i64 - cb as i64).abs() == 1,
+        _ => false,
+    }
+}
+
+/// Returns the spans `(start, end)` of the longest dictionary words in `chars`.
+fn dictionary_spans(chars: &[char], dictionary: &HashSet<String>) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let found = (i + MIN_WORD_LEN..=chars.len())
+            .rev()
+            .find(|&j| dictionary.contains(&chars[i..j].iter().collect::<String>()));
+        match found {
+            Some(j) => {
+                spans.push((i, j));
+                i = j;
+            }
+            None => i += 1,
+        }
+    }
+    spans
+}
+
+/// Returns the estimated log10 guesses to crack `password`.
+pub fn guesses_log10(password: &str, dictionary: &HashSet<String>) -> f64 {
+    let chars: Vec<char> = password.chars().collect();
+    let normalized: Vec<char> = chars.iter().map(|&c| unleet(c)).collect();
+    let char_bits = charset_size(password).log2();
+    let word_bits = (dictionary.len().max(2) as f64).log2();
+    let spans = dictionary_spans(&normalized, dictionary);
+    let mut bits = 0.0;
+    // The length of the current pattern run, charged log2 of it in total.
+    let mut run = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(&(_, end)) = spans.iter().find(|(start, _)| *start == i) {
+            bits += word_bits;
+            run = 0;
+            i = end;
+            continue;
+        }
+        if i > 0 && run > 0 && continues_pattern(chars[i - 1], chars[i]) {
+            bits += ((run + 1) as f64 / run as f64).log2();
+            run += 1;
+        } else {
+            bits += char_bits;
+            run = 1;
+        }
+        i += 1;
+    }
+    bits * 2f64.log10()
+}
+
+/// Returns the score of `password` from 0 to 4, like zxcvbn.
+pub fn score(password: &str, dictionary: &HashSet<String>) -> u8 {
+    let guesses = guesses_log10(password, dictionary);
+    if guesses < 3.0 {
+        0
+    } else if guesses < 6.0 {
+        1
+    } else if guesses < 8.0 {
+        2
+    } else if guesses < 10.0 {
+        3
+    } else {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary() -> HashSet<String> {
+        ["password", "dragon", "monkey"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_weak_passwords() {
+        let dict = dictionary();
+        assert_eq!(score("aaaaaaaa", &dict), 0);
+        assert_eq!(score("12345678", &dict), 0);
+        assert_eq!(score("qwertyui", &dict), 0);
+        assert!(score("P@ssw0rd", &dict) <= 1);
+    }
+
+    #[test]
+    fn test_strong_passwords() {
+        let dict = dictionary();
+        assert_eq!(score("x7#Kq9!vZ2mR", &dict), 4);
+        assert!(score("correct horse battery staple", &dict) >= 3);
+    }
+}
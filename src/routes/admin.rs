@@ -0,0 +1,570 @@
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::directory::{Directory, DirectoryBackend, DirectoryRef};
+use crate::error::Error;
+use crate::ldap::PWD_ACCOUNT_LOCKED_TIME;
+use crate::ldap::{ClusterStats, LdapConfig};
+use crate::mail::Mailer;
+use crate::models::{
+    is_policy_locked, AdminSession, ApiMessage, HelpdeskSession, Invite, InviteManager,
+    LockedAccount, LoginThrottle, NewInvite, PolicyLockedAccount, RegistrationApproval,
+    RegistrationManager, ReviewRegistration, RevokeInvite, RevokeSession, Role, SessionInfo,
+    SessionManager, UnlockRequest, UserListMeta, UserQuery, UserSummary,
+};
+use chrono::Utc;
+use rocket::request::{FlashMessage, LenientForm};
+use rocket::response::{Flash, Redirect};
+use rocket::{Route, State};
+use rocket_contrib::json::Json;
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize)]
+struct PageLink {
+    number: usize,
+    active: bool,
+}
+
+#[derive(Serialize)]
+struct UsersContext {
+    uid: String,
+    is_staff: bool,
+    is_admin: bool,
+    users: Vec<UserSummary>,
+    meta: UserListMeta,
+    page_links: Vec<PageLink>,
+    prev_page: Option<usize>,
+    next_page: Option<usize>,
+    next_order: &'static str,
+    error: Option<String>,
+}
+
+/// Returns the users match to `query`.
+fn query_users(
+    ldap: &mut dyn Directory,
+    query: &UserQuery,
+) -> ldap3::result::Result<(Vec<UserSummary>, UserListMeta)> {
+    ldap.search_users(query)
+}
+
+#[get("/admin/users?<query..>", format = "json")]
+pub(crate) fn users_json(
+    query: LenientForm<UserQuery>,
+    _session: HelpdeskSession,
+    mut ldap: DirectoryRef,
+) -> Json<ApiMessage<Vec<UserSummary>, String, UserListMeta>> {
+    match query_users(&mut *ldap, &query) {
+        Ok((users, meta)) => Json(ApiMessage {
+            data: Some(users),
+            errors: None,
+            meta: Some(meta),
+        }),
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to search users: {}", err);
+            Json(ApiMessage {
+                data: None,
+                errors: Some(err.message().to_string()),
+                meta: None,
+            })
+        }
+    }
+}
+
+#[get("/admin/users?<query..>", rank = 2)]
+pub(crate) fn users(
+    query: LenientForm<UserQuery>,
+    session: HelpdeskSession,
+    mut ldap: DirectoryRef,
+) -> Template {
+    let (users, meta, error) = match query_users(&mut *ldap, &query) {
+        Ok((users, meta)) => (users, meta, None),
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to search users: {}", err);
+            let (users, meta) = query.apply(Vec::new());
+            (users, meta, Some(err.message().to_string()))
+        }
+    };
+    let page_links = (1..=meta.pages)
+        .map(|number| PageLink {
+            number,
+            active: number == meta.page,
+        })
+        .collect();
+    let context = UsersContext {
+        uid: Clone::clone(&session.uid),
+        is_staff: true,
+        is_admin: session.has_role(&Role::ADMIN),
+        prev_page: Some(meta.page - 1).filter(|x| *x >= 1),
+        next_page: Some(meta.page + 1).filter(|x| *x <= meta.pages),
+        next_order: if query.desc() { "asc" } else { "desc" },
+        users,
+        meta,
+        page_links,
+        error,
+    };
+    Template::render("admin_users", &context)
+}
+
+#[get("/admin/users", rank = 3)]
+pub(crate) fn users_without_session() -> Redirect {
+    Redirect::to(uri!(crate::routes::login::login_page))
+}
+
+#[derive(Serialize)]
+struct LockoutsContext {
+    uid: String,
+    is_staff: bool,
+    is_admin: bool,
+    throttled: Vec<LockedAccount>,
+    policy_locked: Vec<PolicyLockedAccount>,
+    flash: Option<String>,
+    flash_success: bool,
+    error: Option<String>,
+    csrf_token: String,
+}
+
+#[get("/admin/lockouts")]
+pub(crate) fn lockouts(
+    session: AdminSession,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    throttle: State<LoginThrottle>,
+    mut ldap: DirectoryRef,
+) -> Template {
+    let duration = ldap.cfg().pwd_lockout_duration;
+    let now = Utc::now();
+    let (policy_locked, error) = match ldap.locked_users() {
+        Ok(entries) => (
+            entries
+                .into_iter()
+                .filter(|x| {
+                    x.attrs
+                        .get(PWD_ACCOUNT_LOCKED_TIME)
+                        .map_or(false, |v| is_policy_locked(v, duration, now))
+                })
+                .map(|x| PolicyLockedAccount {
+                    uid: x
+                        .attrs
+                        .get("uid")
+                        .and_then(|v| v.first())
+                        .cloned()
+                        .unwrap_or_default(),
+                    locked_time: x
+                        .attrs
+                        .get(PWD_ACCOUNT_LOCKED_TIME)
+                        .and_then(|v| v.first())
+                        .map(|v| format_generalized_time(v))
+                        .unwrap_or_default(),
+                    dn: x.dn,
+                })
+                .collect(),
+            None,
+        ),
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to search locked users: {}", err);
+            (Vec::new(), Some(err.message().to_string()))
+        }
+    };
+    let context = LockoutsContext {
+        uid: Clone::clone(&session.uid),
+        is_staff: true,
+        is_admin: true,
+        throttled: throttle.locked_accounts(),
+        policy_locked,
+        flash: flash.as_ref().map(|x| x.msg().to_string()),
+        flash_success: flash.as_ref().map_or(false, |x| x.name() == "success"),
+        error,
+        csrf_token: csrf.to_string(),
+    };
+    Template::render("admin_lockouts", &context)
+}
+
+#[get("/admin/lockouts", rank = 2)]
+pub(crate) fn lockouts_without_session() -> Redirect {
+    Redirect::to(uri!(crate::routes::login::login_page))
+}
+
+#[post("/admin/lockouts/unlock", data = "<unlock>")]
+pub(crate) fn unlock(
+    unlock: CsrfForm<UnlockRequest>,
+    _session: AdminSession,
+    throttle: State<LoginThrottle>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    if let Some(username) = &unlock.username {
+        throttle.unlock(username);
+    }
+    if let Some(dn) = &unlock.dn {
+        if let Err(err) = ldap.unlock_user(dn) {
+            let err = Error::from(err);
+            println!("Failed to unlock {}: {}", dn, err);
+            return Flash::error(Redirect::to(uri!(lockouts)), err.message());
+        }
+    }
+    Flash::success(Redirect::to(uri!(lockouts)), "账号已解除锁定！")
+}
+
+#[derive(Serialize)]
+struct SessionsContext {
+    uid: String,
+    is_staff: bool,
+    is_admin: bool,
+    user_uid: String,
+    user_dn: String,
+    sessions: Vec<SessionInfo>,
+    flash: Option<String>,
+    flash_success: bool,
+    csrf_token: String,
+}
+
+#[get("/admin/sessions?<uid>")]
+pub(crate) fn sessions(
+    uid: String,
+    session: AdminSession,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    session_manager: State<SessionManager>,
+    mut ldap: DirectoryRef,
+) -> Result<Template, Flash<Redirect>> {
+    let entry = ldap.entry_of_username(&uid).map_err(|_| {
+        Flash::error(
+            Redirect::to(uri!(users_without_session)),
+            "用户账号不存在！",
+        )
+    })?;
+    let context = SessionsContext {
+        uid: Clone::clone(&session.uid),
+        is_staff: true,
+        is_admin: true,
+        user_uid: entry
+            .attrs
+            .get("uid")
+            .and_then(|v| v.first())
+            .cloned()
+            .unwrap_or(uid),
+        sessions: session_manager
+            .sessions_of(&entry.dn)
+            .iter()
+            .map(|x| SessionInfo::new(x, &session.ssid))
+            .collect(),
+        user_dn: entry.dn,
+        flash: flash.as_ref().map(|x| x.msg().to_string()),
+        flash_success: flash.as_ref().map_or(false, |x| x.name() == "success"),
+        csrf_token: csrf.to_string(),
+    };
+    Ok(Template::render("admin_sessions", &context))
+}
+
+#[post("/admin/sessions/revoke?<uid>", data = "<revoke>")]
+pub(crate) fn sessions_revoke(
+    uid: String,
+    revoke: CsrfForm<RevokeSession>,
+    _session: AdminSession,
+    session_manager: State<SessionManager>,
+) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(sessions: uid = uid.as_str()));
+    let dn = match &revoke.dn {
+        Some(v) => v,
+        None => return Flash::error(redirect, "未指定用户账号！"),
+    };
+    match &revoke.id {
+        Some(id) if session_manager.revoke(dn, id) => Flash::success(redirect, "会话已注销！"),
+        Some(_) => Flash::error(redirect, "会话不存在或已过期！"),
+        None => {
+            let count = session_manager.revoke_all(dn, None);
+            Flash::success(redirect, format!("已注销 {} 个会话！", count))
+        }
+    }
+}
+
+/// Returns the LDAP generalized time `value` formatted in local time.
+fn format_generalized_time(value: &str) -> String {
+    // "000001010000Z" means the account is locked permanently.
+    if value.starts_with("00000101") {
+        return "永久锁定".to_string();
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S%.fZ")
+        .map(|x| {
+            chrono::DateTime::<chrono::Utc>::from_utc(x, chrono::Utc)
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| value.to_string())
+}
+
+#[derive(Serialize)]
+struct RegistrationsContext {
+    uid: String,
+    is_staff: bool,
+    is_admin: bool,
+    mode: &'static str,
+    registrations: Vec<RegistrationApproval>,
+    flash: Option<String>,
+    flash_success: bool,
+    csrf_token: String,
+}
+
+#[get("/admin/registrations")]
+pub(crate) fn registrations(
+    session: AdminSession,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    registration_manager: State<RegistrationManager>,
+) -> Template {
+    let context = RegistrationsContext {
+        uid: Clone::clone(&session.uid),
+        is_staff: true,
+        is_admin: true,
+        mode: registration_manager.mode.as_str(),
+        registrations: registration_manager.approvals(),
+        flash: flash.as_ref().map(|x| x.msg().to_string()),
+        flash_success: flash.as_ref().map_or(false, |x| x.name() == "success"),
+        csrf_token: csrf.to_string(),
+    };
+    Template::render("admin_registrations", &context)
+}
+
+#[get("/admin/registrations", rank = 2)]
+pub(crate) fn registrations_without_session() -> Redirect {
+    Redirect::to(uri!(crate::routes::login::login_page))
+}
+
+#[post("/admin/registrations/approve", data = "<review>")]
+pub(crate) fn registrations_approve(
+    review: CsrfForm<ReviewRegistration>,
+    _session: AdminSession,
+    registration_manager: State<RegistrationManager>,
+    mailer: State<Mailer>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    let approval = match registration_manager.take_approval(&review.id) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                Redirect::to(uri!(registrations)),
+                "注册申请不存在或已被处理！",
+            )
+        }
+    };
+    let registration = &approval.registration;
+    match crate::routes::register::is_taken(
+        &mut *ldap,
+        &[registration.uid.as_str(), registration.mail.as_str()],
+    ) {
+        Ok(false) => {}
+        Ok(true) => {
+            let msg = format!(
+                "用户名称或电子邮箱已被使用，请拒绝 {} 的注册申请！",
+                approval.uid
+            );
+            registration_manager.restore_approval(approval);
+            return Flash::error(Redirect::to(uri!(registrations)), msg);
+        }
+        Err(err) => {
+            println!("Failed to look up {}: {}", approval.uid, err);
+            let msg = format!("创建账号 {} 失败：{}", approval.uid, err.message());
+            registration_manager.restore_approval(approval);
+            return Flash::error(Redirect::to(uri!(registrations)), msg);
+        }
+    }
+    if let Err(err) = ldap.new_user(&approval.registration) {
+        let err = Error::from(err);
+        println!("Failed to create user {}: {}", approval.uid, err);
+        let msg = format!("创建账号 {} 失败：{}", approval.uid, err.message());
+        registration_manager.restore_approval(approval);
+        return Flash::error(Redirect::to(uri!(registrations)), msg);
+    }
+    let mut context = HashMap::new();
+    context.insert("uid", approval.uid.as_str());
+    if let Err(err) = mailer.send_template(&approval.mail, "register_approved", &context) {
+        println!("Failed to send approval mail to {}: {}", approval.mail, err);
+    }
+    Flash::success(
+        Redirect::to(uri!(registrations)),
+        format!("已批准 {} 的注册申请！", approval.uid),
+    )
+}
+
+#[post("/admin/registrations/reject", data = "<review>")]
+pub(crate) fn registrations_reject(
+    review: CsrfForm<ReviewRegistration>,
+    _session: AdminSession,
+    registration_manager: State<RegistrationManager>,
+    mailer: State<Mailer>,
+) -> Flash<Redirect> {
+    let reason = review
+        .reason
+        .as_ref()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty());
+    let reason = match reason {
+        Some(v) => v,
+        None => return Flash::error(Redirect::to(uri!(registrations)), "请填写拒绝的原因！"),
+    };
+    let approval = match registration_manager.take_approval(&review.id) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                Redirect::to(uri!(registrations)),
+                "注册申请不存在或已被处理！",
+            )
+        }
+    };
+    let mut context = HashMap::new();
+    context.insert("uid", approval.uid.as_str());
+    context.insert("reason", reason);
+    if let Err(err) = mailer.send_template(&approval.mail, "register_rejected", &context) {
+        println!(
+            "Failed to send rejection mail to {}: {}",
+            approval.mail, err
+        );
+        return Flash::error(
+            Redirect::to(uri!(registrations)),
+            format!("已拒绝 {} 的注册申请，但通知邮件发送失败！", approval.uid),
+        );
+    }
+    Flash::success(
+        Redirect::to(uri!(registrations)),
+        format!("已拒绝 {} 的注册申请！", approval.uid),
+    )
+}
+
+#[derive(Serialize)]
+struct InvitesContext {
+    uid: String,
+    is_staff: bool,
+    is_admin: bool,
+    base_url: String,
+    base_dn: String,
+    invites: Vec<Invite>,
+    flash: Option<String>,
+    flash_success: bool,
+    csrf_token: String,
+}
+
+#[get("/admin/invites")]
+pub(crate) fn invites(
+    session: AdminSession,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    invite_manager: State<InviteManager>,
+    ldap_cfg: State<LdapConfig>,
+    mailer: State<Mailer>,
+) -> Template {
+    let context = InvitesContext {
+        uid: Clone::clone(&session.uid),
+        is_staff: true,
+        is_admin: true,
+        base_url: Clone::clone(&mailer.cfg.base_url),
+        base_dn: Clone::clone(&ldap_cfg.base_dn),
+        invites: invite_manager.list(),
+        flash: flash.as_ref().map(|x| x.msg().to_string()),
+        flash_success: flash.as_ref().map_or(false, |x| x.name() == "success"),
+        csrf_token: csrf.to_string(),
+    };
+    Template::render("admin_invites", &context)
+}
+
+#[get("/admin/invites", rank = 2)]
+pub(crate) fn invites_without_session() -> Redirect {
+    Redirect::to(uri!(crate::routes::login::login_page))
+}
+
+#[post("/admin/invites/create", data = "<new>")]
+pub(crate) fn invites_create(
+    new: CsrfForm<NewInvite>,
+    session: AdminSession,
+    invite_manager: State<InviteManager>,
+    ldap_cfg: State<LdapConfig>,
+    mailer: State<Mailer>,
+) -> Flash<Redirect> {
+    let ou = new.ou.as_ref().map(|x| x.trim()).filter(|x| !x.is_empty());
+    if let Some(ou) = ou {
+        if !ldap_cfg.is_under_base_dn(ou) {
+            return Flash::error(
+                Redirect::to(uri!(invites)),
+                format!("目标组织单位必须位于 {} 之下！", ldap_cfg.base_dn),
+            );
+        }
+    }
+    let invite = match invite_manager.create(&new, &session.uid) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                Redirect::to(uri!(invites)),
+                "创建邀请失败，请稍后再次尝试！",
+            )
+        }
+    };
+    let link = format!(
+        "{}{}",
+        mailer.cfg.base_url,
+        uri!(crate::routes::register::register_invite: invite = invite.token.as_str())
+    );
+    if let Some(mail) = &invite.mail {
+        let mut context = HashMap::new();
+        context.insert("link", link.as_str());
+        context.insert("expires", invite.expires_at.as_str());
+        if let Err(err) = mailer.send_template(mail, "invite", &context) {
+            println!("Failed to send invite mail to {}: {}", mail, err);
+            return Flash::error(
+                Redirect::to(uri!(invites)),
+                format!("邀请已创建，但邀请邮件发送失败：{}", link),
+            );
+        }
+    }
+    Flash::success(Redirect::to(uri!(invites)), format!("邀请已创建：{}", link))
+}
+
+#[post("/admin/invites/revoke", data = "<revoke>")]
+pub(crate) fn invites_revoke(
+    revoke: CsrfForm<RevokeInvite>,
+    _session: AdminSession,
+    invite_manager: State<InviteManager>,
+) -> Flash<Redirect> {
+    if invite_manager.revoke(&revoke.token) {
+        Flash::success(Redirect::to(uri!(invites)), "邀请已撤销！")
+    } else {
+        Flash::error(Redirect::to(uri!(invites)), "邀请不存在或已失效！")
+    }
+}
+
+/// Returns the usage of the LDAP connection pools and the state of the servers,
+/// not found if the directory is not backed by LDAP.
+#[get("/admin/ldap/pool", format = "json")]
+pub(crate) fn ldap_pool(
+    _session: AdminSession,
+    backend: State<DirectoryBackend>,
+) -> Option<Json<ClusterStats>> {
+    match &*backend {
+        DirectoryBackend::Ldap(cluster) => Some(Json(cluster.stats())),
+        #[cfg(any(test, feature = "demo"))]
+        DirectoryBackend::Memory(_) => None,
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        users_json,
+        users,
+        users_without_session,
+        lockouts,
+        lockouts_without_session,
+        unlock,
+        sessions,
+        sessions_revoke,
+        registrations,
+        registrations_without_session,
+        registrations_approve,
+        registrations_reject,
+        invites,
+        invites_without_session,
+        invites_create,
+        invites_revoke,
+        ldap_pool
+    ]
+}
@@ -0,0 +1,40 @@
+use crate::csrf::is_csrf_rejected;
+use rocket::http::Status;
+use rocket::{Catcher, Request, Route};
+use rocket_contrib::templates::Template;
+use std::collections::HashMap;
+
+/// The target of the requests rejected by the `CsrfFairing`.
+#[get("/csrf/rejected")]
+pub(crate) fn csrf_rejected() -> Status {
+    Status::Forbidden
+}
+
+#[catch(403)]
+pub(crate) fn forbidden(request: &Request) -> Template {
+    let mut context = HashMap::new();
+    if is_csrf_rejected(request) {
+        context.insert("title", "请求已失效");
+        context.insert("message", "请求已过期或来源不可信，请刷新页面后重试！");
+    } else {
+        context.insert("title", "没有权限");
+        context.insert("message", "你没有权限访问该页面或执行该操作！");
+    }
+    Template::render("error", &context)
+}
+
+#[catch(503)]
+pub(crate) fn service_unavailable() -> Template {
+    let mut context = HashMap::new();
+    context.insert("title", "服务暂不可用");
+    context.insert("message", "目录服务繁忙或无法连接，请稍后重试！");
+    Template::render("error", &context)
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![csrf_rejected]
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![forbidden, service_unavailable]
+}
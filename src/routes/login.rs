@@ -1,7 +1,16 @@
-use crate::ldap::LdapAccessor;
-use crate::models::{Login, SessionManager, SessionRef, User};
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::directory::DirectoryRef;
+use crate::error::Error;
+use crate::ldap::ppolicy::PolicyError;
+use crate::ldap::PWD_ACCOUNT_LOCKED_TIME;
+use crate::models::{
+    is_policy_locked, verify_digest, ClientInfo, Login, LoginThrottle, NewPassword, PasswordExpiry,
+    PasswordExpiryManager, Role, SessionManager, SessionRef, Totp, TotpCode, TotpManager, User,
+};
+use crate::policy::{PasswordOwner, PasswordPolicy};
+use chrono::Utc;
 use rocket::http::{Cookie, Cookies};
-use rocket::request::{FlashMessage, Form};
+use rocket::request::FlashMessage;
 use rocket::response::{Flash, Redirect};
 use rocket::{Route, State};
 use rocket_contrib::templates::Template;
@@ -9,26 +18,103 @@ use std::collections::HashMap;
 
 #[post("/login", data = "<login>")]
 pub(crate) fn login(
-    login: Form<Login>,
+    login: CsrfForm<Login>,
+    client: ClientInfo,
     session_manager: State<SessionManager>,
+    totp_manager: State<TotpManager>,
+    expiry_manager: State<PasswordExpiryManager>,
+    throttle: State<LoginThrottle>,
     mut cookies: Cookies,
-    mut ldap: LdapAccessor,
+    mut ldap: DirectoryRef,
 ) -> Result<Redirect, Flash<Redirect>> {
-    if let Ok(entry) = ldap.entry_of_username(&login.username) {
+    let ip = client.ip_string();
+    let entry = ldap.entry_of_username(&login.username).ok();
+    // The failures are counted by the uid, so that the uid and mail share the limit.
+    let username = entry
+        .as_ref()
+        .and_then(|x| x.attrs.get("uid").and_then(|v| v.first()))
+        .map_or(login.username.as_str(), |x| x.as_str())
+        .to_string();
+    if let Err(err) = throttle.check(&username, &ip) {
+        return Err(Flash::error(Redirect::to(uri!(login_page)), err.message()));
+    }
+    if let Some(entry) = entry {
         let dn = &entry.dn;
-        let uid = &entry.attrs["uid"][0];
-        if ldap.verify_password(&dn, &login.password) {
-            let session = SessionRef::new(Clone::clone(dn), Clone::clone(uid));
-            cookies.add_private(Cookie::new("ssid", session.ssid.to_string()));
-            session_manager.add(session);
-            Ok(Redirect::to(uri!(crate::routes::index::index)))
-        } else {
-            Err(Flash::error(
+        let uid = &username;
+        let locked = entry.attrs.get(PWD_ACCOUNT_LOCKED_TIME).map_or(false, |x| {
+            is_policy_locked(x, ldap.cfg().pwd_lockout_duration, Utc::now())
+        });
+        if locked {
+            return Err(Flash::error(
                 Redirect::to(uri!(login_page)),
-                "用户名或密码有误，请重新输入！",
-            ))
+                "账号已被锁定，请与管理员联系！",
+            ));
+        }
+        match ldap.bind_user(dn, &login.password) {
+            Ok(response) => {
+                let now = Utc::now();
+                let mut expiry = PasswordExpiry::from_attrs(&entry.attrs, &expiry_manager.cfg);
+                if let Some(ref response) = response {
+                    expiry.apply_policy(response, now);
+                }
+                let must_change_password = expiry.is_expired(now);
+                let roles = ldap.roles_of(dn).unwrap_or_else(|_| vec![Role::USER]);
+                // Requires the second factor if two-factor authentication enabled.
+                if let Some(secret) = entry
+                    .attrs
+                    .get(&ldap.cfg().totp_attribute)
+                    .and_then(|x| x.first())
+                {
+                    let recovery_codes = entry
+                        .attrs
+                        .get(&ldap.cfg().totp_recovery_attribute)
+                        .cloned()
+                        .unwrap_or_default();
+                    let key = totp_manager.add_login(
+                        Clone::clone(dn),
+                        Clone::clone(uid),
+                        roles,
+                        Clone::clone(secret),
+                        recovery_codes,
+                        must_change_password,
+                    );
+                    cookies.add_private(session_manager.cookie("pending_login", key));
+                    return Ok(Redirect::to(uri!(login_totp_page)));
+                }
+                // The failures are cleared only after all factors verified.
+                throttle.record_success(&username);
+                if must_change_password {
+                    let key = expiry_manager.add(Clone::clone(dn), Clone::clone(uid), roles);
+                    cookies.add_private(session_manager.cookie("pending_password", key));
+                    return Ok(Redirect::to(uri!(login_password_page)));
+                }
+                let session = SessionRef::new(Clone::clone(dn), Clone::clone(uid), roles, &client);
+                session_manager.start(&mut cookies, session);
+                Ok(Redirect::to(uri!(crate::routes::index::index)))
+            }
+            Err(err) => {
+                let err = Error::from(err);
+                // The outages of the directory are not the failures of the user.
+                if let Error::Unavailable(_) = err {
+                    println!("Failed to bind as {}: {}", dn, err);
+                    return Err(Flash::error(
+                        Redirect::to(uri!(login_page)),
+                        format!("{}！", err.message()),
+                    ));
+                }
+                throttle.record_failure(&username, &ip);
+                let msg = match err {
+                    Error::Policy(PolicyError::AccountLocked) => "账号已被锁定，请与管理员联系！",
+                    Error::Policy(PolicyError::PasswordExpired) => {
+                        "密码已过期且宽限登录次数已用完，请通过找回密码重置！"
+                    }
+                    _ => "用户名或密码有误，请重新输入！",
+                };
+                Err(Flash::error(Redirect::to(uri!(login_page)), msg))
+            }
         }
     } else {
+        throttle.record_failure(&username, &ip);
         Err(Flash::error(
             Redirect::to(uri!(login_page)),
             "用户账号或邮箱不存在，请重新输入！",
@@ -42,8 +128,9 @@ pub(crate) fn login_user(_user: User) -> Redirect {
 }
 
 #[get("/login", rank = 2)]
-pub(crate) fn login_page(flash: Option<FlashMessage>) -> Template {
+pub(crate) fn login_page(flash: Option<FlashMessage>, csrf: CsrfToken) -> Template {
     let mut context = HashMap::new();
+    context.insert("csrf_token", csrf.as_str());
     if let Some(ref msg) = flash {
         context.insert("flash", msg.msg());
     }
@@ -51,6 +138,188 @@ pub(crate) fn login_page(flash: Option<FlashMessage>) -> Template {
     Template::render("login", &context)
 }
 
+#[post("/login/totp", data = "<totp>")]
+pub(crate) fn login_totp(
+    totp: CsrfForm<TotpCode>,
+    client: ClientInfo,
+    session_manager: State<SessionManager>,
+    totp_manager: State<TotpManager>,
+    expiry_manager: State<PasswordExpiryManager>,
+    throttle: State<LoginThrottle>,
+    mut cookies: Cookies,
+    mut ldap: DirectoryRef,
+) -> Result<Redirect, Flash<Redirect>> {
+    let ip = client.ip_string();
+    let key = cookies
+        .get_private("pending_login")
+        .map(|x| x.value().to_string())
+        .unwrap_or_default();
+    let pending = match totp_manager.attempt_login(&key) {
+        Some(v) => v,
+        None => {
+            cookies.remove_private(Cookie::named("pending_login"));
+            return Err(Flash::error(
+                Redirect::to(uri!(login_page)),
+                "验证已过期或尝试次数过多，请重新登录！",
+            ));
+        }
+    };
+    if let Err(err) = throttle.check(&pending.uid, &ip) {
+        return Err(Flash::error(
+            Redirect::to(uri!(login_totp_page)),
+            err.message(),
+        ));
+    }
+    let verified = Totp::from_base32(&pending.secret)
+        .and_then(|x| x.verify(&totp.code, Utc::now().timestamp()))
+        .map_or(false, |step| totp_manager.accept_step(&pending.dn, step));
+    if !verified {
+        // The recovery code can be used only once.
+        let recovery_code = pending
+            .recovery_codes
+            .iter()
+            .find(|x| verify_digest(totp.code.trim(), x));
+        let used = recovery_code.map_or(false, |digest| {
+            ldap.remove_recovery_code(&pending.dn, digest).is_ok()
+        });
+        if !used {
+            throttle.record_failure(&pending.uid, &ip);
+            return Err(Flash::error(
+                Redirect::to(uri!(login_totp_page)),
+                "验证码有误，请重新输入！",
+            ));
+        }
+    }
+    throttle.record_success(&pending.uid);
+    totp_manager.remove_login(&key);
+    cookies.remove_private(Cookie::named("pending_login"));
+    if pending.must_change_password {
+        let key = expiry_manager.add(pending.dn, pending.uid, pending.roles);
+        cookies.add_private(session_manager.cookie("pending_password", key));
+        return Ok(Redirect::to(uri!(login_password_page)));
+    }
+    let session = SessionRef::new(pending.dn, pending.uid, pending.roles, &client);
+    session_manager.start(&mut cookies, session);
+    Ok(Redirect::to(uri!(crate::routes::index::index)))
+}
+
+#[get("/login/totp")]
+pub(crate) fn login_totp_page(
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    totp_manager: State<TotpManager>,
+    mut cookies: Cookies,
+) -> Result<Template, Redirect> {
+    let pending = cookies
+        .get_private("pending_login")
+        .map_or(false, |x| totp_manager.has_login(x.value()));
+    if !pending {
+        return Err(Redirect::to(uri!(login_page)));
+    }
+    let mut context = HashMap::new();
+    context.insert("csrf_token", csrf.as_str());
+    if let Some(ref msg) = flash {
+        context.insert("flash", msg.msg());
+    }
+    Ok(Template::render("login_totp", &context))
+}
+
+#[post("/login/password", data = "<new_password>")]
+pub(crate) fn login_password(
+    new_password: CsrfForm<NewPassword>,
+    client: ClientInfo,
+    session_manager: State<SessionManager>,
+    expiry_manager: State<PasswordExpiryManager>,
+    policy: State<PasswordPolicy>,
+    mut cookies: Cookies,
+    mut ldap: DirectoryRef,
+) -> Result<Redirect, Flash<Redirect>> {
+    let key = cookies
+        .get_private("pending_password")
+        .map(|x| x.value().to_string())
+        .unwrap_or_default();
+    let pending = match expiry_manager.attempt(&key) {
+        Some(v) => v,
+        None => {
+            cookies.remove_private(Cookie::named("pending_password"));
+            return Err(Flash::error(
+                Redirect::to(uri!(login_page)),
+                "修改密码已过期或尝试次数过多，请重新登录！",
+            ));
+        }
+    };
+    if new_password.new_password != new_password.new_password_confirm {
+        return Err(Flash::error(
+            Redirect::to(uri!(login_password_page)),
+            "两次输入的新密码不一致！",
+        ));
+    }
+    if new_password.new_password == new_password.old_password {
+        return Err(Flash::error(
+            Redirect::to(uri!(login_password_page)),
+            "新密码不能与原密码相同！",
+        ));
+    }
+    let entry = ldap.entry_of_username(&pending.uid).ok();
+    let owner = match &entry {
+        Some(v) => PasswordOwner::from(v),
+        None => PasswordOwner {
+            uid: &pending.uid,
+            ..Default::default()
+        },
+    };
+    if let Err(errors) = policy.check(&new_password.new_password, &owner) {
+        return Err(Flash::error(
+            Redirect::to(uri!(login_password_page)),
+            errors.join("；"),
+        ));
+    }
+    if let Err(err) = ldap.update_password(&pending.dn, &new_password) {
+        let err = Error::from(err);
+        println!("Failed to change password of {}: {}", pending.uid, err);
+        return Err(Flash::error(
+            Redirect::to(uri!(login_password_page)),
+            format!("{}！", err.message()),
+        ));
+    }
+    expiry_manager.remove(&key);
+    cookies.remove_private(Cookie::named("pending_password"));
+    // The other logins may be stolen, log them out.
+    session_manager.revoke_all(&pending.dn, None);
+    let session = SessionRef::new(pending.dn, pending.uid, pending.roles, &client);
+    session_manager.start(&mut cookies, session);
+    Ok(Redirect::to(uri!(crate::routes::index::index)))
+}
+
+#[get("/login/password")]
+pub(crate) fn login_password_page(
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    expiry_manager: State<PasswordExpiryManager>,
+    mut cookies: Cookies,
+) -> Result<Template, Redirect> {
+    let pending = cookies
+        .get_private("pending_password")
+        .map_or(false, |x| expiry_manager.has(x.value()));
+    if !pending {
+        return Err(Redirect::to(uri!(login_page)));
+    }
+    let mut context = HashMap::new();
+    context.insert("csrf_token", csrf.as_str());
+    if let Some(ref msg) = flash {
+        context.insert("flash", msg.msg());
+    }
+    Ok(Template::render("login_password", &context))
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![login, login_user, login_page]
+    routes![
+        login,
+        login_user,
+        login_page,
+        login_totp,
+        login_totp_page,
+        login_password,
+        login_password_page
+    ]
 }
@@ -1,4 +1,4 @@
-use crate::models::{SessionManager, SessionRef};
+use crate::models::{SessionManager, SessionRef, SESSION_COOKIE};
 use rocket::http::{Cookie, Cookies};
 use rocket::response::{Flash, Redirect};
 use rocket::{Route, State};
@@ -9,8 +9,7 @@ pub(crate) fn logout(
     session_manager: State<SessionManager>,
     mut cookies: Cookies,
 ) -> Flash<Redirect> {
-    cookies.remove_private(Cookie::named("ssid"));
-    session_manager.remove(&session.ssid);
+    session_manager.end(&mut cookies, &session.ssid);
     Flash::success(
         Redirect::to(uri!(crate::routes::login::login_page)),
         "当前会话已注销，请重新登录！",
@@ -18,8 +17,12 @@ pub(crate) fn logout(
 }
 
 #[get("/logout", rank = 2)]
-pub(crate) fn logout_without_session(mut cookies: Cookies) -> Flash<Redirect> {
-    cookies.remove_private(Cookie::named("ssid"));
+pub(crate) fn logout_without_session(
+    session_manager: State<SessionManager>,
+    mut cookies: Cookies,
+) -> Flash<Redirect> {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+    session_manager.renew_csrf(&mut cookies);
     Flash::success(
         Redirect::to(uri!(crate::routes::login::login_page)),
         "请先登录再执行其他操作！",
@@ -1,3 +1,5 @@
+pub(crate) mod admin;
+pub(crate) mod error;
 pub(crate) mod index;
 pub(crate) mod login;
 pub(crate) mod logout;
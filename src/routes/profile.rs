@@ -1,23 +1,62 @@
-use crate::ldap::LdapAccessor;
-use crate::models::{ApiMessage, NewPassword, Person, SessionRef};
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::directory::DirectoryRef;
+use crate::error::Error;
+use crate::models::{
+    ApiMessage, ClientInfo, LoginThrottle, NewPassword, PasswordExpiry, PasswordExpiryManager,
+    Person, RevokeSession, Role, SessionInfo, SessionManager, SessionRef, Totp, TotpDisable,
+    TotpEnroll, TotpManager,
+};
+use crate::policy::{PasswordOwner, PasswordPolicy};
+use chrono::Utc;
 use image::imageops::FilterType as ImageFilterType;
 use image::io::Reader as ImageReader;
 use image::ImageOutputFormat;
-use rocket::http::ContentType;
-use rocket::request::Form;
-use rocket::response::status::BadRequest;
-use rocket::response::Redirect;
-use rocket::{Data, Route};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use rocket::http::{ContentType, Cookies};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{Data, Route, State};
 use rocket_contrib::json::Json;
 use rocket_contrib::templates::Template;
 use rocket_multipart_form_data::{
     mime, MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
+use serde::Serialize;
 use std::collections::HashMap;
 
+#[derive(Serialize)]
+struct ProfileContext {
+    #[serde(flatten)]
+    fields: HashMap<String, String>,
+    sessions: Vec<SessionInfo>,
+}
+
 #[get("/profile")]
-pub(crate) fn profile(session: SessionRef, mut ldap: LdapAccessor) -> Template {
+pub(crate) fn profile(
+    session: SessionRef,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    session_manager: State<SessionManager>,
+    expiry_manager: State<PasswordExpiryManager>,
+    mut ldap: DirectoryRef,
+) -> Template {
     let mut context: HashMap<String, String> = HashMap::new();
+    context.insert("csrf_token".to_string(), csrf.to_string());
+    if let Some(ref msg) = flash {
+        context.insert("flash".to_string(), msg.msg().to_string());
+        if msg.name() == "success" {
+            context.insert("flash_success".to_string(), "true".to_string());
+        }
+    }
+    if session.has_role(&Role::ADMIN) || session.has_role(&Role::HELPDESK) {
+        context.insert("is_staff".to_string(), "true".to_string());
+    }
+    if session.has_role(&Role::ADMIN) {
+        context.insert("is_admin".to_string(), "true".to_string());
+    }
+    let roles: Vec<&str> = session.roles.iter().map(|x| x.title()).collect();
+    context.insert("roles".to_string(), roles.join("，"));
     // Fetch user informations
     if let Ok(entry) = ldap.entry_of_username(&session.uid) {
         // let dn = &entry.dn;
@@ -39,9 +78,30 @@ pub(crate) fn profile(session: SessionRef, mut ldap: LdapAccessor) -> Template {
             context.insert("photo".to_string(), photo);
         }
         context.insert("createTimestamp".to_string(), format!("{}", create_date));
+        if entry.attrs.contains_key(&ldap.cfg().totp_attribute) {
+            context.insert("totp_enabled".to_string(), "true".to_string());
+        }
+        let expiry = PasswordExpiry::from_attrs(&entry.attrs, &expiry_manager.cfg);
+        if let Some(days) = expiry.days_left(Utc::now()) {
+            context.insert("password_days_left".to_string(), days.to_string());
+            if days <= expiry_manager.cfg.warning_days {
+                context.insert("password_expiring".to_string(), "true".to_string());
+            }
+        }
     }
+    let sessions = session_manager
+        .sessions_of(&session.dn)
+        .iter()
+        .map(|x| SessionInfo::new(x, &session.ssid))
+        .collect();
     // Render the page
-    Template::render("profile", &context)
+    Template::render(
+        "profile",
+        &ProfileContext {
+            fields: context,
+            sessions,
+        },
+    )
 }
 
 #[get("/profile", rank = 2)]
@@ -54,17 +114,17 @@ pub(crate) fn profile_avatar(
     content_type: &ContentType,
     data: Data,
     session: SessionRef,
-    mut ldap: LdapAccessor,
-) -> Result<(), BadRequest<String>> {
+    mut ldap: DirectoryRef,
+) -> Result<(), Error> {
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
         MultipartFormDataField::file("avatar_file")
             .content_type_by_string(Some(mime::IMAGE_JPEG))
-            .map_err(|err| BadRequest(Some(err.to_string())))?,
+            .map_err(|err| Error::InvalidUpload(err.to_string()))?,
         MultipartFormDataField::text("avatar_file_name"),
     ]);
 
     let multipart_form_data = MultipartFormData::parse(content_type, data, options)
-        .map_err(|err| BadRequest(Some(err.to_string())))?;
+        .map_err(|err| Error::InvalidUpload(err.to_string()))?;
 
     if let Some(files) = multipart_form_data.files.get("avatar_file") {
         let file = &files[0];
@@ -73,56 +133,256 @@ pub(crate) fn profile_avatar(
         let path = &file.path;
 
         let image = ImageReader::open(path)
-            .map_err(|err| BadRequest(Some(err.to_string())))?
+            .map_err(|err| Error::InvalidUpload(err.to_string()))?
             .with_guessed_format()
-            .map_err(|err| BadRequest(Some(err.to_string())))?
+            .map_err(|err| Error::InvalidUpload(err.to_string()))?
             .decode()
-            .map_err(|err| BadRequest(Some(err.to_string())))?;
+            .map_err(|err| Error::InvalidUpload(err.to_string()))?;
 
         let scaled = image.resize_to_fill(512, 512, ImageFilterType::Triangle);
         let mut buffer = Vec::new();
         scaled
             .write_to(&mut buffer, ImageOutputFormat::Jpeg(80))
-            .map_err(|err| BadRequest(Some(err.to_string())))?;
+            .map_err(|err| Error::InvalidUpload(err.to_string()))?;
 
-        ldap.update_photo(&session.dn, &buffer)
-            .map_err(|err| BadRequest(Some(err.to_string())))?;
+        ldap.update_photo(&session.dn, &buffer)?;
     }
 
     Ok(())
 }
 
+/// Verify the password of the user of `session`, the failures are throttled as
+/// the logins, so a stolen session can't be used to guess the password.
+fn verify_password(
+    ldap: &mut DirectoryRef,
+    throttle: &LoginThrottle,
+    session: &SessionRef,
+    client: &ClientInfo,
+    password: &str,
+) -> Result<(), String> {
+    let ip = client.ip_string();
+    throttle
+        .check(&session.uid, &ip)
+        .map_err(|err| err.message())?;
+    if !ldap.verify_password(&session.dn, password) {
+        throttle.record_failure(&session.uid, &ip);
+        return Err("账号密码有误，请重新输入！".to_string());
+    }
+    throttle.record_success(&session.uid);
+    Ok(())
+}
+
 #[post("/profile/password", data = "<new_password>")]
 pub(crate) fn profile_password(
-    new_password: Form<NewPassword>,
+    new_password: CsrfForm<NewPassword>,
     session: SessionRef,
-    mut ldap: LdapAccessor,
-) -> Json<ApiMessage<String, String, ()>> {
-    match ldap.update_password(&session.dn, &new_password.into_inner()) {
-        Ok(_) => Json(ApiMessage {
-            data: Some("okay".to_string()),
-            errors: None,
+    session_manager: State<SessionManager>,
+    policy: State<PasswordPolicy>,
+    throttle: State<LoginThrottle>,
+    client: ClientInfo,
+    mut cookies: Cookies,
+    mut ldap: DirectoryRef,
+) -> Json<ApiMessage<String, Vec<String>, ()>> {
+    let new_password = new_password.into_inner();
+    let ip = client.ip_string();
+    if let Err(err) = throttle.check(&session.uid, &ip) {
+        return Json(ApiMessage {
+            data: None,
+            errors: Some(vec![err.message()]),
+            meta: None,
+        });
+    }
+    if new_password.new_password != new_password.new_password_confirm {
+        return Json(ApiMessage {
+            data: None,
+            errors: Some(vec!["两次输入的新密码必须相同".to_string()]),
             meta: None,
-        }),
-        Err(err) => Json(ApiMessage {
+        });
+    }
+    let entry = ldap.entry_of_username(&session.uid).ok();
+    let owner = match &entry {
+        Some(v) => PasswordOwner::from(v),
+        None => PasswordOwner {
+            uid: &session.uid,
+            ..Default::default()
+        },
+    };
+    if let Err(errors) = policy.check(&new_password.new_password, &owner) {
+        return Json(ApiMessage {
             data: None,
-            errors: Some(err.to_string()),
+            errors: Some(errors),
             meta: None,
-        }),
+        });
+    }
+    match ldap.update_password(&session.dn, &new_password) {
+        Ok(_) => {
+            throttle.record_success(&session.uid);
+            // The other logins may be stolen, log them out.
+            session_manager.revoke_all(&session.dn, Some(&session.ssid));
+            session_manager.rotate(&mut cookies, &session);
+            Json(ApiMessage {
+                data: Some("okay".to_string()),
+                errors: None,
+                meta: None,
+            })
+        }
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to change password of {}: {}", session.uid, err);
+            // The old password is verified by the directory.
+            if let Error::InvalidCredentials = err {
+                throttle.record_failure(&session.uid, &ip);
+            }
+            Json(ApiMessage {
+                data: None,
+                errors: Some(vec![err.message().to_string()]),
+                meta: None,
+            })
+        }
     }
 }
 
 #[post("/profile/person", data = "<person>")]
 pub(crate) fn profile_person(
-    person: Form<Person>,
+    person: CsrfForm<Person>,
     session: SessionRef,
-    mut ldap: LdapAccessor,
-) -> Result<(), BadRequest<String>> {
-    ldap.update_person(&session.dn, &person.into_inner())
-        .map_err(|err| BadRequest(Some(err.to_string())))?;
+    mut ldap: DirectoryRef,
+) -> Result<(), Error> {
+    ldap.update_person(&session.dn, &person.into_inner())?;
     Ok(())
 }
 
+#[get("/profile/totp")]
+pub(crate) fn profile_totp_page(
+    session: SessionRef,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    totp_manager: State<TotpManager>,
+) -> Template {
+    let enrollment = totp_manager.enrollment(&session.ssid);
+    let mut context: HashMap<&str, String> = HashMap::new();
+    context.insert("csrf_token", csrf.to_string());
+    if let Some(totp) = Totp::from_base32(&enrollment.secret) {
+        let uri = totp.provisioning_uri(&totp_manager.issuer, &session.uid);
+        if let Ok(code) = QrCode::new(uri.as_bytes()) {
+            let image = code.render::<svg::Color>().min_dimensions(200, 200).build();
+            context.insert("qrcode", image);
+        }
+    }
+    context.insert("secret", enrollment.secret);
+    context.insert("recovery_codes", enrollment.recovery_codes.join("\n"));
+    if let Some(ref msg) = flash {
+        context.insert("flash", msg.msg().to_string());
+    }
+    Template::render("totp", &context)
+}
+
+#[post("/profile/totp", data = "<totp>")]
+pub(crate) fn profile_totp(
+    totp: CsrfForm<TotpEnroll>,
+    session: SessionRef,
+    session_manager: State<SessionManager>,
+    totp_manager: State<TotpManager>,
+    throttle: State<LoginThrottle>,
+    client: ClientInfo,
+    mut cookies: Cookies,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    if let Err(msg) = verify_password(&mut ldap, &throttle, &session, &client, &totp.password) {
+        return Flash::error(Redirect::to(uri!(profile_totp_page)), msg);
+    }
+    // The enrolled secret can't be replaced without disabling it by the password.
+    let totp_attribute = Clone::clone(&ldap.cfg().totp_attribute);
+    let enrolled = ldap
+        .entry_of_username(&session.uid)
+        .map_or(true, |x| x.attrs.contains_key(&totp_attribute));
+    if enrolled {
+        return Flash::error(
+            Redirect::to(uri!(profile)),
+            "两步验证已启用，请先停用后重新启用！",
+        );
+    }
+    let enrollment = totp_manager.enrollment(&session.ssid);
+    let verified = Totp::from_base32(&enrollment.secret)
+        .and_then(|x| x.verify(&totp.code, Utc::now().timestamp()))
+        .map_or(false, |step| totp_manager.accept_step(&session.dn, step));
+    if !verified {
+        return Flash::error(
+            Redirect::to(uri!(profile_totp_page)),
+            "验证码有误，请确认设备时间准确后重新输入！",
+        );
+    }
+    match ldap.update_totp(
+        &session.dn,
+        Some(&enrollment.secret),
+        &enrollment.recovery_codes,
+    ) {
+        Ok(_) => {
+            totp_manager.take_enrollment(&session.ssid);
+            session_manager.rotate(&mut cookies, &session);
+            Flash::success(Redirect::to(uri!(profile)), "两步验证已启用！")
+        }
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to enable TOTP of {}: {}", session.uid, err);
+            Flash::error(
+                Redirect::to(uri!(profile_totp_page)),
+                format!("启用两步验证失败：{}", err.message()),
+            )
+        }
+    }
+}
+
+#[post("/profile/totp/disable", data = "<disable>")]
+pub(crate) fn profile_totp_disable(
+    disable: CsrfForm<TotpDisable>,
+    session: SessionRef,
+    session_manager: State<SessionManager>,
+    throttle: State<LoginThrottle>,
+    client: ClientInfo,
+    mut cookies: Cookies,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    if let Err(msg) = verify_password(&mut ldap, &throttle, &session, &client, &disable.password) {
+        return Flash::error(Redirect::to(uri!(profile)), msg);
+    }
+    match ldap.update_totp(&session.dn, None, &[]) {
+        Ok(_) => {
+            session_manager.rotate(&mut cookies, &session);
+            Flash::success(Redirect::to(uri!(profile)), "两步验证已停用！")
+        }
+        Err(err) => {
+            let err = Error::from(err);
+            println!("Failed to disable TOTP of {}: {}", session.uid, err);
+            Flash::error(
+                Redirect::to(uri!(profile)),
+                format!("停用两步验证失败：{}", err.message()),
+            )
+        }
+    }
+}
+
+#[post("/profile/sessions/revoke", data = "<revoke>")]
+pub(crate) fn profile_sessions_revoke(
+    revoke: CsrfForm<RevokeSession>,
+    session: SessionRef,
+    session_manager: State<SessionManager>,
+) -> Flash<Redirect> {
+    match &revoke.id {
+        Some(id) if session_manager.revoke(&session.dn, id) => {
+            Flash::success(Redirect::to(uri!(profile)), "会话已注销！")
+        }
+        Some(_) => Flash::error(Redirect::to(uri!(profile)), "会话不存在或已过期！"),
+        None => {
+            let count = session_manager.revoke_all(&session.dn, Some(&session.ssid));
+            Flash::success(
+                Redirect::to(uri!(profile)),
+                format!("已注销其他 {} 个会话！", count),
+            )
+        }
+    }
+}
+
 pub fn routes() -> Vec<Route> {
     routes![
         profile,
@@ -130,5 +390,9 @@ pub fn routes() -> Vec<Route> {
         profile_avatar,
         profile_password,
         profile_person,
+        profile_totp_page,
+        profile_totp,
+        profile_totp_disable,
+        profile_sessions_revoke,
     ]
 }
@@ -1,13 +1,152 @@
-use rocket::Route;
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::directory::DirectoryRef;
+use crate::error::Error;
+use crate::mail::Mailer;
+use crate::models::{RecoverManager, RecoverRequest, ResetPassword, SessionManager};
+use crate::policy::{PasswordOwner, PasswordPolicy};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
+use rocket::{Route, State};
 use rocket_contrib::templates::Template;
 use std::collections::HashMap;
 
+#[post("/recover", data = "<request>")]
+pub(crate) fn recover(
+    request: CsrfForm<RecoverRequest>,
+    recover_manager: State<RecoverManager>,
+    mailer: State<Mailer>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    if let Ok(entry) = ldap.entry_of_username(&request.username) {
+        let uid = entry
+            .attrs
+            .get("uid")
+            .and_then(|v| v.first())
+            .unwrap_or(&request.username);
+        if let Some(mail) = entry.attrs.get("mail").and_then(|x| x.first()) {
+            let token = match recover_manager.issue(Clone::clone(&entry.dn), Clone::clone(uid)) {
+                Some(v) => v,
+                None => {
+                    return Flash::error(
+                        Redirect::to(uri!(recover_page)),
+                        "申请重置密码失败，请稍后再次尝试或与管理员联系！",
+                    )
+                }
+            };
+            let link = uri!(recover_token_page: token = token).to_string();
+            let ttl = recover_manager.ttl().num_minutes().to_string();
+            let mut context = HashMap::new();
+            context.insert("uid", uid.as_str());
+            context.insert("link", link.as_str());
+            context.insert("ttl", ttl.as_str());
+            if let Err(err) = mailer.send_template(mail, "recover", &context) {
+                println!("Failed to send recover mail to {}: {}", mail, err);
+                return Flash::error(
+                    Redirect::to(uri!(recover_page)),
+                    "邮件发送失败，请稍后再次尝试或与管理员联系！",
+                );
+            }
+        }
+    }
+    // Do not disclose whether the account exists.
+    Flash::success(
+        Redirect::to(uri!(recover_page)),
+        "如果该账号存在，重置密码的链接已发送到其电子邮箱，请查收！",
+    )
+}
+
 #[get("/recover")]
-pub(crate) fn recover() -> Template {
-    let context: HashMap<String, String> = HashMap::new();
+pub(crate) fn recover_page(flash: Option<FlashMessage>, csrf: CsrfToken) -> Template {
+    let mut context = HashMap::new();
+    context.insert("csrf_token", csrf.as_str());
+    if let Some(ref msg) = flash {
+        context.insert("flash", msg.msg());
+        if msg.name() == "success" {
+            context.insert("flash_success", "true");
+        }
+    }
     Template::render("recover", &context)
 }
 
+#[post("/recover/<token>", data = "<reset>")]
+pub(crate) fn recover_token(
+    token: String,
+    reset: CsrfForm<ResetPassword>,
+    recover_manager: State<RecoverManager>,
+    session_manager: State<SessionManager>,
+    policy: State<PasswordPolicy>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    if reset.new_password != reset.new_password_confirm {
+        return Flash::error(
+            Redirect::to(uri!(recover_token_page: token = token)),
+            "两次输入的账号密码必须相同！",
+        );
+    }
+    let ticket = match recover_manager.peek(&token) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                Redirect::to(uri!(recover_page)),
+                "重置密码的链接无效或已过期，请重新申请！",
+            )
+        }
+    };
+    let entry = ldap.entry_of_username(&ticket.uid).ok();
+    let owner = match &entry {
+        Some(v) => PasswordOwner::from(v),
+        None => PasswordOwner {
+            uid: &ticket.uid,
+            ..Default::default()
+        },
+    };
+    if let Err(errors) = policy.check(&reset.new_password, &owner) {
+        return Flash::error(
+            Redirect::to(uri!(recover_token_page: token = token)),
+            errors.join("；"),
+        );
+    }
+    if let Err(err) = ldap.reset_password(&ticket.dn, &reset.into_inner()) {
+        // The ticket is kept to try again.
+        let err = Error::from(err);
+        println!("Failed to reset password of {}: {}", ticket.uid, err);
+        return Flash::error(
+            Redirect::to(uri!(recover_token_page: token = token)),
+            format!("重置密码失败：{}！", err.message()),
+        );
+    }
+    // The link can be used only once.
+    recover_manager.consume(&token);
+    session_manager.revoke_all(&ticket.dn, None);
+    Flash::success(
+        Redirect::to(uri!(crate::routes::login::login_page)),
+        "账号密码已重置，请使用新的密码登录！",
+    )
+}
+
+#[get("/recover/<token>")]
+pub(crate) fn recover_token_page(
+    token: String,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    recover_manager: State<RecoverManager>,
+) -> Result<Template, Flash<Redirect>> {
+    let ticket = recover_manager.peek(&token).ok_or_else(|| {
+        Flash::error(
+            Redirect::to(uri!(recover_page)),
+            "重置密码的链接无效或已过期，请重新申请！",
+        )
+    })?;
+    let mut context = HashMap::new();
+    context.insert("uid", ticket.uid.as_str());
+    context.insert("token", token.as_str());
+    context.insert("csrf_token", csrf.as_str());
+    if let Some(ref msg) = flash {
+        context.insert("flash", msg.msg());
+    }
+    Ok(Template::render("recover_reset", &context))
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![recover]
+    routes![recover, recover_page, recover_token, recover_token_page]
 }
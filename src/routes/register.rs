@@ -1,36 +1,313 @@
-use crate::ldap::LdapAccessor;
-use crate::models::NewUser;
-use rocket::request::{FlashMessage, Form};
+use crate::csrf::{CsrfForm, CsrfToken};
+use crate::directory::{Directory, DirectoryRef};
+use crate::error::Error;
+use crate::mail::Mailer;
+use crate::models::{
+    ConfirmRegistration, Invite, InviteManager, NewUser, PendingRegistration, RegistrationManager,
+    RegistrationMode,
+};
+use crate::policy::{PasswordOwner, PasswordPolicy};
+use rocket::request::FlashMessage;
 use rocket::response::{Flash, Redirect};
-use rocket::Route;
+use rocket::{Route, State};
 use rocket_contrib::templates::Template;
 use std::collections::HashMap;
 
+/// Returns the redirect back to the register page, keeping the `invite` if any.
+fn back_to_register(invite: Option<&str>) -> Redirect {
+    match invite {
+        Some(v) => Redirect::to(uri!(register_invite: invite = v)),
+        None => Redirect::to(uri!(register_empty)),
+    }
+}
+
+/// Returns true if any of `usernames` is the uid or mail of an existing user.
+pub(crate) fn is_taken(ldap: &mut dyn Directory, usernames: &[&str]) -> Result<bool, Error> {
+    for username in usernames {
+        match ldap.entry_of_username(username).map_err(Error::from) {
+            Ok(_) => return Ok(true),
+            Err(Error::NoSuchObject) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(false)
+}
+
 #[post("/register", data = "<user>")]
-pub(crate) fn register(user: Form<NewUser>, mut ldap: LdapAccessor) -> Flash<Redirect> {
+pub(crate) fn register(
+    user: CsrfForm<NewUser>,
+    registration_manager: State<RegistrationManager>,
+    invite_manager: State<InviteManager>,
+    policy: State<PasswordPolicy>,
+    mailer: State<Mailer>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
     let user = user.into_inner();
-    match ldap.new_user(&user) {
-        Ok(_) => Flash::success(
-            Redirect::to(uri!(crate::routes::index::index)),
-            "注册账号成功，请登录核实或执行其他操作！",
-        ),
-        Err(_) => Flash::error(
+    let invite_token = user
+        .invite
+        .as_ref()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty());
+    match registration_manager.mode {
+        RegistrationMode::Closed => {
+            return Flash::error(Redirect::to(uri!(register_empty)), "当前未开放注册！")
+        }
+        RegistrationMode::InviteOnly if invite_token.is_none() => {
+            return Flash::error(Redirect::to(uri!(register_empty)), "当前仅限受邀用户注册！")
+        }
+        _ => {}
+    }
+    if user.password != user.password_confirm {
+        return Flash::error(
+            back_to_register(invite_token),
+            "两次输入的账号密码必须相同！",
+        );
+    }
+    let owner = PasswordOwner {
+        uid: &user.uid,
+        cn: &user.cn,
+        mail: &user.mail,
+    };
+    if let Err(errors) = policy.check(&user.password, &owner) {
+        return Flash::error(back_to_register(invite_token), errors.join("；"));
+    }
+    // The pending registrations don't hold the uid or mail, the later one of
+    // the same account replaces the earlier, and the uniqueness is checked
+    // again when the account created.
+    let taken = match is_taken(&mut *ldap, &[user.uid.as_str(), user.mail.as_str()]) {
+        Ok(v) => v,
+        Err(err) => {
+            println!("Failed to look up {}: {}", user.uid, err);
+            return Flash::error(
+                back_to_register(invite_token),
+                format!("{}！", err.message()),
+            );
+        }
+    };
+    if taken {
+        return Flash::error(
+            back_to_register(invite_token),
+            "用户名称或电子邮箱已被使用，请更换后再次尝试！",
+        );
+    }
+    let mut registration = PendingRegistration::new(&user, ldap.cfg().password_scheme);
+    if let Some(token) = invite_token {
+        // The invite is used when the account created, see `register_verify`.
+        match invite_manager.peek(token).filter(|x| x.accepts(&user.mail)) {
+            Some(invite) => registration = registration.with_invite(&invite),
+            None => {
+                return Flash::error(
+                    back_to_register(invite_token),
+                    "邀请链接无效、已过期或不适用于该电子邮箱！",
+                )
+            }
+        }
+    }
+    let token = match registration_manager.issue(registration) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                back_to_register(invite_token),
+                "注册账号失败，请稍后再次尝试或与管理员联系！",
+            )
+        }
+    };
+    let link = uri!(register_verify: token = token).to_string();
+    let ttl = registration_manager.ttl().num_minutes().to_string();
+    let mut context = HashMap::new();
+    context.insert("uid", user.uid.as_str());
+    context.insert("link", link.as_str());
+    context.insert("ttl", ttl.as_str());
+    if let Err(err) = mailer.send_template(&user.mail, "register", &context) {
+        println!("Failed to send register mail to {}: {}", user.mail, err);
+        return Flash::error(
             Redirect::to(uri!(register_empty)),
-            "注册账号失败，请仔细检查各项内容后再次尝试！",
-        ),
+            "邮件发送失败，请稍后再次尝试或与管理员联系！",
+        );
     }
+    Flash::success(
+        Redirect::to(uri!(register_empty)),
+        format!(
+            "确认邮件已发送到 {}，请在 {} 分钟内点击邮件中的链接完成注册！",
+            user.mail, ttl
+        ),
+    )
 }
 
-#[get("/register")]
-pub(crate) fn register_empty(flash: Option<FlashMessage>) -> Template {
-    println!("flash={:?}", flash);
+/// Render the register page, with the `invite` if any.
+fn render_register(
+    flash: Option<FlashMessage>,
+    csrf: &CsrfToken,
+    mode: RegistrationMode,
+    invite: Option<&Invite>,
+) -> Template {
     let mut context = HashMap::new();
+    context.insert("csrf_token", csrf.as_str());
+    match (mode, invite) {
+        (RegistrationMode::Closed, _) => {
+            context.insert("closed", "当前未开放注册！");
+        }
+        (_, Some(invite)) => {
+            context.insert("invite", invite.token.as_str());
+            if let Some(mail) = &invite.mail {
+                context.insert("invite_mail", mail.as_str());
+            }
+        }
+        (RegistrationMode::Open, None) => {}
+        (RegistrationMode::Approval, None) => {
+            context.insert("notice", "注册申请需经管理员审核通过后方可使用。");
+        }
+        (RegistrationMode::InviteOnly, None) => {
+            context.insert("closed", "当前仅限受邀用户注册！");
+        }
+    }
     if let Some(ref msg) = flash {
         context.insert("flash", msg.msg());
+        if msg.name() == "success" {
+            context.insert("flash_success", "true");
+        }
     }
     Template::render("register", &context)
 }
 
+#[get("/register")]
+pub(crate) fn register_empty(
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    registration_manager: State<RegistrationManager>,
+) -> Template {
+    render_register(flash, &csrf, registration_manager.mode, None)
+}
+
+#[get("/register?<invite>")]
+pub(crate) fn register_invite(
+    invite: String,
+    flash: Option<FlashMessage>,
+    csrf: CsrfToken,
+    registration_manager: State<RegistrationManager>,
+    invite_manager: State<InviteManager>,
+) -> Result<Template, Flash<Redirect>> {
+    match invite_manager.peek(&invite) {
+        Some(v) => Ok(render_register(
+            flash,
+            &csrf,
+            registration_manager.mode,
+            Some(&v),
+        )),
+        None => Err(Flash::error(
+            Redirect::to(uri!(register_empty)),
+            "邀请链接无效或已过期，请与管理员联系！",
+        )),
+    }
+}
+
+#[get("/register/verify/<token>")]
+pub(crate) fn register_verify(
+    token: String,
+    csrf: CsrfToken,
+    registration_manager: State<RegistrationManager>,
+) -> Result<Template, Flash<Redirect>> {
+    // Only shows the registration, the mail scanners following the link
+    // must not confirm it.
+    let registration = registration_manager.peek(&token).ok_or_else(|| {
+        Flash::error(
+            Redirect::to(uri!(register_empty)),
+            "注册确认链接无效或已过期，请重新注册！",
+        )
+    })?;
+    let mut context = HashMap::new();
+    context.insert("uid", registration.uid.as_str());
+    context.insert("cn", registration.cn.as_str());
+    context.insert("mail", registration.mail.as_str());
+    context.insert("token", token.as_str());
+    context.insert("csrf_token", csrf.as_str());
+    Ok(Template::render("register_verify", &context))
+}
+
+#[post("/register/verify", data = "<confirm>")]
+pub(crate) fn register_confirm(
+    confirm: CsrfForm<ConfirmRegistration>,
+    registration_manager: State<RegistrationManager>,
+    invite_manager: State<InviteManager>,
+    mut ldap: DirectoryRef,
+) -> Flash<Redirect> {
+    let registration = match registration_manager.consume(&confirm.token) {
+        Some(v) => v,
+        None => {
+            return Flash::error(
+                Redirect::to(uri!(register_empty)),
+                "注册确认链接无效或已过期，请重新注册！",
+            )
+        }
+    };
+    match is_taken(
+        &mut *ldap,
+        &[registration.uid.as_str(), registration.mail.as_str()],
+    ) {
+        Ok(false) => {}
+        Ok(true) => {
+            return Flash::error(
+                Redirect::to(uri!(register_empty)),
+                "用户名称或电子邮箱已被使用，请重新注册！",
+            )
+        }
+        Err(err) => {
+            println!("Failed to look up {}: {}", registration.uid, err);
+            return Flash::error(
+                Redirect::to(uri!(register_empty)),
+                format!("注册账号失败：{}！", err.message()),
+            );
+        }
+    }
+    if registration_manager.mode == RegistrationMode::Approval && registration.invite.is_none() {
+        if !registration_manager.submit_approval(registration) {
+            return Flash::error(
+                Redirect::to(uri!(register_empty)),
+                "提交注册申请失败，请稍后再次尝试或与管理员联系！",
+            );
+        }
+        return Flash::success(
+            Redirect::to(uri!(register_empty)),
+            "电子邮箱已确认，注册申请已提交，请等待管理员审核！",
+        );
+    }
+    let invite = match &registration.invite {
+        Some(token) => match invite_manager.consume(token, &registration.mail) {
+            Some(v) => Some(v),
+            None => {
+                return Flash::error(
+                    Redirect::to(uri!(register_empty)),
+                    "邀请链接已失效，请与管理员联系！",
+                )
+            }
+        },
+        None => None,
+    };
+    match ldap.new_user(&registration) {
+        Ok(_) => Flash::success(
+            Redirect::to(uri!(crate::routes::login::login_page)),
+            "注册账号成功，请登录核实或执行其他操作！",
+        ),
+        Err(err) => {
+            if let Some(invite) = &invite {
+                invite_manager.release(invite);
+            }
+            let err = Error::from(err);
+            println!("Failed to create user {}: {}", registration.uid, err);
+            Flash::error(
+                Redirect::to(uri!(register_empty)),
+                format!("注册账号失败：{}！", err.message()),
+            )
+        }
+    }
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![register, register_empty]
+    routes![
+        register,
+        register_empty,
+        register_invite,
+        register_verify,
+        register_confirm
+    ]
 }
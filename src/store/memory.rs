@@ -0,0 +1,226 @@
+use super::{RecordStore, Result, SessionStore};
+use crate::models::Session;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+#[derive(Default)]
+struct SessionTable {
+    sessions: HashMap<String, Session>,
+    /// The session ids of each user indexed by dn.
+    by_dn: HashMap<String, HashSet<String>>,
+}
+
+impl SessionTable {
+    fn insert(&mut self, session: Session) {
+        self.by_dn
+            .entry(Clone::clone(&session.dn))
+            .or_default()
+            .insert(Clone::clone(&session.ssid));
+        self.sessions.insert(Clone::clone(&session.ssid), session);
+    }
+
+    fn remove(&mut self, ssid: &str) -> Option<Session> {
+        let session = self.sessions.remove(ssid)?;
+        if let Some(ids) = self.by_dn.get_mut(&session.dn) {
+            ids.remove(ssid);
+            if ids.is_empty() {
+                self.by_dn.remove(&session.dn);
+            }
+        }
+        Some(session)
+    }
+}
+
+/// The store to keep sessions in the memory of current process.
+pub struct MemorySessionStore {
+    table: RwLock<SessionTable>,
+}
+
+impl MemorySessionStore {
+    /// Construct a new memory store.
+    pub fn new() -> Self {
+        Self {
+            table: RwLock::new(SessionTable::default()),
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn insert(&self, session: &Session) -> Result<()> {
+        self.table.write()?.insert(Clone::clone(session));
+        Ok(())
+    }
+
+    fn get(&self, ssid: &str) -> Result<Option<Session>> {
+        Ok(self.table.read()?.sessions.get(ssid).cloned())
+    }
+
+    fn touch(&self, ssid: &str, last_seen: DateTime<Utc>) -> Result<()> {
+        if let Some(x) = self.table.write()?.sessions.get_mut(ssid) {
+            x.last_seen = last_seen;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, ssid: &str) -> Result<()> {
+        self.table.write()?.remove(ssid);
+        Ok(())
+    }
+
+    fn list_by_dn(&self, dn: &str) -> Result<Vec<Session>> {
+        let table = self.table.read()?;
+        Ok(table
+            .by_dn
+            .get(dn)
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(|x| table.sessions.get(x).cloned())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn remove_by_dn(&self, dn: &str, keep: Option<&str>) -> Result<usize> {
+        let mut table = self.table.write()?;
+        let ids: Vec<String> = table
+            .by_dn
+            .get(dn)
+            .map(|ids| {
+                ids.iter()
+                    .filter(|x| Some(x.as_str()) != keep)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        for id in &ids {
+            table.remove(id);
+        }
+        Ok(ids.len())
+    }
+
+    fn remove_expired(
+        &self,
+        created_before: DateTime<Utc>,
+        seen_before: DateTime<Utc>,
+    ) -> Result<usize> {
+        let mut table = self.table.write()?;
+        let ids: Vec<String> = table
+            .sessions
+            .values()
+            .filter(|v| v.created_at < created_before || v.last_seen < seen_before)
+            .map(|v| Clone::clone(&v.ssid))
+            .collect();
+        for id in &ids {
+            table.remove(id);
+        }
+        Ok(ids.len())
+    }
+}
+
+/// A record with the expiration.
+struct Record {
+    value: String,
+    expires: DateTime<Utc>,
+}
+
+/// The store to keep records in the memory of current process.
+pub struct MemoryRecordStore {
+    records: RwLock<HashMap<(String, String), Record>>,
+}
+
+impl MemoryRecordStore {
+    /// Construct a new memory store.
+    pub fn new() -> Self {
+        Self {
+            records: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl RecordStore for MemoryRecordStore {
+    fn put(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<()> {
+        self.records.write()?.insert(
+            (kind.to_string(), key.to_string()),
+            Record {
+                value: value.to_string(),
+                expires,
+            },
+        );
+        Ok(())
+    }
+
+    fn insert(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<bool> {
+        let now = Utc::now();
+        let mut records = self.records.write()?;
+        let k = (kind.to_string(), key.to_string());
+        if records.get(&k).map_or(false, |x| x.expires > now) {
+            return Ok(false);
+        }
+        records.insert(
+            k,
+            Record {
+                value: value.to_string(),
+                expires,
+            },
+        );
+        Ok(true)
+    }
+
+    fn replace(
+        &self,
+        kind: &str,
+        key: &str,
+        old: &str,
+        value: &str,
+        expires: DateTime<Utc>,
+    ) -> Result<bool> {
+        let now = Utc::now();
+        let mut records = self.records.write()?;
+        match records.get_mut(&(kind.to_string(), key.to_string())) {
+            Some(record) if record.expires > now && record.value == old => {
+                record.value = value.to_string();
+                record.expires = expires;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn get(&self, kind: &str, key: &str) -> Result<Option<String>> {
+        let now = Utc::now();
+        Ok(self
+            .records
+            .read()?
+            .get(&(kind.to_string(), key.to_string()))
+            .filter(|x| x.expires > now)
+            .map(|x| Clone::clone(&x.value)))
+    }
+
+    fn list(&self, kind: &str) -> Result<Vec<String>> {
+        let now = Utc::now();
+        Ok(self
+            .records
+            .read()?
+            .iter()
+            .filter(|(k, v)| k.0 == kind && v.expires > now)
+            .map(|(_, v)| Clone::clone(&v.value))
+            .collect())
+    }
+
+    fn remove(&self, kind: &str, key: &str) -> Result<bool> {
+        let now = Utc::now();
+        Ok(self
+            .records
+            .write()?
+            .remove(&(kind.to_string(), key.to_string()))
+            .map_or(false, |x| x.expires > now))
+    }
+
+    fn remove_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        let mut records = self.records.write()?;
+        let count = records.len();
+        records.retain(|_, v| v.expires > now);
+        Ok(count - records.len())
+    }
+}
@@ -0,0 +1,337 @@
+use crate::models::{Session, SessionConfig};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+mod memory;
+mod sqlite;
+
+pub use memory::{MemoryRecordStore, MemorySessionStore};
+pub use sqlite::{SqliteRecordStore, SqliteSessionStore};
+
+/// The errors may occurred on accessing the session store.
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Poisoned,
+    UnknownBackend(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(err) => write!(f, "{}", err),
+            StoreError::Io(err) => write!(f, "{}", err),
+            StoreError::Json(err) => write!(f, "{}", err),
+            StoreError::Poisoned => write!(f, "The session store is poisoned"),
+            StoreError::UnknownBackend(name) => write!(f, "Unknown session store: {}", name),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Sqlite(err)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Json(err)
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for StoreError {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        StoreError::Poisoned
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+/// A trait to persist the sessions.
+pub trait SessionStore: Send + Sync {
+    /// Insert or replace the `session`.
+    fn insert(&self, session: &Session) -> Result<()>;
+
+    /// Returns the session specified by `ssid`.
+    fn get(&self, ssid: &str) -> Result<Option<Session>>;
+
+    /// Update the `last_seen` of the session specified by `ssid`.
+    fn touch(&self, ssid: &str, last_seen: DateTime<Utc>) -> Result<()>;
+
+    /// Remove the session specified by `ssid`.
+    fn remove(&self, ssid: &str) -> Result<()>;
+
+    /// Returns the sessions of the user specified by `dn`.
+    fn list_by_dn(&self, dn: &str) -> Result<Vec<Session>>;
+
+    /// Remove the sessions of the user specified by `dn` except `keep`,
+    /// returns the number of them.
+    fn remove_by_dn(&self, dn: &str, keep: Option<&str>) -> Result<usize>;
+
+    /// Remove the sessions created before `created_before` or last seen before
+    /// `seen_before`, returns the number of them.
+    fn remove_expired(
+        &self,
+        created_before: DateTime<Utc>,
+        seen_before: DateTime<Utc>,
+    ) -> Result<usize>;
+}
+
+/// A trait to persist the short-lived records shared by the processes, e.g.
+/// the invites and the pending registrations.
+///
+/// Each record is a JSON value keyed by `kind` and `key`, the expired records
+/// are invisible and removed by `remove_expired`.
+pub trait RecordStore: Send + Sync {
+    /// Insert or replace the record, expires at `expires`.
+    fn put(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<()>;
+
+    /// Insert the record if there is no one not expired, returns true if inserted.
+    fn insert(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<bool>;
+
+    /// Replace the record if its value is still `old`, returns true if replaced.
+    ///
+    /// Only one of the concurrent callers read the same `old` gets true, which
+    /// makes it usable to update the record atomically.
+    fn replace(
+        &self,
+        kind: &str,
+        key: &str,
+        old: &str,
+        value: &str,
+        expires: DateTime<Utc>,
+    ) -> Result<bool>;
+
+    /// Returns the record specified by `kind` and `key` if not expired.
+    fn get(&self, kind: &str, key: &str) -> Result<Option<String>>;
+
+    /// Returns the records of `kind` not expired.
+    fn list(&self, kind: &str) -> Result<Vec<String>>;
+
+    /// Remove the record, returns true if it was existing.
+    ///
+    /// Only one of the concurrent callers gets true, which makes it usable to
+    /// consume the record once.
+    fn remove(&self, kind: &str, key: &str) -> Result<bool>;
+
+    /// Remove the records expired before `now`, returns the number of them.
+    fn remove_expired(&self, now: DateTime<Utc>) -> Result<usize>;
+}
+
+impl dyn RecordStore {
+    /// Insert or replace the record with the JSON of `value`.
+    pub fn put_json<T: Serialize>(
+        &self,
+        kind: &str,
+        key: &str,
+        value: &T,
+        expires: DateTime<Utc>,
+    ) -> Result<()> {
+        self.put(kind, key, &serde_json::to_string(value)?, expires)
+    }
+
+    /// Returns the record parsed from JSON.
+    pub fn get_json<T: DeserializeOwned>(&self, kind: &str, key: &str) -> Result<Option<T>> {
+        match self.get(kind, key)? {
+            Some(v) => Ok(Some(serde_json::from_str(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the records of `kind` parsed from JSON.
+    pub fn list_json<T: DeserializeOwned>(&self, kind: &str) -> Result<Vec<T>> {
+        self.list(kind)?
+            .iter()
+            .map(|x| serde_json::from_str(x).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+/// Returns the session store specified in `cfg`.
+pub fn open_session_store(cfg: &SessionConfig) -> Result<Box<dyn SessionStore>> {
+    match cfg.store.as_str() {
+        "memory" => Ok(Box::new(MemorySessionStore::new())),
+        "sqlite" => Ok(Box::new(SqliteSessionStore::open(&cfg.path)?)),
+        other => Err(StoreError::UnknownBackend(other.to_string())),
+    }
+}
+
+/// Returns the record store specified in `cfg`, the `sqlite` backend shares
+/// the database file with the sessions.
+pub fn open_record_store(cfg: &SessionConfig) -> Result<Box<dyn RecordStore>> {
+    match cfg.store.as_str() {
+        "memory" => Ok(Box::new(MemoryRecordStore::new())),
+        "sqlite" => Ok(Box::new(SqliteRecordStore::open(&cfg.path)?)),
+        other => Err(StoreError::UnknownBackend(other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Role, SessionManager, SessionRef};
+    use chrono::{Duration, TimeZone};
+    use rocket::config::Value;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+
+    /// Returns the database file of test `name`, removed if exists.
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("lamager-{}-{}.db", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn session(ssid: &str, dn: &str, created_at: DateTime<Utc>) -> Session {
+        Session {
+            ssid: ssid.to_string(),
+            dn: dn.to_string(),
+            uid: "alice".to_string(),
+            roles: vec![Role::ADMIN, Role::USER],
+            created_at,
+            last_seen: created_at,
+            user_agent: "test".to_string(),
+            ip: "127.0.0.1".to_string(),
+        }
+    }
+
+    /// Insert, look up and remove sessions in `store`.
+    fn round_trip(store: &dyn SessionStore) {
+        // The sqlite store keeps the times in seconds.
+        let now = Utc.timestamp(Utc::now().timestamp(), 0);
+        let old = now - Duration::hours(1);
+        store.insert(&session("a", "uid=alice", now)).unwrap();
+        store.insert(&session("b", "uid=alice", now)).unwrap();
+        store.insert(&session("c", "uid=alice", old)).unwrap();
+        store.insert(&session("d", "uid=bob", now)).unwrap();
+
+        let a = store.get("a").unwrap().unwrap();
+        assert_eq!(a.dn, "uid=alice");
+        assert_eq!(a.roles, vec![Role::ADMIN, Role::USER]);
+        assert_eq!(a.created_at, now);
+        assert_eq!(a.ip, "127.0.0.1");
+        assert!(store.get("x").unwrap().is_none());
+
+        store.touch("a", now + Duration::minutes(1)).unwrap();
+        assert_eq!(
+            store.get("a").unwrap().unwrap().last_seen,
+            now + Duration::minutes(1)
+        );
+        assert_eq!(store.list_by_dn("uid=alice").unwrap().len(), 3);
+
+        // Sweep the session created an hour ago.
+        let expired = now - Duration::minutes(30);
+        assert_eq!(store.remove_expired(expired, expired).unwrap(), 1);
+        assert!(store.get("c").unwrap().is_none());
+
+        assert_eq!(store.remove_by_dn("uid=alice", Some("a")).unwrap(), 1);
+        assert!(store.get("a").unwrap().is_some());
+        assert!(store.get("b").unwrap().is_none());
+
+        store.remove("a").unwrap();
+        assert!(store.list_by_dn("uid=alice").unwrap().is_empty());
+        assert_eq!(store.list_by_dn("uid=bob").unwrap().len(), 1);
+    }
+
+    /// Put, look up and remove records in `store`.
+    fn record_round_trip(store: &dyn RecordStore) {
+        let now = Utc::now();
+        store
+            .put("invite", "a", "1", now + Duration::hours(1))
+            .unwrap();
+        store
+            .put("invite", "b", "2", now + Duration::hours(1))
+            .unwrap();
+        store
+            .put("invite", "c", "3", now - Duration::hours(1))
+            .unwrap();
+        store
+            .put("ticket", "a", "4", now + Duration::hours(1))
+            .unwrap();
+
+        assert_eq!(store.get("invite", "a").unwrap(), Some("1".to_string()));
+        assert_eq!(store.get("ticket", "a").unwrap(), Some("4".to_string()));
+        // The expired records are invisible.
+        assert_eq!(store.get("invite", "c").unwrap(), None);
+        let mut values = store.list("invite").unwrap();
+        values.sort();
+        assert_eq!(values, vec!["1", "2"]);
+
+        store
+            .put("invite", "a", "5", now + Duration::hours(1))
+            .unwrap();
+        assert_eq!(store.get("invite", "a").unwrap(), Some("5".to_string()));
+        // Only the first of the callers read the same value replaces it.
+        assert!(store
+            .replace("invite", "a", "5", "6", now + Duration::hours(1))
+            .unwrap());
+        assert!(!store
+            .replace("invite", "a", "5", "7", now + Duration::hours(1))
+            .unwrap());
+        assert_eq!(store.get("invite", "a").unwrap(), Some("6".to_string()));
+        // The records not expired are not overwritten by the inserts.
+        assert!(!store
+            .insert("invite", "a", "8", now + Duration::hours(1))
+            .unwrap());
+        assert!(store
+            .insert("invite", "d", "9", now + Duration::hours(1))
+            .unwrap());
+        assert!(store.remove("invite", "d").unwrap());
+        assert!(store
+            .insert("invite", "c", "10", now - Duration::hours(1))
+            .unwrap());
+        assert!(store.remove("invite", "a").unwrap());
+        assert!(!store.remove("invite", "a").unwrap());
+        assert_eq!(store.remove_expired(now).unwrap(), 1);
+        assert_eq!(store.list("invite").unwrap(), vec!["2"]);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        round_trip(&MemorySessionStore::new());
+        record_round_trip(&MemoryRecordStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let path = db_path("store");
+        round_trip(&SqliteSessionStore::open(&path).unwrap());
+        record_round_trip(&SqliteRecordStore::open(&path).unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_shared() {
+        let path = db_path("shared");
+        let mut map = BTreeMap::new();
+        map.insert("store".to_string(), Value::from("sqlite"));
+        map.insert(
+            "path".to_string(),
+            Value::from(path.to_string_lossy().as_ref()),
+        );
+        let cfg = SessionConfig::from(&map);
+        // Two managers stand for two processes of the same deployment.
+        let first = SessionManager::new(Clone::clone(&cfg)).unwrap();
+        let second = SessionManager::new(cfg).unwrap();
+        let session = session("shared", "uid=alice", Utc::now());
+        first.add(SessionRef(Arc::new(session)));
+        assert_eq!(second.get("shared").unwrap().uid, "alice");
+        second.remove("shared");
+        assert!(first.get("shared").is_none());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,275 @@
+use super::{RecordStore, Result, SessionStore};
+use crate::models::{Role, Session};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The time to wait for the lock held by other processes.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The store to keep sessions in a SQLite database file, the sessions survive
+/// restarts and can be shared between processes on the same host.
+pub struct SqliteSessionStore {
+    con: Mutex<Connection>,
+}
+
+/// Open the database at `path` with the shared settings, the file will be
+/// created if not exists.
+fn open_database(path: &Path) -> Result<Connection> {
+    if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let con = Connection::open(path)?;
+    con.busy_timeout(BUSY_TIMEOUT)?;
+    // WAL allows the readers and the writer from other processes concurrently.
+    con.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    Ok(con)
+}
+
+impl SqliteSessionStore {
+    /// Open the database at `path`, the file and tables will be created if not exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let con = open_database(path.as_ref())?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                ssid TEXT PRIMARY KEY,
+                dn TEXT NOT NULL,
+                uid TEXT NOT NULL,
+                roles TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                user_agent TEXT NOT NULL DEFAULT '',
+                ip TEXT NOT NULL DEFAULT ''
+            );",
+        )?;
+        con.execute_batch(
+            "CREATE INDEX IF NOT EXISTS sessions_last_seen ON sessions (last_seen);
+            CREATE INDEX IF NOT EXISTS sessions_dn ON sessions (dn);",
+        )?;
+        Ok(Self {
+            con: Mutex::new(con),
+        })
+    }
+}
+
+/// The columns of the sessions in order of `session_of_row`.
+const SESSION_COLUMNS: &str = "ssid, dn, uid, roles, created_at, last_seen, user_agent, ip";
+
+/// Returns the session constructed from the `row`.
+fn session_of_row(row: &Row) -> rusqlite::Result<Session> {
+    let roles: String = row.get(3)?;
+    Ok(Session {
+        ssid: row.get(0)?,
+        dn: row.get(1)?,
+        uid: row.get(2)?,
+        roles: roles
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(Role::from)
+            .collect(),
+        created_at: Utc.timestamp(row.get(4)?, 0),
+        last_seen: Utc.timestamp(row.get(5)?, 0),
+        user_agent: row.get(6)?,
+        ip: row.get(7)?,
+    })
+}
+
+impl SessionStore for SqliteSessionStore {
+    fn insert(&self, session: &Session) -> Result<()> {
+        let roles: Vec<&str> = session.roles.iter().map(Role::as_str).collect();
+        self.con.lock()?.execute(
+            &format!(
+                "INSERT OR REPLACE INTO sessions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                SESSION_COLUMNS
+            ),
+            params![
+                session.ssid,
+                session.dn,
+                session.uid,
+                roles.join(","),
+                session.created_at.timestamp(),
+                session.last_seen.timestamp(),
+                session.user_agent,
+                session.ip
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get(&self, ssid: &str) -> Result<Option<Session>> {
+        let session = self
+            .con
+            .lock()?
+            .query_row(
+                &format!("SELECT {} FROM sessions WHERE ssid = ?1", SESSION_COLUMNS),
+                params![ssid],
+                session_of_row,
+            )
+            .optional()?;
+        Ok(session)
+    }
+
+    fn touch(&self, ssid: &str, last_seen: DateTime<Utc>) -> Result<()> {
+        self.con.lock()?.execute(
+            "UPDATE sessions SET last_seen = ?2 WHERE ssid = ?1",
+            params![ssid, last_seen.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, ssid: &str) -> Result<()> {
+        self.con
+            .lock()?
+            .execute("DELETE FROM sessions WHERE ssid = ?1", params![ssid])?;
+        Ok(())
+    }
+
+    fn list_by_dn(&self, dn: &str) -> Result<Vec<Session>> {
+        let con = self.con.lock()?;
+        let mut stmt = con.prepare(&format!(
+            "SELECT {} FROM sessions WHERE dn = ?1",
+            SESSION_COLUMNS
+        ))?;
+        let sessions = stmt
+            .query_map(params![dn], session_of_row)?
+            .collect::<rusqlite::Result<Vec<Session>>>()?;
+        Ok(sessions)
+    }
+
+    fn remove_by_dn(&self, dn: &str, keep: Option<&str>) -> Result<usize> {
+        let count = self.con.lock()?.execute(
+            "DELETE FROM sessions WHERE dn = ?1 AND ssid IS NOT ?2",
+            params![dn, keep],
+        )?;
+        Ok(count)
+    }
+
+    fn remove_expired(
+        &self,
+        created_before: DateTime<Utc>,
+        seen_before: DateTime<Utc>,
+    ) -> Result<usize> {
+        let count = self.con.lock()?.execute(
+            "DELETE FROM sessions WHERE created_at < ?1 OR last_seen < ?2",
+            params![created_before.timestamp(), seen_before.timestamp()],
+        )?;
+        Ok(count)
+    }
+}
+
+/// The store to keep records in a SQLite database, usually the same file of
+/// the sessions.
+pub struct SqliteRecordStore {
+    con: Mutex<Connection>,
+}
+
+impl SqliteRecordStore {
+    /// Open the database at `path`, the file and tables will be created if not exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let con = open_database(path.as_ref())?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS records (
+                kind TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                expires INTEGER NOT NULL,
+                PRIMARY KEY (kind, key)
+            );
+            CREATE INDEX IF NOT EXISTS records_expires ON records (expires);",
+        )?;
+        Ok(Self {
+            con: Mutex::new(con),
+        })
+    }
+}
+
+impl RecordStore for SqliteRecordStore {
+    fn put(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<()> {
+        self.con.lock()?.execute(
+            "INSERT OR REPLACE INTO records (kind, key, value, expires) VALUES (?1, ?2, ?3, ?4)",
+            params![kind, key, value, expires.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn insert(&self, kind: &str, key: &str, value: &str, expires: DateTime<Utc>) -> Result<bool> {
+        // The expired record is overwritten, it's invisible already.
+        let count = self.con.lock()?.execute(
+            "INSERT INTO records (kind, key, value, expires) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (kind, key) DO UPDATE SET value = excluded.value, expires = excluded.expires
+            WHERE records.expires <= ?5",
+            params![
+                kind,
+                key,
+                value,
+                expires.timestamp(),
+                Utc::now().timestamp()
+            ],
+        )?;
+        Ok(count > 0)
+    }
+
+    fn replace(
+        &self,
+        kind: &str,
+        key: &str,
+        old: &str,
+        value: &str,
+        expires: DateTime<Utc>,
+    ) -> Result<bool> {
+        let count = self.con.lock()?.execute(
+            "UPDATE records SET value = ?4, expires = ?5
+            WHERE kind = ?1 AND key = ?2 AND value = ?3 AND expires > ?6",
+            params![
+                kind,
+                key,
+                old,
+                value,
+                expires.timestamp(),
+                Utc::now().timestamp()
+            ],
+        )?;
+        Ok(count > 0)
+    }
+
+    fn get(&self, kind: &str, key: &str) -> Result<Option<String>> {
+        let value = self
+            .con
+            .lock()?
+            .query_row(
+                "SELECT value FROM records WHERE kind = ?1 AND key = ?2 AND expires > ?3",
+                params![kind, key, Utc::now().timestamp()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    fn list(&self, kind: &str) -> Result<Vec<String>> {
+        let con = self.con.lock()?;
+        let mut stmt = con.prepare("SELECT value FROM records WHERE kind = ?1 AND expires > ?2")?;
+        let values = stmt
+            .query_map(params![kind, Utc::now().timestamp()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(values)
+    }
+
+    fn remove(&self, kind: &str, key: &str) -> Result<bool> {
+        let count = self.con.lock()?.execute(
+            "DELETE FROM records WHERE kind = ?1 AND key = ?2 AND expires > ?3",
+            params![kind, key, Utc::now().timestamp()],
+        )?;
+        Ok(count > 0)
+    }
+
+    fn remove_expired(&self, now: DateTime<Utc>) -> Result<usize> {
+        let count = self.con.lock()?.execute(
+            "DELETE FROM records WHERE expires <= ?1",
+            params![now.timestamp()],
+        )?;
+        Ok(count)
+    }
+}
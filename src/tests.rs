@@ -0,0 +1,637 @@
+//! The tests of the routes, against the in-memory directory.
+
+use crate::directory::{DirectoryBackend, MemoryDirectory};
+use crate::error::Error;
+use crate::models::Totp;
+use chrono::Utc;
+use rocket::config::{Config, Environment, LoggingLevel, Table, Value};
+use rocket::error::LaunchErrorKind;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const SEED: &str = "\
+dn: uid=alice,ou=demo,dc=example,dc=com
+objectClass: inetOrgPerson
+objectClass: person
+uid: alice
+cn: Alice Liddell
+sn: Liddell
+mail: alice@example.com
+userPassword: Correct-Horse-Battery-42
+
+dn: cn=admins,ou=groups,dc=example,dc=com
+objectClass: groupOfNames
+cn: admins
+member: uid=alice,ou=demo,dc=example,dc=com
+";
+
+/// The client of a rocket seeded with `SEED`, the mails are written to `mails`.
+fn client(mails: &Path) -> Client {
+    client_with(mails, &[])
+}
+
+/// The client of a rocket seeded with `SEED` with the `extras` config.
+fn client_with(mails: &Path, extras: &[(&str, Value)]) -> Client {
+    let mut ldap = Table::new();
+    ldap.insert("backend".to_string(), Value::from("memory"));
+    ldap.insert(
+        "base_dn".to_string(),
+        Value::from("ou=demo,dc=example,dc=com"),
+    );
+    ldap.insert(
+        "group_base_dn".to_string(),
+        Value::from("ou=groups,dc=example,dc=com"),
+    );
+    let mut roles = Table::new();
+    roles.insert(
+        "admin".to_string(),
+        Value::from(vec!["CN=Admins, ou=groups,dc=example,dc=com"]),
+    );
+    roles.insert(
+        "auditor".to_string(),
+        Value::from(vec!["cn=auditors,ou=groups,dc=example,dc=com"]),
+    );
+    ldap.insert("roles".to_string(), Value::Table(roles));
+    let mut mail = Table::new();
+    mail.insert("transport".to_string(), Value::from("file"));
+    mail.insert(
+        "dir".to_string(),
+        Value::from(mails.to_string_lossy().as_ref()),
+    );
+    let mut config = Config::build(Environment::Development)
+        .log_level(LoggingLevel::Off)
+        .extra("ldap", ldap)
+        .extra("mail", mail);
+    for (name, value) in extras {
+        config = config.extra(name, Clone::clone(value));
+    }
+    let config = config.finalize().unwrap();
+    let client = Client::new(crate::mount(rocket::custom(config))).unwrap();
+    directory(&client).load_ldif(SEED).unwrap();
+    client
+}
+
+#[test]
+fn test_invalid_config() {
+    let mut ldap = Table::new();
+    ldap.insert("backend".to_string(), Value::from("memory"));
+    ldap.insert("password_scheme".to_string(), Value::from("MD4"));
+    let mails = mail_dir("invalid-config");
+    let mut mail = Table::new();
+    mail.insert("transport".to_string(), Value::from("file"));
+    mail.insert(
+        "dir".to_string(),
+        Value::from(mails.to_string_lossy().as_ref()),
+    );
+    let config = Config::build(Environment::Development)
+        .log_level(LoggingLevel::Off)
+        .extra("ldap", ldap)
+        .extra("mail", mail)
+        .finalize()
+        .unwrap();
+    match Client::new(crate::mount(rocket::custom(config))) {
+        // The kind is inspected so that the error is handled, or it panics on drop.
+        Err(err) => match err.kind() {
+            LaunchErrorKind::FailedFairings(_) => {}
+            kind => panic!("unexpected {}", kind),
+        },
+        Ok(_) => panic!("launched with an unknown password scheme"),
+    }
+    let _ = fs::remove_dir_all(&mails);
+}
+
+/// Returns the memory directory of the `client`.
+fn directory(client: &Client) -> &MemoryDirectory {
+    match client.rocket().state::<DirectoryBackend>() {
+        Some(DirectoryBackend::Memory(memory)) => memory,
+        _ => panic!("the memory directory is not managed"),
+    }
+}
+
+/// Returns the directory for the mails of test `name`.
+fn mail_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lamager-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Returns the CSRF token in the page of `uri`.
+fn csrf_token(client: &Client, uri: &str) -> String {
+    let body = client.get(uri).dispatch().body_string().unwrap();
+    let start = body.find("name=\"csrf_token\" value=\"").unwrap() + 25;
+    let len = body[start..].find('"').unwrap();
+    body[start..start + len].to_string()
+}
+
+/// Post the form `body` to `uri`, returns the status and the location.
+fn post_form(client: &Client, uri: &str, body: &str) -> (Status, Option<String>) {
+    let token = csrf_token(client, "/register");
+    let response = client
+        .post(uri)
+        .header(ContentType::Form)
+        .body(format!("{}&csrf_token={}", body, token))
+        .dispatch();
+    let location = response.headers().get_one("Location").map(String::from);
+    (response.status(), location)
+}
+
+fn login(client: &Client, username: &str, password: &str) -> Option<String> {
+    let body = format!("username={}&password={}", username, password);
+    post_form(client, "/login", &body).1
+}
+
+#[test]
+fn test_login() {
+    let mails = mail_dir("login");
+    let client = client(&mails);
+    assert_eq!(
+        login(&client, "alice", "wrong-password"),
+        Some("/login".to_string())
+    );
+    assert_eq!(
+        login(&client, "nobody", "Correct-Horse-Battery-42"),
+        Some("/login".to_string())
+    );
+    assert_eq!(
+        login(&client, "alice", "Correct-Horse-Battery-42"),
+        Some("/".to_string())
+    );
+    let mut response = client.get("/profile").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.body_string().unwrap().contains("Alice Liddell"));
+
+    // The entries found by the mail may have no uid.
+    directory(&client)
+        .load_ldif(
+            "\
+dn: cn=carol,ou=demo,dc=example,dc=com
+objectClass: inetOrgPerson
+cn: carol
+mail: carol@example.com
+userPassword: Correct-Horse-Battery-42
+",
+        )
+        .unwrap();
+    client.get("/logout").dispatch();
+    assert_eq!(
+        login(&client, "carol%40example.com", "wrong-password"),
+        Some("/login".to_string())
+    );
+    let (status, _) = post_form(&client, "/recover", "username=carol%40example.com");
+    assert_eq!(status, Status::SeeOther);
+    let _ = fs::remove_dir_all(&mails);
+}
+
+/// Login from `remote` with the `X-Real-IP` header of `real_ip`, returns the location.
+fn login_from(
+    client: &Client,
+    remote: &str,
+    real_ip: &str,
+    username: &str,
+    password: &str,
+) -> Option<String> {
+    let token = csrf_token(client, "/register");
+    let response = client
+        .post("/login")
+        .remote(remote.parse().unwrap())
+        .header(ContentType::Form)
+        .header(Header::new("X-Real-IP", real_ip.to_string()))
+        .body(format!(
+            "username={}&password={}&csrf_token={}",
+            username, password, token
+        ))
+        .dispatch();
+    response.headers().get_one("Location").map(String::from)
+}
+
+#[test]
+fn test_client_ip() {
+    // The X-Real-IP header of the untrusted clients is ignored.
+    let mails = mail_dir("client-ip");
+    let client = client(&mails);
+    for i in 0..4 {
+        let real_ip = format!("10.0.0.{}", i);
+        let username = format!("user{}", i);
+        login_from(&client, "192.0.2.1:4000", &real_ip, &username, "wrong");
+    }
+    assert_eq!(
+        login_from(
+            &client,
+            "192.0.2.1:4000",
+            "10.0.0.9",
+            "alice",
+            "Correct-Horse-Battery-42"
+        ),
+        Some("/login".to_string())
+    );
+    let _ = fs::remove_dir_all(&mails);
+
+    // The header is honoured for the trusted proxies.
+    let mails = mail_dir("trusted-proxy");
+    let proxies = Value::from(vec!["127.0.0.1"]);
+    let client = client_with(&mails, &[("trusted_proxies", proxies)]);
+    for i in 0..4 {
+        let real_ip = format!("10.0.0.{}", i);
+        let username = format!("user{}", i);
+        login_from(&client, "127.0.0.1:4000", &real_ip, &username, "wrong");
+    }
+    assert_eq!(
+        login_from(
+            &client,
+            "127.0.0.1:4000",
+            "10.0.0.9",
+            "alice",
+            "Correct-Horse-Battery-42"
+        ),
+        Some("/".to_string())
+    );
+    let _ = fs::remove_dir_all(&mails);
+}
+
+/// Returns the links to confirm the registrations mailed to `mail`.
+fn register_links(mails: &Path, mail: &str) -> Vec<String> {
+    fs::read_dir(mails)
+        .unwrap()
+        .map(|x| fs::read_to_string(x.unwrap().path()).unwrap())
+        .filter(|x| x.contains(mail))
+        .map(|x| {
+            let start = x.find("/register/verify/").unwrap();
+            let len = x[start..].find(char::is_whitespace).unwrap();
+            x[start..start + len].to_string()
+        })
+        .collect()
+}
+
+/// Confirm the registration of `link`, returns the location.
+fn confirm_register(client: &Client, link: &str) -> Option<String> {
+    let token = &link["/register/verify/".len()..];
+    let response = client
+        .post("/register/verify")
+        .header(ContentType::Form)
+        .body(format!(
+            "token={}&csrf_token={}",
+            token,
+            csrf_token(client, "/register")
+        ))
+        .dispatch();
+    response.headers().get_one("Location").map(String::from)
+}
+
+#[test]
+fn test_register() {
+    let mails = mail_dir("register");
+    let client = client(&mails);
+    let body = "uid=bob&cn=Bob&mail=bob%40example.com\
+                &password=Purple-Monkey-Dishwasher-7\
+                &password_confirm=Purple-Monkey-Dishwasher-7";
+    assert_eq!(
+        post_form(&client, "/register", body).1,
+        Some("/register".to_string())
+    );
+    let first = register_links(&mails, "bob@example.com").remove(0);
+    // Following the link only shows the registration.
+    let mut response = client.get(first.as_str()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.body_string().unwrap().contains("bob@example.com"));
+
+    // Registering again replaces the pending registration.
+    post_form(&client, "/register", body);
+    let links = register_links(&mails, "bob@example.com");
+    assert_eq!(links.len(), 2);
+    let second = links.into_iter().find(|x| *x != first).unwrap();
+    assert_eq!(
+        confirm_register(&client, &first),
+        Some("/register".to_string())
+    );
+    assert_eq!(
+        confirm_register(&client, &second),
+        Some("/login".to_string())
+    );
+    assert_eq!(
+        login(&client, "bob", "Purple-Monkey-Dishwasher-7"),
+        Some("/".to_string())
+    );
+    // The link can be used only once.
+    assert_eq!(
+        confirm_register(&client, &second),
+        Some("/register".to_string())
+    );
+    let response = client.get(second.as_str()).dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/register"));
+
+    // The uid of the existing user is rejected.
+    post_form(&client, "/register", body);
+    assert_eq!(register_links(&mails, "bob@example.com").len(), 2);
+
+    // The uid taken after the registration is rejected on confirming.
+    let body = "uid=carol&cn=Carol&mail=carol%40example.com\
+                &password=Purple-Monkey-Dishwasher-7\
+                &password_confirm=Purple-Monkey-Dishwasher-7";
+    post_form(&client, "/register", body);
+    let link = register_links(&mails, "carol@example.com").remove(0);
+    directory(&client)
+        .load_ldif(
+            "dn: uid=carol,ou=demo,dc=example,dc=com\n\
+             objectClass: inetOrgPerson\n\
+             uid: carol\n\
+             cn: Carol\n\
+             sn: Carol\n\
+             mail: carol@other.example.com\n",
+        )
+        .unwrap();
+    assert_eq!(
+        confirm_register(&client, &link),
+        Some("/register".to_string())
+    );
+    let _ = fs::remove_dir_all(&mails);
+}
+
+/// Returns the id of the first registration waiting for approval.
+fn approval_id(client: &Client) -> String {
+    let body = client
+        .get("/admin/registrations")
+        .dispatch()
+        .body_string()
+        .unwrap();
+    let start = body.find("name=\"id\" value=\"").unwrap() + 17;
+    let len = body[start..].find('"').unwrap();
+    body[start..start + len].to_string()
+}
+
+#[test]
+fn test_register_approval() {
+    let mails = mail_dir("approval");
+    let client = client_with(&mails, &[("register_mode", Value::from("approval"))]);
+    for uid in &["dave", "erin"] {
+        let body = format!(
+            "uid={}&cn={}&mail={}%40example.com\
+             &password=Purple-Monkey-Dishwasher-7\
+             &password_confirm=Purple-Monkey-Dishwasher-7",
+            uid, uid, uid
+        );
+        post_form(&client, "/register", &body);
+        let link = register_links(&mails, &format!("{}@example.com", uid)).remove(0);
+        assert_eq!(
+            confirm_register(&client, &link),
+            Some("/register".to_string())
+        );
+    }
+    // The account is created only after approved.
+    assert_eq!(
+        login(&client, "dave", "Purple-Monkey-Dishwasher-7"),
+        Some("/login".to_string())
+    );
+
+    login(&client, "alice", "Correct-Horse-Battery-42");
+    let id = approval_id(&client);
+    let (_, location) = post_form(
+        &client,
+        "/admin/registrations/approve",
+        &format!("id={}", id),
+    );
+    assert_eq!(location, Some("/admin/registrations".to_string()));
+    let (_, location) = post_form(
+        &client,
+        "/admin/registrations/approve",
+        &format!("id={}", id),
+    );
+    assert_eq!(location, Some("/admin/registrations".to_string()));
+    let id = approval_id(&client);
+    let body = format!("id={}&reason=Unknown+applicant", id);
+    post_form(&client, "/admin/registrations/reject", &body);
+    assert!(!client
+        .get("/admin/registrations")
+        .dispatch()
+        .body_string()
+        .unwrap()
+        .contains("name=\"id\""));
+
+    let approved = login(&client, "dave", "Purple-Monkey-Dishwasher-7");
+    assert_eq!(approved, Some("/".to_string()));
+    let rejected = login(&client, "erin", "Purple-Monkey-Dishwasher-7");
+    assert_eq!(rejected, Some("/login".to_string()));
+    let notified = fs::read_dir(&mails)
+        .unwrap()
+        .map(|x| fs::read_to_string(x.unwrap().path()).unwrap())
+        .any(|x| x.contains("erin@example.com") && x.contains("Unknown applicant"));
+    assert!(notified);
+    let _ = fs::remove_dir_all(&mails);
+}
+
+#[test]
+fn test_roles() {
+    let mails = mail_dir("roles");
+    let client = client(&mails);
+    login(&client, "alice", "Correct-Horse-Battery-42");
+    let response = client.get("/admin/registrations").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // The membership is looked up again on the privileged requests.
+    directory(&client)
+        .load_ldif(
+            "\
+dn: cn=admins,ou=groups,dc=example,dc=com
+objectClass: groupOfNames
+cn: admins
+
+dn: cn=auditors,ou=groups,dc=example,dc=com
+objectClass: groupOfNames
+cn: auditors
+member: uid=alice,ou=demo,dc=example,dc=com
+",
+        )
+        .unwrap();
+    let response = client.get("/admin/registrations").dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body = client.get("/profile").dispatch().body_string().unwrap();
+    assert!(body.contains("auditor，普通用户"));
+    directory(&client).load_ldif(SEED).unwrap();
+    let response = client.get("/admin/registrations").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = client.get("/profile").dispatch().body_string().unwrap();
+    assert!(body.contains("管理员，auditor，普通用户"));
+    let _ = fs::remove_dir_all(&mails);
+}
+
+#[test]
+fn test_totp() {
+    let mails = mail_dir("totp");
+    let client = client(&mails);
+    login(&client, "alice", "Correct-Horse-Battery-42");
+    let body = client
+        .get("/profile/totp")
+        .dispatch()
+        .body_string()
+        .unwrap();
+    let start = body.find("<code>").unwrap() + 6;
+    let len = body[start..].find('<').unwrap();
+    let totp = Totp::from_base32(&body[start..start + len]).unwrap();
+    let step = Utc::now().timestamp() as u64 / 30;
+    let code = format!("{:06}", totp.code_at(step));
+
+    // The password is required to enroll.
+    let body = format!("code={}&password=wrong-password", code);
+    let (_, location) = post_form(&client, "/profile/totp", &body);
+    assert_eq!(location, Some("/profile/totp".to_string()));
+    let body = format!("code={}&password=Correct-Horse-Battery-42", code);
+    let (_, location) = post_form(&client, "/profile/totp", &body);
+    assert_eq!(location, Some("/profile".to_string()));
+
+    // The code accepted can't be replayed.
+    client.get("/logout").dispatch();
+    assert_eq!(
+        login(&client, "alice", "Correct-Horse-Battery-42"),
+        Some("/login/totp".to_string())
+    );
+    let (_, location) = post_form(&client, "/login/totp", &format!("code={}", code));
+    assert_eq!(location, Some("/login/totp".to_string()));
+    let code = format!("{:06}", totp.code_at(step + 1));
+    let (_, location) = post_form(&client, "/login/totp", &format!("code={}", code));
+    assert_eq!(location, Some("/".to_string()));
+    let _ = fs::remove_dir_all(&mails);
+}
+
+#[test]
+fn test_profile_throttle() {
+    let mails = mail_dir("profile-throttle");
+    let client = client(&mails);
+    login(&client, "alice", "Correct-Horse-Battery-42");
+    // The password checks of the session are throttled as the logins.
+    for _ in 0..4 {
+        let (_, location) = post_form(&client, "/profile/totp/disable", "password=wrong");
+        assert_eq!(location, Some("/profile".to_string()));
+    }
+    client.get("/logout").dispatch();
+    assert_eq!(
+        login(&client, "alice", "Correct-Horse-Battery-42"),
+        Some("/login".to_string())
+    );
+    let _ = fs::remove_dir_all(&mails);
+}
+
+#[test]
+fn test_csrf() {
+    let mails = mail_dir("csrf");
+    let client = client(&mails);
+    // The forms without the token or with a wrong one are rejected.
+    let login_body = "username=alice&password=Correct-Horse-Battery-42";
+    for body in &[
+        login_body.to_string(),
+        format!("{}&csrf_token=wrong-token", login_body),
+    ] {
+        let response = client
+            .post("/login")
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    // The token is replaced when the session started.
+    let before = csrf_token(&client, "/register");
+    login(&client, "alice", "Correct-Horse-Battery-42");
+    let token = csrf_token(&client, "/register");
+    assert_ne!(before, token);
+    let response = client
+        .post("/profile/person")
+        .header(ContentType::Form)
+        .body(format!(
+            "uid=alice&cn=Alice&mail=alice%40example.com&location=&csrf_token={}",
+            before
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // The other requests must carry the token in the header.
+    let multipart = || {
+        client
+            .post("/profile/avatar")
+            .header(ContentType::with_params(
+                "multipart",
+                "form-data",
+                ("boundary", "X"),
+            ))
+            .body(
+                "--X\r\nContent-Disposition: form-data; name=\"avatar_file_name\"\r\n\r\n\
+                 a.jpg\r\n--X--\r\n",
+            )
+    };
+    assert_eq!(multipart().dispatch().status(), Status::Forbidden);
+    let response = multipart()
+        .header(Header::new("X-CSRF-Token", before))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = multipart()
+        .header(Header::new("X-CSRF-Token", Clone::clone(&token)))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client
+        .post("/profile/person")
+        .header(ContentType::JSON)
+        .body("{}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // The token is replaced when the session ended.
+    client.get("/logout").dispatch();
+    assert_ne!(csrf_token(&client, "/register"), token);
+    let _ = fs::remove_dir_all(&mails);
+}
+
+#[test]
+fn test_profile() {
+    let mails = mail_dir("profile");
+    let client = client(&mails);
+    let response = client.get("/profile").dispatch();
+    assert_ne!(response.status(), Status::Ok);
+    login(&client, "alice", "Correct-Horse-Battery-42");
+
+    let body = "uid=alice&cn=Alice+Pleasance&mail=alice%40example.com&location=Oxford";
+    let (status, _) = post_form(&client, "/profile/person", body);
+    assert_eq!(status, Status::Ok);
+    let mut response = client.get("/profile").dispatch();
+    assert!(response.body_string().unwrap().contains("Alice Pleasance"));
+
+    // The errors of the directory are reported with the status and message.
+    let alice = directory(&client)
+        .remove("uid=alice,ou=demo,dc=example,dc=com")
+        .unwrap();
+    let token = csrf_token(&client, "/register");
+    let mut response = client
+        .post("/profile/person")
+        .header(ContentType::Form)
+        .body(format!("{}&csrf_token={}", body, token))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(
+        response.body_string(),
+        Some(Error::NoSuchObject.message().to_string())
+    );
+    directory(&client).insert(alice);
+
+    let token = csrf_token(&client, "/profile");
+    let mut response = client
+        .post("/profile/password")
+        .header(ContentType::Form)
+        .body(format!(
+            "old_password=Correct-Horse-Battery-42\
+             &new_password=Tangerine-Lighthouse-19\
+             &new_password_confirm=Tangerine-Lighthouse-19\
+             &csrf_token={}",
+            token
+        ))
+        .dispatch();
+    assert!(response.body_string().unwrap().contains("okay"));
+    assert_eq!(
+        login(&client, "alice", "Correct-Horse-Battery-42"),
+        Some("/login".to_string())
+    );
+    assert_eq!(
+        login(&client, "alice", "Tangerine-Lighthouse-19"),
+        Some("/".to_string())
+    );
+    let _ = fs::remove_dir_all(&mails);
+}